@@ -1,22 +1,45 @@
-use nix_remote::worker_op::StreamingRecv;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
 use nix_remote::worker_op::WorkerOp;
-use nix_remote::{nix_client::NixDaemonClient, nix_daemon_proxy::NixDaemonProxy, stderr::Msg};
+use nix_remote::{
+    nix_client::NixDaemonClient,
+    nix_daemon_proxy::{NixDaemonProxy, ProxyConfig},
+    stderr::{Msg, StderrError},
+    store::{serve, BinaryCacheStore, BinaryCacheStoreConfig, LocalStore, LocalStoreConfig, Store},
+    DaemonVersion, PROTOCOL_VERSION,
+};
 
 macro_rules! for_each_op {
     ($macro_name:ident !) => {
         $macro_name!(
             IsValidPath,
+            HasSubstitutes,
+            QueryPathHash,
+            QueryReferences,
             QueryReferrers,
+            AddToStoreLegacy,
             AddToStore,
+            AddTextToStore,
             BuildPaths,
             EnsurePath,
             AddTempRoot,
+            AddIndirectRoot,
+            SyncWithGC,
             FindRoots,
+            ExportPath,
+            QueryDeriver,
             SetOptions,
             CollectGarbage,
+            QuerySubstitutablePathInfo,
+            QueryDerivationOutputs,
             QueryAllValidPaths,
+            QueryFailedPaths,
+            ClearFailedPaths,
             QueryPathInfo,
+            ImportPaths,
+            QueryDerivationOutputNames,
             QueryPathFromHashPart,
+            QuerySubstitutablePathInfos,
             QueryValidPaths,
             QuerySubstitutablePaths,
             QueryValidDerivers,
@@ -32,33 +55,99 @@ macro_rules! for_each_op {
             QueryRealisation,
             AddMultipleToStore,
             AddBuildLog,
-            BuildPathsWithResults
+            BuildPathsWithResults,
+            AddPermRoot
         )
     };
 }
 
-fn main() {
-    let mut daemon = NixDaemonProxy::new(std::io::stdin(), std::io::stdout()).unwrap();
+/// Opens a store of our own from a url like `local?root=/tmp/x` or `file:///tmp/cache`.
+fn open_store(url: &str) -> nix_remote::Result<Box<dyn Store>> {
+    if url.starts_with("file://") {
+        let config = BinaryCacheStoreConfig::from_url(url)?;
+        Ok(Box::new(BinaryCacheStore::open(config)?))
+    } else {
+        Ok(Box::new(LocalStore::open(LocalStoreConfig::from_url(
+            url,
+        )?)?))
+    }
+}
 
-    let mut child = std::process::Command::new("nix-daemon")
+/// Starts `nix-daemon` and connects to it, offering protocol versions up to `version`.
+///
+/// The child has to be reaped once we're done with it; see [`stop_daemon`].
+fn spawn_daemon(version: DaemonVersion) -> (Child, NixDaemonClient<ChildStdout, ChildStdin>) {
+    let mut child = Command::new("nix-daemon")
         .arg("--stdio")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
         .spawn()
         .unwrap();
+    let client = NixDaemonClient::with_version(
+        child.stdout.take().unwrap(),
+        child.stdin.take().unwrap(),
+        version,
+    )
+    .unwrap();
+    (child, client)
+}
+
+/// Hangs up on a `nix-daemon` from [`spawn_daemon`], and waits for it to exit.
+fn stop_daemon(mut child: Child, client: NixDaemonClient<ChildStdout, ChildStdin>) {
+    drop(client);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn main() {
+    // With `--store <url>`, serve that store instead of forwarding to `nix-daemon`.
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if let [flag, url] = args.as_slice() {
+        if flag == "--store" {
+            let mut store = open_store(url).unwrap();
+            if let Err(e) = serve(store.as_mut(), std::io::stdin(), std::io::stdout()) {
+                eprintln!("{e:?}");
+            }
+            return;
+        }
+    }
 
-    let mut client =
-        NixDaemonClient::new(child.stdout.take().unwrap(), child.stdin.take().unwrap()).unwrap();
+    // We forward worker ops unchanged, so our client and nix-daemon have to agree on the
+    // protocol version. Don't offer our client anything newer than nix-daemon speaks...
+    let (mut child, mut client) = spawn_daemon(PROTOCOL_VERSION);
+    let config = ProxyConfig {
+        protocol_version: client.protocol_version(),
+        ..ProxyConfig::default()
+    };
+    let mut daemon = NixDaemonProxy::new(std::io::stdin(), std::io::stdout(), config).unwrap();
+
+    // ...and if our client is older than that, talk to nix-daemon using its version.
+    if daemon.protocol_version() != client.protocol_version() {
+        stop_daemon(child, client);
+        (child, client) = spawn_daemon(daemon.protocol_version());
+    }
+    if daemon.protocol_version() != client.protocol_version() {
+        stop_daemon(child, client);
+        let msg = Msg::Error(StderrError::new(format!(
+            "nix-daemon doesn't speak protocol version {}",
+            daemon.protocol_version()
+        )));
+        daemon.send_error_to_client(&msg).unwrap();
+        daemon.flush_tx_to_client().unwrap();
+        return;
+    }
 
     loop {
         match &daemon.receive_next_op_from_client() {
             Err(e) => {
+                // Either the client hung up, or we can't make sense of it anymore.
                 eprintln!("{e:?}");
+                break;
             }
             Ok(worker_op) => {
                 client.send_worker_op_to_daemon(worker_op).unwrap();
 
-                if worker_op.requires_streaming() {
+                if worker_op.requires_streaming_at(daemon.protocol_version()) {
                     const BUF_SIZE: usize = 4096;
                     let mut buff = vec![0; BUF_SIZE];
 
@@ -84,6 +173,12 @@ fn main() {
                     daemon
                         .send_error_to_client(&error_message_from_builder)
                         .unwrap();
+                    // The daemon wants data from the client; pass it along.
+                    if let Msg::Read(_len) = error_message_from_builder {
+                        daemon.flush_tx_to_client().unwrap();
+                        let data = daemon.read_tunnel_data().unwrap();
+                        client.write_tunnel_data(&data).unwrap();
+                    }
                     // forward message to client via our daemon
                     if error_message_from_builder == Msg::Last(()) {
                         break;
@@ -113,4 +208,5 @@ fn main() {
             }
         }
     }
+    stop_daemon(child, client);
 }
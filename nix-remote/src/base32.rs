@@ -0,0 +1,113 @@
+//! Nix's flavor of base32.
+//!
+//! This isn't RFC 4648 base32: it uses a different alphabet (without `e`, `o`, `u` and
+//! `t`), and it starts with the most significant digit of the whole input, as if it were
+//! a little-endian number.
+
+/// The digits, in order.
+pub const ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("invalid character {0:?} in base32")]
+    InvalidChar(char),
+
+    #[error("{0} is not a valid length for base32")]
+    InvalidLength(usize),
+
+    /// The last digit has bits that don't fit in the decoded bytes.
+    #[error("base32 string has trailing bits")]
+    TrailingBits,
+}
+
+/// The length of the encoding of `len` bytes.
+pub fn encoded_len(len: usize) -> usize {
+    if len == 0 {
+        0
+    } else {
+        (len * 8 - 1) / 5 + 1
+    }
+}
+
+/// The number of bytes encoded by `len` digits.
+pub fn decoded_len(len: usize) -> usize {
+    len * 5 / 8
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    (0..encoded_len(bytes.len()))
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            // Bits from the lower byte, and then from the upper byte.
+            let low = bytes[i] >> j;
+            let high = bytes.get(i + 1).map_or(0, |&c| (c as u16) << (8 - j)) as u8;
+            ALPHABET[((low | high) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+pub fn decode(s: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let len = decoded_len(s.len());
+    if encoded_len(len) != s.len() {
+        return Err(DecodeError::InvalidLength(s.len()));
+    }
+    let mut bytes = vec![0u8; len];
+    for (n, &c) in s.iter().rev().enumerate() {
+        let digit = ALPHABET
+            .iter()
+            .position(|&d| d == c)
+            .ok_or(DecodeError::InvalidChar(c as char))? as u16;
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        let bits = digit << j;
+        bytes[i] |= bits as u8;
+        match bytes.get_mut(i + 1) {
+            Some(next) => *next |= (bits >> 8) as u8,
+            None if bits >> 8 != 0 => return Err(DecodeError::TrailingBits),
+            None => {}
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        // The sha256 of the empty string, as `nix hash convert` prints it.
+        let digest = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ];
+        let encoded = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+        assert_eq!(encode(&digest), encoded);
+        assert_eq!(decode(encoded.as_bytes()).unwrap(), digest);
+        assert_eq!(encode(&[]), "");
+        assert_eq!(decode(b"").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn roundtrip() {
+        arbtest::arbtest(|u| {
+            let bytes: Vec<u8> = u.arbitrary()?;
+            let encoded = encode(&bytes);
+            assert_eq!(encoded.len(), encoded_len(bytes.len()));
+            assert_eq!(decode(encoded.as_bytes()).unwrap(), bytes);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(decode(b"e0"), Err(DecodeError::InvalidChar('e')));
+        assert_eq!(decode(b"000"), Err(DecodeError::InvalidLength(3)));
+        // One byte takes two digits, and the first one can only use three bits.
+        assert_eq!(decode(b"80"), Err(DecodeError::TrailingBits));
+        assert_eq!(decode(b"70").unwrap(), vec![7 << 5]);
+    }
+}
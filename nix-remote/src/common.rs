@@ -1,6 +0,0 @@
-const WORKER_MAGIC_1: u64 = 0x6e697863;
-const WORKER_MAGIC_2: u64 = 0x6478696f;
-const PROTOCOL_VERSION: DaemonVersion = DaemonVersion {
-    major: 1,
-    minor: 34,
-};
@@ -0,0 +1,397 @@
+//! Content addresses, which say how a content-addressed store path was made.
+//!
+//! Nix writes them as strings, like `text:sha256:<nix32>` for paths added with
+//! `AddTextToStore` (or `builtins.toFile`), or `fixed:r:sha256:<nix32>` for fixed-output
+//! paths that were hashed as a nar.
+
+use std::{fmt, str::FromStr};
+
+use bstr::BStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::{Hash, HashAlgorithm, HashError, HashFormat},
+    NixString,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ContentAddressError {
+    #[error("content address '{0}' is not valid UTF-8")]
+    NotUtf8(String),
+
+    #[error("content address '{0}' has an unknown method")]
+    UnknownMethod(String),
+
+    #[error("text content address '{0}' must use sha256")]
+    TextNotSha256(String),
+
+    #[error("invalid content address '{ca}': {error}")]
+    Hash { ca: String, error: HashError },
+}
+
+/// How the contents of a path were hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub enum ContentAddressMethod {
+    /// The path is a single file, hashed like `builtins.toFile` does. Text paths may
+    /// refer to other paths, but not to themselves.
+    Text,
+    /// The path is a single file, and its contents were hashed.
+    Flat,
+    /// The nar serialization of the path was hashed.
+    Recursive,
+    /// The path was hashed like git hashes a tree.
+    Git,
+}
+
+impl ContentAddressMethod {
+    /// What comes before the hash in a content address.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ContentAddressMethod::Text => "text:",
+            ContentAddressMethod::Flat => "fixed:",
+            ContentAddressMethod::Recursive => "fixed:r:",
+            ContentAddressMethod::Git => "fixed:git:",
+        }
+    }
+
+    /// Splits the method off the front of `s`.
+    fn strip_prefix(s: &str) -> Option<(Self, &str)> {
+        if let Some(rest) = s.strip_prefix("text:") {
+            Some((ContentAddressMethod::Text, rest))
+        } else if let Some(rest) = s.strip_prefix("fixed:r:") {
+            Some((ContentAddressMethod::Recursive, rest))
+        } else if let Some(rest) = s.strip_prefix("fixed:git:") {
+            Some((ContentAddressMethod::Git, rest))
+        } else {
+            s.strip_prefix("fixed:")
+                .map(|rest| (ContentAddressMethod::Flat, rest))
+        }
+    }
+}
+
+/// A content address method together with a hash algorithm, like `fixed:r:sha256`.
+///
+/// This is what `AddToStore` sends, since the daemon computes the hash itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentAddressMethodWithAlgo {
+    pub method: ContentAddressMethod,
+    pub algorithm: HashAlgorithm,
+}
+
+impl ContentAddressMethodWithAlgo {
+    /// Parses the form used in derivation outputs, like `r:sha256`, which leaves off the
+    /// `fixed:`.
+    pub fn parse_method_algo(s: &str) -> Result<Self, ContentAddressError> {
+        if s.starts_with("text:") {
+            s.parse()
+        } else {
+            format!("fixed:{s}").parse()
+        }
+    }
+
+    /// Renders the form used in derivation outputs, like `r:sha256`.
+    pub fn render_method_algo(&self) -> String {
+        let method = self.method.prefix().trim_start_matches("fixed:");
+        format!("{method}{}", self.algorithm)
+    }
+}
+
+impl fmt::Display for ContentAddressMethodWithAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.method.prefix(), self.algorithm)
+    }
+}
+
+impl FromStr for ContentAddressMethodWithAlgo {
+    type Err = ContentAddressError;
+
+    fn from_str(s: &str) -> Result<Self, ContentAddressError> {
+        let (method, algorithm) = ContentAddressMethod::strip_prefix(s)
+            .ok_or_else(|| ContentAddressError::UnknownMethod(s.to_owned()))?;
+        let algorithm = algorithm
+            .parse()
+            .map_err(|error| ContentAddressError::Hash {
+                ca: s.to_owned(),
+                error,
+            })?;
+        if method == ContentAddressMethod::Text && algorithm != HashAlgorithm::Sha256 {
+            return Err(ContentAddressError::TextNotSha256(s.to_owned()));
+        }
+        Ok(ContentAddressMethodWithAlgo { method, algorithm })
+    }
+}
+
+/// A content address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentAddress {
+    /// `text:sha256:<hash>`; the hash is always sha256.
+    Text(Hash),
+    /// `fixed:<algorithm>:<hash>`
+    Flat(Hash),
+    /// `fixed:r:<algorithm>:<hash>`
+    Recursive(Hash),
+    /// `fixed:git:<algorithm>:<hash>`
+    Git(Hash),
+}
+
+impl ContentAddress {
+    pub fn new(method: ContentAddressMethod, hash: Hash) -> Result<Self, ContentAddressError> {
+        Ok(match method {
+            ContentAddressMethod::Text if hash.algorithm() != HashAlgorithm::Sha256 => {
+                return Err(ContentAddressError::TextNotSha256(format!(
+                    "{}{hash}",
+                    method.prefix()
+                )));
+            }
+            ContentAddressMethod::Text => ContentAddress::Text(hash),
+            ContentAddressMethod::Flat => ContentAddress::Flat(hash),
+            ContentAddressMethod::Recursive => ContentAddress::Recursive(hash),
+            ContentAddressMethod::Git => ContentAddress::Git(hash),
+        })
+    }
+
+    pub fn method(&self) -> ContentAddressMethod {
+        match self {
+            ContentAddress::Text(_) => ContentAddressMethod::Text,
+            ContentAddress::Flat(_) => ContentAddressMethod::Flat,
+            ContentAddress::Recursive(_) => ContentAddressMethod::Recursive,
+            ContentAddress::Git(_) => ContentAddressMethod::Git,
+        }
+    }
+
+    pub fn hash(&self) -> &Hash {
+        match self {
+            ContentAddress::Text(hash)
+            | ContentAddress::Flat(hash)
+            | ContentAddress::Recursive(hash)
+            | ContentAddress::Git(hash) => hash,
+        }
+    }
+
+    pub fn method_with_algo(&self) -> ContentAddressMethodWithAlgo {
+        ContentAddressMethodWithAlgo {
+            method: self.method(),
+            algorithm: self.hash().algorithm(),
+        }
+    }
+
+    /// Parses the wire form of a content address.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ContentAddressError> {
+        std::str::from_utf8(bytes)
+            .map_err(|_| ContentAddressError::NotUtf8(BStr::new(bytes).to_string()))?
+            .parse()
+    }
+}
+
+impl fmt::Display for ContentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            self.method().prefix(),
+            self.hash().encode(HashFormat::Nix32, true)
+        )
+    }
+}
+
+impl FromStr for ContentAddress {
+    type Err = ContentAddressError;
+
+    fn from_str(s: &str) -> Result<Self, ContentAddressError> {
+        let (method, hash) = ContentAddressMethod::strip_prefix(s)
+            .ok_or_else(|| ContentAddressError::UnknownMethod(s.to_owned()))?;
+        let hash = Hash::parse(hash).map_err(|error| ContentAddressError::Hash {
+            ca: s.to_owned(),
+            error,
+        })?;
+        ContentAddress::new(method, hash)
+            .map_err(|_| ContentAddressError::TextNotSha256(s.to_owned()))
+    }
+}
+
+/// A content address that may be missing, which is sent as an empty string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct OptionalContentAddress(pub Option<ContentAddress>);
+
+impl OptionalContentAddress {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ContentAddressError> {
+        if bytes.is_empty() {
+            Ok(OptionalContentAddress(None))
+        } else {
+            ContentAddress::from_bytes(bytes).map(|ca| OptionalContentAddress(Some(ca)))
+        }
+    }
+}
+
+impl From<Option<ContentAddress>> for OptionalContentAddress {
+    fn from(ca: Option<ContentAddress>) -> Self {
+        OptionalContentAddress(ca)
+    }
+}
+
+impl From<ContentAddress> for OptionalContentAddress {
+    fn from(ca: ContentAddress) -> Self {
+        OptionalContentAddress(Some(ca))
+    }
+}
+
+/// Serializes a value as the string that `Display` renders.
+fn serialize_display<S: serde::Serializer>(
+    value: impl fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    NixString::from(value.to_string()).serialize(serializer)
+}
+
+/// Deserializes a string, and parses it with `parse`.
+fn deserialize_parsed<'de, D: serde::Deserializer<'de>, T>(
+    deserializer: D,
+    parse: impl FnOnce(&[u8]) -> Result<T, ContentAddressError>,
+) -> Result<T, D::Error> {
+    let s = NixString::deserialize(deserializer)?;
+    parse(s.0.as_slice()).map_err(serde::de::Error::custom)
+}
+
+impl Serialize for ContentAddressMethodWithAlgo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentAddressMethodWithAlgo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, |bytes| {
+            std::str::from_utf8(bytes)
+                .map_err(|_| ContentAddressError::NotUtf8(BStr::new(bytes).to_string()))?
+                .parse()
+        })
+    }
+}
+
+impl Serialize for ContentAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, ContentAddress::from_bytes)
+    }
+}
+
+impl Serialize for OptionalContentAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Some(ca) => ca.serialize(serializer),
+            None => NixString::default().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OptionalContentAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, OptionalContentAddress::from_bytes)
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for ContentAddressMethodWithAlgo {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(ContentAddress::arbitrary(u)?.method_with_algo())
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for ContentAddress {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let method = ContentAddressMethod::arbitrary(u)?;
+        let algorithm = match method {
+            ContentAddressMethod::Text => HashAlgorithm::Sha256,
+            _ => u.arbitrary()?,
+        };
+        let hash =
+            Hash::new(algorithm, u.bytes(algorithm.size())?).expect("digest has the right size");
+        Ok(ContentAddress::new(method, hash).expect("text uses sha256"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "text:sha256:1vb2xd67750jrvqgykxrf5ipl3h1q57sxc57q5nzjw56bqp3sxzr";
+    const FLAT: &str = "fixed:sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0";
+    const RECURSIVE: &str = "fixed:r:sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh";
+
+    #[test]
+    fn parse_and_render() {
+        let text: ContentAddress = TEXT.parse().unwrap();
+        assert_eq!(text.method(), ContentAddressMethod::Text);
+        assert_eq!(text.hash().algorithm(), HashAlgorithm::Sha256);
+        let flat: ContentAddress = FLAT.parse().unwrap();
+        assert_eq!(flat.method(), ContentAddressMethod::Flat);
+        assert_eq!(flat.hash().algorithm(), HashAlgorithm::Sha1);
+        let recursive: ContentAddress = RECURSIVE.parse().unwrap();
+        assert_eq!(recursive.method_with_algo().to_string(), "fixed:r:sha256");
+        for ca in [TEXT, FLAT, RECURSIVE] {
+            assert_eq!(ca.parse::<ContentAddress>().unwrap().to_string(), ca);
+        }
+
+        // Hashes in other formats are accepted, but rendered in nix32.
+        let base16 = format!("fixed:git:{}", flat.hash().encode(HashFormat::Base16, true));
+        let git: ContentAddress = base16.parse().unwrap();
+        assert_eq!(git, ContentAddress::Git(*flat.hash()));
+        assert_eq!(
+            git.to_string(),
+            "fixed:git:sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0"
+        );
+
+        assert_eq!(
+            "fixed:git:sha1".parse(),
+            Ok(ContentAddressMethodWithAlgo {
+                method: ContentAddressMethod::Git,
+                algorithm: HashAlgorithm::Sha1
+            })
+        );
+        for s in ["r:sha256", "sha1", "text:sha256", "git:sha1"] {
+            let method_algo = ContentAddressMethodWithAlgo::parse_method_algo(s).unwrap();
+            assert_eq!(method_algo.render_method_algo(), s);
+        }
+        assert_eq!(
+            OptionalContentAddress::from_bytes(b""),
+            Ok(OptionalContentAddress(None))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<ContentAddress>().unwrap_err().to_string();
+        assert_eq!(
+            err("nar:sha256:abcd"),
+            "content address 'nar:sha256:abcd' has an unknown method"
+        );
+        assert_eq!(
+            err("text:sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0"),
+            "text content address 'text:sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0' must use sha256"
+        );
+        assert_eq!(
+            err("fixed:r:sha256:abcd"),
+            "invalid content address 'fixed:r:sha256:abcd': \
+             hash 'sha256:abcd' has wrong length for hash type 'sha256'"
+        );
+        assert_eq!(
+            "text:sha1".parse::<ContentAddressMethodWithAlgo>(),
+            Err(ContentAddressError::TextNotSha256("text:sha1".to_owned()))
+        );
+        assert_eq!(
+            "fixed:r:blake3"
+                .parse::<ContentAddressMethodWithAlgo>()
+                .unwrap_err()
+                .to_string(),
+            "invalid content address 'fixed:r:blake3': unknown hash algorithm 'blake3'"
+        );
+    }
+}
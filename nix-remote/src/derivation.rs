@@ -0,0 +1,961 @@
+//! Derivations, in the ATerm format of `.drv` files.
+//!
+//! A `.drv` file looks like
+//!
+//! ```text
+//! Derive([("out","/nix/store/<hash>-foo","","")],[("/nix/store/<hash>-bar.drv",["out"])],
+//!     ["/nix/store/<hash>-builder.sh"],"x86_64-linux","/bin/sh",["-e","builder.sh"],
+//!     [("name","foo"),("out","/nix/store/<hash>-foo")])
+//! ```
+//!
+//! (but all on one line). The wire protocol sends a [`worker_op::Derivation`] instead,
+//! which is the same thing without the input derivations.
+//!
+//! The output paths of a derivation are computed from the derivation itself, and
+//! [`DrvHashes`] does that computation so that they can be checked.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bstr::BStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    content_address::{ContentAddress, ContentAddressError, ContentAddressMethodWithAlgo},
+    hash::{Hash, HashAlgorithm, HashError, HashFormat},
+    store_path::{output_path_name, StoreDir, StorePathError},
+    worker_op, NixString, Path, Result, StorePath, StorePathSet, StringSet,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DerivationError {
+    #[error("expected '{expected}' at position {pos} of derivation")]
+    Expected { expected: String, pos: usize },
+
+    #[error("bad path '{0}' in derivation")]
+    BadPath(String),
+
+    #[error("path '{0}' is not a derivation")]
+    NotADerivation(String),
+
+    #[error("invalid derivation output ('{path}', '{method_algo}', '{hash}')")]
+    InvalidOutput {
+        path: String,
+        method_algo: String,
+        hash: String,
+    },
+
+    #[error("no hash for output '{output}' of derivation '{drv}'")]
+    MissingOutputHash { output: String, drv: String },
+
+    #[error("fixed-output derivation '{0}' has more than one output")]
+    MultipleFixedOutputs(String),
+
+    #[error("the fixed output '{output}' of derivation '{drv}' must be named 'out'")]
+    FixedOutputName { output: String, drv: String },
+
+    #[error("derivation '{drv}' has incorrect output '{actual}', should be '{expected}'")]
+    IncorrectOutput {
+        drv: String,
+        actual: String,
+        expected: String,
+    },
+
+    #[error(
+        "derivation '{drv}' has incorrect environment variable '{var}', should be '{expected}'"
+    )]
+    IncorrectEnv {
+        drv: String,
+        var: String,
+        expected: String,
+    },
+
+    #[error(transparent)]
+    Hash(#[from] HashError),
+
+    #[error(transparent)]
+    ContentAddress(#[from] ContentAddressError),
+
+    #[error(transparent)]
+    StorePath(#[from] StorePathError),
+}
+
+/// A derivation, as it is stored in a `.drv` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Derivation {
+    pub outputs: BTreeMap<NixString, DerivationOutput>,
+    /// The derivations whose outputs this one needs, and which of their outputs.
+    pub input_derivations: BTreeMap<StorePath, BTreeSet<NixString>>,
+    pub input_sources: BTreeSet<StorePath>,
+    pub platform: NixString,
+    pub builder: NixString,
+    pub args: Vec<NixString>,
+    pub env: BTreeMap<NixString, NixString>,
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> DerivationError {
+        DerivationError::Expected {
+            expected: expected.to_owned(),
+            pos: self.pos,
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), DerivationError> {
+        if self.input[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(token))
+        }
+    }
+
+    fn string(&mut self) -> Result<NixString, DerivationError> {
+        self.expect("\"")?;
+        let mut s = Vec::new();
+        loop {
+            let Some(&c) = self.input.get(self.pos) else {
+                return Err(self.error("\""));
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(s.into()),
+                b'\\' => {
+                    let Some(&c) = self.input.get(self.pos) else {
+                        return Err(self.error("\""));
+                    };
+                    self.pos += 1;
+                    s.push(match c {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        c => c,
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn path(&mut self) -> Result<StorePath, DerivationError> {
+        let path = self.string()?;
+        if !path.0.starts_with(b"/") {
+            return Err(DerivationError::BadPath(BStr::new(&path.0).to_string()));
+        }
+        Ok(StorePath(path))
+    }
+
+    /// Parses a list like `[a,b,c]`, using `item` for the elements.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, DerivationError>,
+    ) -> Result<Vec<T>, DerivationError> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        if self.expect("]").is_ok() {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.expect("]").is_ok() {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn output(&mut self) -> Result<(NixString, DerivationOutput), DerivationError> {
+        self.expect("(")?;
+        let name = self.string()?;
+        self.expect(",")?;
+        // Content-addressed outputs don't know their path yet.
+        let store_path = StorePath(self.string()?);
+        if !store_path.0 .0.is_empty() && !store_path.0 .0.starts_with(b"/") {
+            return Err(DerivationError::BadPath(
+                BStr::new(&store_path.0 .0).to_string(),
+            ));
+        }
+        self.expect(",")?;
+        let method_algo = self.string()?;
+        self.expect(",")?;
+        let hash = self.string()?;
+        self.expect(")")?;
+        let output = DerivationOutput::from_strings(store_path, &method_algo.0, &hash.0)?;
+        Ok((name, output))
+    }
+
+    fn input_derivation(&mut self) -> Result<(StorePath, BTreeSet<NixString>), DerivationError> {
+        self.expect("(")?;
+        let path = self.path()?;
+        self.expect(",")?;
+        let outputs = self.list(Self::string)?;
+        self.expect(")")?;
+        Ok((path, outputs.into_iter().collect()))
+    }
+
+    fn env_var(&mut self) -> Result<(NixString, NixString), DerivationError> {
+        self.expect("(")?;
+        let name = self.string()?;
+        self.expect(",")?;
+        let value = self.string()?;
+        self.expect(")")?;
+        Ok((name, value))
+    }
+}
+
+fn render_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'"');
+    for &c in s {
+        match c {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+}
+
+/// Renders a list like `[a,b,c]`, using `item` for the elements.
+fn render_list<T>(
+    out: &mut Vec<u8>,
+    items: impl IntoIterator<Item = T>,
+    item: impl Fn(&mut Vec<u8>, T),
+) {
+    out.push(b'[');
+    for (i, x) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        item(out, x);
+    }
+    out.push(b']');
+}
+
+impl Derivation {
+    /// Parses the contents of a `.drv` file.
+    pub fn parse(input: &[u8]) -> Result<Self, DerivationError> {
+        let mut p = Parser { input, pos: 0 };
+        p.expect("Derive(")?;
+        let outputs = p.list(Parser::output)?;
+        p.expect(",")?;
+        let input_derivations = p.list(Parser::input_derivation)?;
+        p.expect(",")?;
+        let input_sources = p.list(Parser::path)?;
+        p.expect(",")?;
+        let platform = p.string()?;
+        p.expect(",")?;
+        let builder = p.string()?;
+        p.expect(",")?;
+        let args = p.list(Parser::string)?;
+        p.expect(",")?;
+        let env = p.list(Parser::env_var)?;
+        p.expect(")")?;
+        if p.pos != input.len() {
+            return Err(p.error("end of derivation"));
+        }
+        Ok(Derivation {
+            outputs: outputs.into_iter().collect(),
+            input_derivations: input_derivations.into_iter().collect(),
+            input_sources: input_sources.into_iter().collect(),
+            platform,
+            builder,
+            args,
+            env: env.into_iter().collect(),
+        })
+    }
+
+    /// Renders the derivation in the format of `.drv` files.
+    pub fn render(&self) -> Vec<u8> {
+        let inputs = self
+            .input_derivations
+            .iter()
+            .map(|(path, outputs)| (path.0 .0.as_slice(), outputs));
+        self.render_with(false, inputs)
+    }
+
+    /// Renders the derivation with other input derivations, and optionally without its
+    /// output paths, for [`DrvHashes::hash_modulo`].
+    fn render_with<'a>(
+        &self,
+        mask_outputs: bool,
+        input_derivations: impl IntoIterator<Item = (&'a [u8], &'a BTreeSet<NixString>)>,
+    ) -> Vec<u8> {
+        let mut out = b"Derive(".to_vec();
+        render_list(&mut out, &self.outputs, |out, (name, output)| {
+            let (path, method_algo, hash) = output.to_strings();
+            out.push(b'(');
+            render_string(out, &name.0);
+            out.push(b',');
+            render_string(out, if mask_outputs { b"" } else { &path.0 .0 });
+            out.push(b',');
+            render_string(out, &method_algo.0);
+            out.push(b',');
+            render_string(out, &hash.0);
+            out.push(b')');
+        });
+        out.push(b',');
+        render_list(&mut out, input_derivations, |out, (path, outputs)| {
+            out.push(b'(');
+            render_string(out, path);
+            out.push(b',');
+            render_list(out, outputs, |out, name| render_string(out, &name.0));
+            out.push(b')');
+        });
+        out.push(b',');
+        render_list(&mut out, &self.input_sources, |out, path| {
+            render_string(out, &path.0 .0)
+        });
+        out.push(b',');
+        render_string(&mut out, &self.platform.0);
+        out.push(b',');
+        render_string(&mut out, &self.builder.0);
+        out.push(b',');
+        render_list(&mut out, &self.args, |out, arg| render_string(out, &arg.0));
+        out.push(b',');
+        render_list(&mut out, &self.env, |out, (name, value)| {
+            out.push(b'(');
+            render_string(out, &name.0);
+            out.push(b',');
+            let value: &[u8] = if mask_outputs && self.outputs.contains_key(name) {
+                b""
+            } else {
+                &value.0
+            };
+            render_string(out, value);
+            out.push(b')');
+        });
+        out.push(b')');
+        out
+    }
+
+    /// Is this a fixed-output derivation? See [`is_fixed_output`].
+    pub fn is_fixed_output(&self) -> bool {
+        is_fixed_output(&self.outputs)
+    }
+}
+
+/// Are `outputs` (by name) the outputs of a fixed-output derivation, whose contents
+/// are known in advance?
+///
+/// These are the derivations that are allowed to access the network. Like nix, this
+/// means a single fixed output called `out`.
+pub fn is_fixed_output<'a>(
+    outputs: impl IntoIterator<Item = (&'a NixString, &'a DerivationOutput)>,
+) -> bool {
+    let mut outputs = outputs.into_iter();
+    match (outputs.next(), outputs.next()) {
+        (Some((name, output)), None) => name.0 == b"out" && output.is_fixed(),
+        _ => false,
+    }
+}
+
+/// An output of a derivation.
+///
+/// In `.drv` files and on the wire, an output is three strings: its path, its method
+/// and hash algorithm (like `r:sha256`), and its hash. Which of them are empty says
+/// what kind of output it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivationOutput {
+    /// `(path, "", "")`: the path comes from the hash of the derivation.
+    InputAddressed(StorePath),
+    /// `(path, method_algo, hash)`: the contents are known in advance. The path follows
+    /// from `ca`, but nix sends it anyway so it is kept.
+    CAFixed { path: StorePath, ca: ContentAddress },
+    /// `("", method_algo, "")`: the path is only known once the output is built.
+    CAFloating(ContentAddressMethodWithAlgo),
+    /// `("", "", "")`: input-addressed, but the path isn't known yet because some input
+    /// is floating.
+    Deferred,
+    /// `("", method_algo, "impure")`: the output is different every time it is built.
+    Impure(ContentAddressMethodWithAlgo),
+}
+
+impl DerivationOutput {
+    /// Works out what kind of output the three strings of an output describe.
+    pub fn from_strings(
+        path: StorePath,
+        method_algo: &[u8],
+        hash: &[u8],
+    ) -> Result<Self, DerivationError> {
+        let invalid = || DerivationError::InvalidOutput {
+            path: BStr::new(&path.0 .0).to_string(),
+            method_algo: BStr::new(method_algo).to_string(),
+            hash: BStr::new(hash).to_string(),
+        };
+        let has_path = !path.0 .0.is_empty();
+        if method_algo.is_empty() {
+            return match (has_path, hash.is_empty()) {
+                (true, true) => Ok(DerivationOutput::InputAddressed(path)),
+                (false, true) => Ok(DerivationOutput::Deferred),
+                (_, false) => Err(invalid()),
+            };
+        }
+        let method_algo =
+            ContentAddressMethodWithAlgo::parse_method_algo(&BStr::new(method_algo).to_string())?;
+        match (has_path, hash) {
+            (false, b"") => Ok(DerivationOutput::CAFloating(method_algo)),
+            (false, b"impure") => Ok(DerivationOutput::Impure(method_algo)),
+            (true, hash) if !hash.is_empty() && hash != b"impure" => {
+                let hash =
+                    Hash::parse_unprefixed(&BStr::new(hash).to_string(), method_algo.algorithm)?;
+                let ca = ContentAddress::new(method_algo.method, hash)?;
+                Ok(DerivationOutput::CAFixed { path, ca })
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The path, method and hash algorithm, and hash of this output.
+    pub fn to_strings(&self) -> (StorePath, NixString, NixString) {
+        let none = || StorePath(NixString::default());
+        let method_algo = |m: &ContentAddressMethodWithAlgo| m.render_method_algo().into();
+        match self {
+            DerivationOutput::InputAddressed(path) => {
+                (path.clone(), NixString::default(), NixString::default())
+            }
+            DerivationOutput::CAFixed { path, ca } => (
+                path.clone(),
+                method_algo(&ca.method_with_algo()),
+                ca.hash().encode(HashFormat::Base16, false).into(),
+            ),
+            DerivationOutput::CAFloating(m) => (none(), method_algo(m), NixString::default()),
+            DerivationOutput::Deferred => (none(), NixString::default(), NixString::default()),
+            DerivationOutput::Impure(m) => (none(), method_algo(m), "impure".to_owned().into()),
+        }
+    }
+
+    /// The path of this output, if it is known before building it.
+    pub fn path(&self) -> Option<&StorePath> {
+        match self {
+            DerivationOutput::InputAddressed(path) | DerivationOutput::CAFixed { path, .. } => {
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Is this a fixed output, whose contents are known in advance?
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, DerivationOutput::CAFixed { .. })
+    }
+}
+
+impl Serialize for DerivationOutput {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_strings().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DerivationOutput {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (path, method_algo, hash) =
+            <(StorePath, NixString, NixString)>::deserialize(deserializer)?;
+        DerivationOutput::from_strings(path, &method_algo.0, &hash.0)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for DerivationOutput {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Paths can't be empty, or they would mean a different kind of output.
+        let path = |u: &mut arbitrary::Unstructured<'a>| -> arbitrary::Result<StorePath> {
+            let mut path: StorePath = u.arbitrary()?;
+            path.0 .0.insert(0, b'/');
+            Ok(path)
+        };
+        Ok(match u.int_in_range(0..=4)? {
+            0 => DerivationOutput::InputAddressed(path(u)?),
+            1 => DerivationOutput::CAFixed {
+                path: path(u)?,
+                ca: u.arbitrary()?,
+            },
+            2 => DerivationOutput::CAFloating(u.arbitrary()?),
+            3 => DerivationOutput::Deferred,
+            _ => DerivationOutput::Impure(u.arbitrary()?),
+        })
+    }
+}
+
+/// The hashes of a derivation's outputs, modulo fixed-output derivations.
+pub type OutputHashes = BTreeMap<NixString, Hash>;
+
+/// Computes the hashes that the output paths of input-addressed derivations come from.
+///
+/// The hash of a derivation depends on the hashes of its input derivations, so this
+/// reads them with `read_derivation` and remembers their hashes.
+pub struct DrvHashes<F> {
+    store_dir: StoreDir,
+    read_derivation: F,
+    hashes: BTreeMap<StorePath, OutputHashes>,
+}
+
+impl<F: FnMut(&StorePath) -> Result<Derivation>> DrvHashes<F> {
+    pub fn new(store_dir: StoreDir, read_derivation: F) -> Self {
+        DrvHashes {
+            store_dir,
+            read_derivation,
+            hashes: BTreeMap::new(),
+        }
+    }
+
+    /// The output hashes of the derivation at `drv_path`, like nix's
+    /// `pathDerivationModulo`.
+    pub fn path_hashes(&mut self, drv_path: &StorePath) -> Result<OutputHashes> {
+        if let Some(hashes) = self.hashes.get(drv_path) {
+            return Ok(hashes.clone());
+        }
+        let drv = (self.read_derivation)(drv_path)?;
+        let hashes = self.hash_modulo(&drv, &self.drv_name(drv_path)?, false)?;
+        self.hashes.insert(drv_path.clone(), hashes.clone());
+        Ok(hashes)
+    }
+
+    /// Hashes a derivation called `drv_name`, like nix's `hashDerivationModulo`.
+    ///
+    /// The outputs of a fixed-output derivation only depend on their content addresses.
+    /// Otherwise, all the outputs get the hash of the derivation, with the paths of
+    /// input derivations replaced by their hashes so that changing how a fixed output
+    /// is fetched doesn't change anything that depends on it. With `mask_outputs`, the
+    /// output paths are left out, which is how they are computed in the first place.
+    pub fn hash_modulo(
+        &mut self,
+        drv: &Derivation,
+        drv_name: &str,
+        mask_outputs: bool,
+    ) -> Result<OutputHashes> {
+        if drv.outputs.values().any(DerivationOutput::is_fixed) {
+            // Nix refuses anything but a lone fixed output called `out`, like
+            // `is_fixed_output` checks.
+            let mut outputs = drv.outputs.iter();
+            let (Some((name, DerivationOutput::CAFixed { ca, .. })), None) =
+                (outputs.next(), outputs.next())
+            else {
+                return Err(DerivationError::MultipleFixedOutputs(drv_name.to_owned()).into());
+            };
+            if name.0 != b"out" {
+                return Err(DerivationError::FixedOutputName {
+                    output: BStr::new(&name.0).to_string(),
+                    drv: drv_name.to_owned(),
+                }
+                .into());
+            }
+            let path_name = output_path_name(drv_name, "out");
+            let path = self.store_dir.make_fixed_output_path(&path_name, ca)?;
+            let s = format!(
+                "fixed:out:{}:{}:{}",
+                ca.method_with_algo().render_method_algo(),
+                ca.hash().encode(HashFormat::Base16, false),
+                BStr::new(&self.store_dir.print(&path).0 .0),
+            );
+            let mut hashes = OutputHashes::new();
+            hashes.insert(
+                name.clone(),
+                Hash::compute(HashAlgorithm::Sha256, s.as_bytes()),
+            );
+            return Ok(hashes);
+        }
+
+        let mut inputs = BTreeMap::<Vec<u8>, BTreeSet<NixString>>::new();
+        for (drv_path, outputs) in &drv.input_derivations {
+            let hashes = self.path_hashes(drv_path)?;
+            for output in outputs {
+                let hash =
+                    hashes
+                        .get(output)
+                        .ok_or_else(|| DerivationError::MissingOutputHash {
+                            output: BStr::new(&output.0).to_string(),
+                            drv: drv_name.to_owned(),
+                        })?;
+                let hash = hash.encode(HashFormat::Base16, false).into_bytes();
+                inputs.entry(hash).or_default().insert(output.clone());
+            }
+        }
+        let rendered = drv.render_with(
+            mask_outputs,
+            inputs
+                .iter()
+                .map(|(hash, outputs)| (hash.as_slice(), outputs)),
+        );
+        let hash = Hash::compute(HashAlgorithm::Sha256, &rendered);
+        Ok(drv
+            .outputs
+            .keys()
+            .map(|name| (name.clone(), hash))
+            .collect())
+    }
+
+    /// Checks that the output paths of the derivation at `drv_path`, and the
+    /// environment variables that point to them, are the ones that nix would compute.
+    ///
+    /// The derivation is read with `read_derivation`, because this needs all of it: one
+    /// that came over the wire (like in `BuildDerivation`) has lost its input
+    /// derivations, so it would hash to something else.
+    ///
+    /// Outputs that don't have a path yet (because they are content-addressed but not
+    /// fixed) aren't checked.
+    pub fn check_outputs(&mut self, drv_path: &StorePath) -> Result<()> {
+        let drv_name = self.drv_name(drv_path)?;
+        let drv = (self.read_derivation)(drv_path)?;
+        let mut hashes = None;
+        for (name, output) in &drv.outputs {
+            let output_name = BStr::new(&name.0).to_string();
+            let (actual, expected) = match output {
+                DerivationOutput::CAFixed { path, ca } => {
+                    let path_name = output_path_name(&drv_name, &output_name);
+                    (path, self.store_dir.make_fixed_output_path(&path_name, ca)?)
+                }
+                DerivationOutput::InputAddressed(path) => {
+                    if hashes.is_none() {
+                        hashes = Some(self.hash_modulo(&drv, &drv_name, true)?);
+                    }
+                    let hash = &hashes.as_ref().expect("just computed")[name];
+                    let expected =
+                        self.store_dir
+                            .make_output_path(&output_name, hash, &drv_name)?;
+                    (path, expected)
+                }
+                _ => continue,
+            };
+            let expected = self.store_dir.print(&expected);
+            let printed = || BStr::new(&expected.0 .0).to_string();
+            let drv_path = || BStr::new(&drv_path.0 .0).to_string();
+            if *actual != expected {
+                return Err(DerivationError::IncorrectOutput {
+                    drv: drv_path(),
+                    actual: BStr::new(&actual.0 .0).to_string(),
+                    expected: printed(),
+                }
+                .into());
+            }
+            if drv.env.get(name) != Some(&expected.0) {
+                return Err(DerivationError::IncorrectEnv {
+                    drv: drv_path(),
+                    var: output_name,
+                    expected: printed(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of a derivation, which is the name of its path without the `.drv`.
+    fn drv_name(&self, drv_path: &StorePath) -> Result<String> {
+        let parsed = self.store_dir.parse(drv_path)?;
+        let name = parsed.name().strip_suffix(".drv").ok_or_else(|| {
+            DerivationError::NotADerivation(BStr::new(&drv_path.0 .0).to_string())
+        })?;
+        Ok(name.to_owned())
+    }
+}
+
+/// A wire derivation has no input derivations.
+impl From<worker_op::Derivation> for Derivation {
+    fn from(drv: worker_op::Derivation) -> Self {
+        Derivation {
+            outputs: drv.outputs.into_iter().collect(),
+            input_derivations: BTreeMap::new(),
+            input_sources: drv.input_sources.paths.into_iter().collect(),
+            platform: drv.platform,
+            builder: drv.builder.0,
+            args: drv.args.paths,
+            env: drv.env.into_iter().collect(),
+        }
+    }
+}
+
+/// Leaves out the input derivations, which can't be sent on the wire. Like nix does for
+/// `BuildDerivation`, the outputs of the input derivations should be added to the input
+/// sources first.
+impl From<Derivation> for worker_op::Derivation {
+    fn from(drv: Derivation) -> Self {
+        worker_op::Derivation {
+            outputs: drv.outputs.into_iter().collect(),
+            input_sources: StorePathSet {
+                paths: drv.input_sources.into_iter().collect(),
+            },
+            platform: drv.platform,
+            builder: Path(drv.builder),
+            args: StringSet { paths: drv.args },
+            env: drv.env.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = r#"Derive([("out","/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1","","")],[("/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-bash-5.2.drv",["out"]),("/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-stdenv.drv",["dev","out"])],["/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-builder.sh"],"x86_64-linux","/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-bash-5.2/bin/bash",["-e","/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-builder.sh"],[("name","hello"),("out","/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"),("script","echo \"hi\\there\"\n\tdone")])"#;
+
+    #[test]
+    fn parse_and_render() {
+        let drv = Derivation::parse(HELLO.as_bytes()).unwrap();
+        assert_eq!(drv.outputs.len(), 1);
+        assert_eq!(drv.input_derivations.len(), 2);
+        let stdenv = StorePath(NixString::from_bytes(
+            b"/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-stdenv.drv",
+        ));
+        assert_eq!(drv.input_derivations[&stdenv].len(), 2);
+        assert_eq!(drv.args.len(), 2);
+        assert_eq!(
+            drv.env[&NixString::from_bytes(b"script")],
+            NixString::from_bytes(b"echo \"hi\\there\"\n\tdone")
+        );
+        assert_eq!(drv.render(), HELLO.as_bytes());
+    }
+
+    #[test]
+    fn fixed_output() {
+        let fod = r#"Derive([("out","/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-src.tar.gz","sha256","e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")],[],[],"builtin","builtin:fetchurl",[],[])"#;
+        let drv = Derivation::parse(fod.as_bytes()).unwrap();
+        let out = &drv.outputs[&NixString::from_bytes(b"out")];
+        assert!(matches!(
+            out,
+            DerivationOutput::CAFixed {
+                ca: ContentAddress::Flat(_),
+                ..
+            }
+        ));
+        assert_eq!(drv.render(), fod.as_bytes());
+
+        // Floating content-addressed outputs have no path yet.
+        let ca = r#"Derive([("out","","r:sha256","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        assert_eq!(
+            Derivation::parse(ca.as_bytes()).unwrap().render(),
+            ca.as_bytes()
+        );
+    }
+
+    #[test]
+    fn outputs() {
+        let path = || StorePath(NixString::from_bytes(b"/nix/store/foo"));
+        let none = || StorePath(NixString::default());
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let cases: [(StorePath, &str, &str); 5] = [
+            (path(), "", ""),
+            (path(), "r:sha256", hash),
+            (none(), "text:sha256", ""),
+            (none(), "", ""),
+            (none(), "r:sha256", "impure"),
+        ];
+        let outputs: Vec<_> = cases
+            .iter()
+            .map(|(p, m, h)| {
+                let output =
+                    DerivationOutput::from_strings(p.clone(), m.as_bytes(), h.as_bytes()).unwrap();
+                let (path, method_algo, hash) = output.to_strings();
+                assert_eq!(
+                    (&path, method_algo.0.as_slice(), hash.0.as_slice()),
+                    (p, m.as_bytes(), h.as_bytes())
+                );
+                output
+            })
+            .collect();
+        assert_eq!(outputs[0], DerivationOutput::InputAddressed(path()));
+        assert!(outputs[1].is_fixed());
+        assert_eq!(outputs[1].path(), Some(&path()));
+        assert!(matches!(outputs[2], DerivationOutput::CAFloating(_)));
+        assert_eq!(outputs[3], DerivationOutput::Deferred);
+        assert!(matches!(outputs[4], DerivationOutput::Impure(_)));
+        assert!(outputs.iter().filter(|o| o.is_fixed()).count() == 1);
+
+        for (p, m, h) in [
+            (path(), "", hash),
+            (none(), "r:sha256", hash),
+            (path(), "r:sha256", ""),
+            (path(), "r:sha256", "impure"),
+        ] {
+            assert!(matches!(
+                DerivationOutput::from_strings(p, m.as_bytes(), h.as_bytes()),
+                Err(DerivationError::InvalidOutput { .. })
+            ));
+        }
+        assert!(DerivationOutput::from_strings(path(), b"r:sha256", b"abc").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Derivation::parse(b"Derive([],[],[],\"x\",\"y\",[],[]"),
+            Err(DerivationError::Expected {
+                expected: ")".to_owned(),
+                pos: 29
+            })
+        );
+        assert_eq!(
+            Derivation::parse(b"Derive([],[],[\"foo\"],\"x\",\"y\",[],[])"),
+            Err(DerivationError::BadPath("foo".to_owned()))
+        );
+        assert!(Derivation::parse(b"Derive([],[],[],\"x\",\"y\",[],[]) ").is_err());
+        assert!(Derivation::parse(b"Derive([],[],[],\"x").is_err());
+    }
+
+    const SRC_DRV: &str = "/nix/store/00000000000000000000000000000000-src.tar.gz.drv";
+    const SRC: &str = r#"Derive([("out","/nix/store/jfkjc5c0hdbcjn3hsz8frxk264d3y06f-src.tar.gz","sha256","2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")],[],[],"builtin","builtin:fetchurl",[],[("name","src.tar.gz"),("out","/nix/store/jfkjc5c0hdbcjn3hsz8frxk264d3y06f-src.tar.gz"),("url","http://example.org/src.tar.gz")])"#;
+    const HELLO_DRV: &str = "/nix/store/11111111111111111111111111111111-hello.drv";
+    const USES_SRC: &str = r#"Derive([("dev","/nix/store/8ai03qbrvz12kkafgz5glb81v5018fx6-hello-dev","",""),("out","/nix/store/07fnc980af6kq9agp4f1m1pbqip15935-hello","","")],[("/nix/store/00000000000000000000000000000000-src.tar.gz.drv",["out"])],["/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-builder.sh"],"x86_64-linux","/bin/sh",["-e","/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-builder.sh"],[("builder","/bin/sh"),("dev","/nix/store/8ai03qbrvz12kkafgz5glb81v5018fx6-hello-dev"),("name","hello"),("out","/nix/store/07fnc980af6kq9agp4f1m1pbqip15935-hello"),("src","/nix/store/jfkjc5c0hdbcjn3hsz8frxk264d3y06f-src.tar.gz"),("system","x86_64-linux")])"#;
+
+    fn path(p: &str) -> StorePath {
+        StorePath(NixString::from_bytes(p.as_bytes()))
+    }
+
+    fn read_derivation(drv_path: &StorePath) -> Result<Derivation> {
+        assert_eq!(*drv_path, path(SRC_DRV));
+        Ok(Derivation::parse(SRC.as_bytes())?)
+    }
+
+    /// Checks the outputs of `drv`, pretending that it is at `drv_path`.
+    fn check_outputs(drv_path: &str, drv: &Derivation) -> Result<()> {
+        let mut hashes = DrvHashes::new(StoreDir::default(), |p: &StorePath| {
+            if *p == path(drv_path) {
+                Ok(drv.clone())
+            } else {
+                read_derivation(p)
+            }
+        });
+        hashes.check_outputs(&path(drv_path))
+    }
+
+    #[test]
+    fn output_paths() {
+        let src = Derivation::parse(SRC.as_bytes()).unwrap();
+        assert!(src.is_fixed_output());
+        let drv = Derivation::parse(USES_SRC.as_bytes()).unwrap();
+        assert!(!drv.is_fixed_output());
+
+        check_outputs(SRC_DRV, &src).unwrap();
+        check_outputs(HELLO_DRV, &drv).unwrap();
+        let mut hashes = DrvHashes::new(StoreDir::default(), read_derivation);
+        let masked = hashes.hash_modulo(&drv, "hello", true).unwrap();
+        assert_eq!(
+            masked[&NixString::from_bytes(b"out")].encode(HashFormat::Base16, false),
+            "6b90c34b1c358e21eac1052ed711b95cfb49d9a4d56a691787ed41164a9a636b"
+        );
+
+        // Changing how the fixed output is fetched doesn't change anything downstream.
+        let mut mirror = src.clone();
+        mirror.env.insert(
+            NixString::from_bytes(b"url"),
+            NixString::from_bytes(b"http://mirror.example.org/src.tar.gz"),
+        );
+        let mut other = DrvHashes::new(StoreDir::default(), |_: &StorePath| Ok(mirror.clone()));
+        assert_eq!(other.hash_modulo(&drv, "hello", true).unwrap(), masked);
+
+        // A derivation that came over the wire doesn't have its input derivations, so
+        // its outputs can't be checked without reading the whole thing.
+        let wire = Derivation::from(worker_op::Derivation::from(drv.clone()));
+        assert_ne!(hashes.hash_modulo(&wire, "hello", true).unwrap(), masked);
+
+        let out = NixString::from_bytes(b"out");
+        let mut wrong = drv.clone();
+        wrong.outputs.insert(
+            out.clone(),
+            DerivationOutput::InputAddressed(path(
+                "/nix/store/pzhj6mmwcrncsx978f1yb8n2qv2lyz75-hello",
+            )),
+        );
+        let err = check_outputs(HELLO_DRV, &wrong).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "derivation '/nix/store/11111111111111111111111111111111-hello.drv' has incorrect \
+             output '/nix/store/pzhj6mmwcrncsx978f1yb8n2qv2lyz75-hello', should be \
+             '/nix/store/07fnc980af6kq9agp4f1m1pbqip15935-hello'"
+        );
+
+        let mut wrong = drv.clone();
+        wrong
+            .env
+            .insert(out.clone(), NixString::from_bytes(b"/tmp"));
+        assert!(matches!(
+            check_outputs(HELLO_DRV, &wrong),
+            Err(crate::Error::Derivation(
+                DerivationError::IncorrectEnv { .. }
+            ))
+        ));
+
+        // The fixed output's hash decides its path.
+        let mut wrong = src.clone();
+        let src_out = src.outputs[&out].path().unwrap().clone();
+        wrong.outputs.insert(
+            out.clone(),
+            DerivationOutput::from_strings(src_out, b"sha256", &[b'0'; 64]).unwrap(),
+        );
+        assert!(matches!(
+            check_outputs(SRC_DRV, &wrong),
+            Err(crate::Error::Derivation(
+                DerivationError::IncorrectOutput { .. }
+            ))
+        ));
+
+        assert!(matches!(
+            check_outputs("/nix/store/11111111111111111111111111111111-hello", &drv),
+            Err(crate::Error::Derivation(DerivationError::NotADerivation(_)))
+        ));
+    }
+
+    #[test]
+    fn fixed_output_shape() {
+        let src = Derivation::parse(SRC.as_bytes()).unwrap();
+        let out = NixString::from_bytes(b"out");
+        let mut hashes = DrvHashes::new(StoreDir::default(), read_derivation);
+
+        // A fixed output can't come with other outputs...
+        let mut multiple = src.clone();
+        multiple.outputs.insert(
+            NixString::from_bytes(b"dev"),
+            DerivationOutput::InputAddressed(path(
+                "/nix/store/8ai03qbrvz12kkafgz5glb81v5018fx6-src.tar.gz-dev",
+            )),
+        );
+        assert!(!multiple.is_fixed_output());
+        assert!(!worker_op::Derivation::from(multiple.clone()).is_fixed_output());
+        assert!(matches!(
+            hashes.hash_modulo(&multiple, "src.tar.gz", false),
+            Err(crate::Error::Derivation(
+                DerivationError::MultipleFixedOutputs(_)
+            ))
+        ));
+
+        // ...and has to be called `out`.
+        let mut misnamed = src.clone();
+        let output = misnamed.outputs.remove(&out).unwrap();
+        misnamed
+            .outputs
+            .insert(NixString::from_bytes(b"src"), output);
+        assert!(!misnamed.is_fixed_output());
+        assert!(!worker_op::Derivation::from(misnamed.clone()).is_fixed_output());
+        assert!(matches!(
+            hashes.hash_modulo(&misnamed, "src.tar.gz", false),
+            Err(crate::Error::Derivation(
+                DerivationError::FixedOutputName { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn wire() {
+        let drv = Derivation::parse(HELLO.as_bytes()).unwrap();
+        let wire = worker_op::Derivation::from(drv.clone());
+        assert_eq!(wire.input_sources.paths.len(), 1);
+        assert_eq!(wire.env.len(), 3);
+
+        let back = Derivation::from(wire);
+        assert!(back.input_derivations.is_empty());
+        assert_eq!(
+            back,
+            Derivation {
+                input_derivations: BTreeMap::new(),
+                ..drv
+            }
+        );
+    }
+}
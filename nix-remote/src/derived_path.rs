@@ -0,0 +1,267 @@
+//! Derived paths: the things that can be asked for in `BuildPaths` and friends.
+//!
+//! A derived path is either a store path that should already exist ("opaque"), or some
+//! outputs of a derivation that should be built. Since protocol version 1.30 they are
+//! written like `/nix/store/<hash>-foo`, `/nix/store/<hash>-foo.drv!*` or
+//! `/nix/store/<hash>-foo.drv!out,dev`. Before that, there was no way to ask for a
+//! derivation itself, and `/nix/store/<hash>-foo.drv` meant all of its outputs.
+
+use std::{collections::BTreeSet, fmt};
+
+use bstr::BStr;
+use serde::{de, ser};
+
+use crate::{
+    serialize::{versioned_serde, Versioned},
+    DaemonVersion, NixString, StorePath,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DerivedPathError {
+    #[error("derived path '{0}' has an empty output name")]
+    EmptyOutputName(String),
+
+    #[error("derived path '{0}' can't be sent with protocol versions before 1.30")]
+    TooOld(String),
+}
+
+/// Which outputs of a derivation are wanted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OutputsSpec {
+    /// All of them, written as `*`.
+    All,
+    Names(BTreeSet<NixString>),
+}
+
+impl OutputsSpec {
+    /// Is `name` one of the wanted outputs?
+    pub fn contains(&self, name: &NixString) -> bool {
+        match self {
+            OutputsSpec::All => true,
+            OutputsSpec::Names(names) => names.contains(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DerivedPath {
+    /// A path that just needs to be valid.
+    Opaque(StorePath),
+    /// Outputs of a derivation, which may need to be built.
+    Built {
+        drv_path: StorePath,
+        outputs: OutputsSpec,
+    },
+}
+
+impl DerivedPath {
+    /// Parses a derived path in the current format.
+    pub fn parse(s: &[u8]) -> Result<Self, DerivedPathError> {
+        let Some(sep) = s.iter().position(|&b| b == b'!') else {
+            return Ok(DerivedPath::Opaque(StorePath(NixString::from_bytes(s))));
+        };
+        let (drv_path, outputs) = (&s[..sep], &s[sep + 1..]);
+        let outputs = if outputs == b"*" {
+            OutputsSpec::All
+        } else {
+            let names = outputs
+                .split(|&b| b == b',')
+                .map(|name| {
+                    if name.is_empty() {
+                        Err(DerivedPathError::EmptyOutputName(BStr::new(s).to_string()))
+                    } else {
+                        Ok(NixString::from_bytes(name))
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            OutputsSpec::Names(names)
+        };
+        Ok(DerivedPath::Built {
+            drv_path: StorePath(NixString::from_bytes(drv_path)),
+            outputs,
+        })
+    }
+
+    /// Parses a derived path in the format of protocol versions before 1.30.
+    pub fn parse_legacy(s: &[u8]) -> Result<Self, DerivedPathError> {
+        match DerivedPath::parse(s)? {
+            DerivedPath::Opaque(path) if path.0 .0.ends_with(b".drv") => Ok(DerivedPath::Built {
+                drv_path: path,
+                outputs: OutputsSpec::All,
+            }),
+            path => Ok(path),
+        }
+    }
+
+    /// Renders a derived path in the current format.
+    pub fn render(&self) -> NixString {
+        match self {
+            DerivedPath::Opaque(path) => path.0.clone(),
+            DerivedPath::Built { drv_path, outputs } => {
+                let mut s = drv_path.0 .0.to_vec();
+                s.push(b'!');
+                match outputs {
+                    OutputsSpec::All => s.push(b'*'),
+                    OutputsSpec::Names(names) => {
+                        let names: Vec<&[u8]> = names.iter().map(|n| n.0.as_slice()).collect();
+                        s.extend_from_slice(&names.join(&b','));
+                    }
+                }
+                s.into()
+            }
+        }
+    }
+
+    /// Renders a derived path in the format of protocol versions before 1.30, which
+    /// can't ask for a derivation itself.
+    pub fn render_legacy(&self) -> Result<NixString, DerivedPathError> {
+        match self {
+            DerivedPath::Opaque(path) if path.0 .0.ends_with(b".drv") => {
+                Err(DerivedPathError::TooOld(self.to_string()))
+            }
+            DerivedPath::Built {
+                drv_path,
+                outputs: OutputsSpec::All,
+            } => Ok(drv_path.0.clone()),
+            path => Ok(path.render()),
+        }
+    }
+
+    /// The store path that this refers to: the path itself, or the derivation.
+    pub fn store_path(&self) -> &StorePath {
+        match self {
+            DerivedPath::Opaque(path) => path,
+            DerivedPath::Built { drv_path, .. } => drv_path,
+        }
+    }
+}
+
+impl fmt::Display for DerivedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", BStr::new(&self.render().0))
+    }
+}
+
+impl Versioned for DerivedPath {
+    fn serialize_versioned<S: ser::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let s = if version.minor >= 30 {
+            self.render()
+        } else {
+            self.render_legacy().map_err(ser::Error::custom)?
+        };
+        ser::Serialize::serialize(&s, serializer)
+    }
+
+    fn deserialize_versioned<'de, D: de::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let s: NixString = de::Deserialize::deserialize(deserializer)?;
+        if version.minor >= 30 {
+            DerivedPath::parse(&s.0)
+        } else {
+            DerivedPath::parse_legacy(&s.0)
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+versioned_serde!(DerivedPath);
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for DerivedPath {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Only generate paths that render unambiguously.
+        fn name(u: &mut arbitrary::Unstructured<'_>) -> arbitrary::Result<String> {
+            let name: String = u.arbitrary()?;
+            let name: String = name.chars().filter(char::is_ascii_alphanumeric).collect();
+            Ok(if name.is_empty() {
+                "out".to_owned()
+            } else {
+                name
+            })
+        }
+        let path = |name: String| StorePath(format!("/nix/store/{name}").into());
+        if u.arbitrary()? {
+            return Ok(DerivedPath::Opaque(path(name(u)?)));
+        }
+        let outputs = if u.arbitrary()? {
+            OutputsSpec::All
+        } else {
+            let names = (0..u.int_in_range(1..=3)?)
+                .map(|_| name(u).map(NixString::from))
+                .collect::<arbitrary::Result<_>>()?;
+            OutputsSpec::Names(names)
+        };
+        Ok(DerivedPath::Built {
+            drv_path: path(format!("{}.drv", name(u)?)),
+            outputs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn built(names: &[&str]) -> DerivedPath {
+        DerivedPath::Built {
+            drv_path: StorePath(NixString::from_bytes(b"/nix/store/x-foo.drv")),
+            outputs: OutputsSpec::Names(names.iter().map(|n| n.to_string().into()).collect()),
+        }
+    }
+
+    #[test]
+    fn parse_and_render() {
+        let opaque = DerivedPath::parse(b"/nix/store/x-foo").unwrap();
+        assert_eq!(
+            opaque,
+            DerivedPath::Opaque(StorePath(NixString::from_bytes(b"/nix/store/x-foo")))
+        );
+        let drv = DerivedPath::parse(b"/nix/store/x-foo.drv").unwrap();
+        assert!(matches!(drv, DerivedPath::Opaque(_)));
+        let all = DerivedPath::parse(b"/nix/store/x-foo.drv!*").unwrap();
+        assert!(matches!(
+            all,
+            DerivedPath::Built {
+                outputs: OutputsSpec::All,
+                ..
+            }
+        ));
+        let names = DerivedPath::parse(b"/nix/store/x-foo.drv!out,dev").unwrap();
+        assert_eq!(names, built(&["dev", "out"]));
+
+        for path in [&opaque, &drv, &all, &names] {
+            assert_eq!(DerivedPath::parse(&path.render().0).unwrap(), *path);
+        }
+        assert_eq!(names.to_string(), "/nix/store/x-foo.drv!dev,out");
+        assert_eq!(
+            DerivedPath::parse(b"/nix/store/x-foo.drv!out,"),
+            Err(DerivedPathError::EmptyOutputName(
+                "/nix/store/x-foo.drv!out,".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn legacy() {
+        assert_eq!(
+            DerivedPath::parse_legacy(b"/nix/store/x-foo.drv").unwrap(),
+            DerivedPath::parse(b"/nix/store/x-foo.drv!*").unwrap()
+        );
+        assert_eq!(
+            DerivedPath::parse_legacy(b"/nix/store/x-foo.drv!out").unwrap(),
+            built(&["out"])
+        );
+        assert_eq!(
+            built(&["out"]).render_legacy().unwrap().0.as_slice(),
+            b"/nix/store/x-foo.drv!out"
+        );
+        let drv = DerivedPath::parse(b"/nix/store/x-foo.drv").unwrap();
+        assert!(drv.render_legacy().is_err());
+    }
+}
@@ -17,7 +17,7 @@ use crate::Result;
 ///
 /// The whole point of this is that it is big enough that you don't want to hold it in
 /// memory all at once. Therefore, this struct might not be ideal for "production" use;
-/// see the [`stream`] function instead.
+/// see [`FramedReader`] instead.
 #[derive(Clone, Default)]
 pub struct FramedData {
     pub data: Vec<ByteBuf>,
@@ -32,7 +32,7 @@ impl std::fmt::Debug for FramedData {
 impl FramedData {
     #[tracing::instrument(skip(r), err)]
     pub fn read(mut r: impl Read) -> Result<FramedData> {
-        let mut de = crate::serialize::NixDeserializer { read: &mut r };
+        let mut de = crate::serialize::NixDeserializer::new(&mut r);
 
         let mut ret = FramedData::default();
         loop {
@@ -51,7 +51,7 @@ impl FramedData {
 
     #[tracing::instrument(skip(self, w), err)]
     pub fn write(&self, mut w: impl Write) -> Result<()> {
-        let mut ser = crate::serialize::NixSerializer { write: &mut w };
+        let mut ser = crate::serialize::NixSerializer::new(&mut w);
 
         for data in &self.data {
             tracing::trace!(len = ?data.len(), "FramedData write");
@@ -62,3 +62,60 @@ impl FramedData {
         Ok(())
     }
 }
+
+/// Reads framed data as a stream, without holding it in memory.
+///
+/// Reading stops at the end of the framed data, leaving the underlying reader positioned
+/// just after it. If you don't need all of the data, call [`FramedReader::drain`] to get
+/// there anyway.
+pub struct FramedReader<R> {
+    read: R,
+    // The number of bytes left in the current frame.
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(read: R) -> Self {
+        FramedReader {
+            read,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Skip the rest of the framed data.
+    pub fn drain(&mut self) -> std::io::Result<()> {
+        std::io::copy(self, &mut std::io::sink())?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FramedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remaining == 0 {
+            if self.done {
+                return Ok(0);
+            }
+            let mut len = [0; 8];
+            self.read.read_exact(&mut len)?;
+            let len = u64::from_le_bytes(len);
+            tracing::trace!(?len, "FramedReader read");
+            if len == 0 {
+                self.done = true;
+            }
+            self.remaining = len as usize;
+        }
+
+        let len = buf.len().min(self.remaining);
+        let n = self.read.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
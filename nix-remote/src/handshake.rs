@@ -0,0 +1,278 @@
+//! The messages exchanged when a connection is opened.
+//!
+//! The handshake goes like this:
+//! - the client sends [`WORKER_MAGIC_1`],
+//! - the daemon sends [`WORKER_MAGIC_2`] and the newest protocol version that it speaks,
+//! - the client sends the newest protocol version that it speaks. From now on, both sides
+//!   use the older of the two versions.
+//! - since 1.38, the client sends its features and then the daemon sends its features,
+//! - the client sends the obsolete cpu affinity and reserve-space fields,
+//! - the daemon sends its version string (since 1.33) and whether it trusts the client
+//!   (since 1.35),
+//! - the daemon sends stderr messages, ending with [`Msg::Last`](crate::stderr::Msg::Last).
+//!
+//! The two sides take turns, so neither hello can be sent all at once. But everything
+//! that one side sends (apart from the final stderr messages) is captured by a
+//! [`ClientHello`] or a [`ServerHello`], and serializing one of those with the negotiated
+//! version produces exactly the bytes that went over the wire. This makes them useful for
+//! logging and for building test fixtures.
+
+use std::collections::BTreeSet;
+
+use serde::{ser::SerializeTuple, Deserialize, Serialize};
+use tagged_serde::TaggedSerde;
+
+use crate::serialize::{self, versioned_serde, SeqAccessExt, Versioned};
+use crate::stderr::StderrError;
+use crate::{
+    supported_features, DaemonVersion, NixString, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2,
+};
+
+/// Everything that can go wrong during the handshake.
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("bad magic number {got:#x}, expected {expected:#x}")]
+    BadMagic { expected: u64, got: u64 },
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(DaemonVersion),
+
+    #[error("connection closed during the handshake")]
+    Eof,
+
+    #[error("daemon error during the handshake: {}", .0.message)]
+    Daemon(StderrError),
+
+    #[error("I/O error during the handshake: {0}")]
+    Io(std::io::Error),
+
+    #[error("(De)serialization error during the handshake: {0}")]
+    Deser(serialize::Error),
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            HandshakeError::Eof
+        } else {
+            HandshakeError::Io(e)
+        }
+    }
+}
+
+impl From<serialize::Error> for HandshakeError {
+    fn from(e: serialize::Error) -> Self {
+        match e {
+            serialize::Error::Io(e) => e.into(),
+            e => HandshakeError::Deser(e),
+        }
+    }
+}
+
+/// Whether the daemon trusts the client.
+///
+/// On the wire, this is an optional boolean. Nix uses "unknown" when it is just forwarding
+/// to another daemon and doesn't know what that daemon thinks.
+#[derive(Debug, TaggedSerde, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrustedFlag {
+    #[default]
+    #[tagged_serde = 0]
+    Unknown,
+    #[tagged_serde = 1]
+    Trusted,
+    #[tagged_serde = 2]
+    NotTrusted,
+}
+
+/// Everything that the client sends during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Always [`WORKER_MAGIC_1`] if the client is well-behaved.
+    pub magic: u64,
+    /// The newest protocol version that the client speaks.
+    pub version: DaemonVersion,
+    /// The protocol features that the client supports. Since 1.38.
+    pub features: BTreeSet<NixString>,
+    /// Obsolete, and ignored by the daemon.
+    pub cpu_affinity: Option<u64>,
+    /// Obsolete, and ignored by the daemon.
+    pub reserve_space: bool,
+}
+
+impl Default for ClientHello {
+    /// The hello that we send.
+    fn default() -> Self {
+        ClientHello {
+            magic: WORKER_MAGIC_1,
+            version: PROTOCOL_VERSION,
+            features: supported_features(),
+            cpu_affinity: None,
+            reserve_space: false,
+        }
+    }
+}
+
+/// Everything that the daemon sends during the handshake, not counting the stderr messages
+/// at the end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerHello {
+    /// Always [`WORKER_MAGIC_2`] if the daemon is well-behaved.
+    pub magic: u64,
+    /// The newest protocol version that the daemon speaks.
+    pub version: DaemonVersion,
+    /// The protocol features that the daemon supports. Since 1.38.
+    pub features: BTreeSet<NixString>,
+    /// A human-readable description of the daemon, like "2.18.1". Since 1.33.
+    pub daemon_version: NixString,
+    /// Whether the daemon trusts the client. Since 1.35.
+    pub trusted: TrustedFlag,
+}
+
+impl Default for ServerHello {
+    /// The hello that we send.
+    fn default() -> Self {
+        ServerHello {
+            magic: WORKER_MAGIC_2,
+            version: PROTOCOL_VERSION,
+            features: supported_features(),
+            daemon_version: NixString::from_bytes(b"rust-nix-bazel-0.1.0"),
+            trusted: TrustedFlag::Trusted,
+        }
+    }
+}
+
+// The cpu affinity is a flag, followed by the actual affinity if the flag was set.
+struct OptionalAffinity<'a>(&'a Option<u64>);
+
+impl Serialize for OptionalAffinity<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            None => 0u64.serialize(serializer),
+            Some(affinity) => (1u64, affinity).serialize(serializer),
+        }
+    }
+}
+
+impl Versioned for ClientHello {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(usize::MAX)?;
+        tup.serialize_element(&self.magic)?;
+        tup.serialize_element(&self.version)?;
+        if version.minor >= 38 {
+            tup.serialize_element(&self.features)?;
+        }
+        tup.serialize_element(&OptionalAffinity(&self.cpu_affinity))?;
+        tup.serialize_element(&self.reserve_space)?;
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct Visitor(DaemonVersion);
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ClientHello;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("ClientHello")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ClientHello, A::Error> {
+                let magic = seq.expect_element()?;
+                let version = seq.expect_element()?;
+                let features = if self.0.minor >= 38 {
+                    seq.expect_element()?
+                } else {
+                    BTreeSet::new()
+                };
+                let cpu_affinity = if seq.expect_element::<u64>()? != 0 {
+                    Some(seq.expect_element()?)
+                } else {
+                    None
+                };
+                Ok(ClientHello {
+                    magic,
+                    version,
+                    features,
+                    cpu_affinity,
+                    reserve_space: seq.expect_element()?,
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, Visitor(version))
+    }
+}
+
+impl Versioned for ServerHello {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(usize::MAX)?;
+        tup.serialize_element(&self.magic)?;
+        tup.serialize_element(&self.version)?;
+        if version.minor >= 38 {
+            tup.serialize_element(&self.features)?;
+        }
+        if version.minor >= 33 {
+            tup.serialize_element(&self.daemon_version)?;
+        }
+        if version.minor >= 35 {
+            tup.serialize_element(&self.trusted)?;
+        }
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct Visitor(DaemonVersion);
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ServerHello;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("ServerHello")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ServerHello, A::Error> {
+                let mut ret = ServerHello {
+                    magic: seq.expect_element()?,
+                    version: seq.expect_element()?,
+                    features: BTreeSet::new(),
+                    daemon_version: NixString::default(),
+                    trusted: TrustedFlag::Unknown,
+                };
+                if self.0.minor >= 38 {
+                    ret.features = seq.expect_element()?;
+                }
+                if self.0.minor >= 33 {
+                    ret.daemon_version = seq.expect_element()?;
+                }
+                if self.0.minor >= 35 {
+                    ret.trusted = seq.expect_element()?;
+                }
+                Ok(ret)
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, Visitor(version))
+    }
+}
+
+versioned_serde!(ClientHello, ServerHello);
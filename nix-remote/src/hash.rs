@@ -0,0 +1,495 @@
+//! Hashes, in all the ways that nix writes them.
+//!
+//! Nix writes hashes in base16, in its own [base32](crate::base32), or in base64, usually
+//! with the algorithm in front (like `sha256:0mdqa9w1...`). It also understands SRI
+//! hashes, like `sha256-47DEQpj8...`. On the wire, nar hashes are base16 without the
+//! algorithm.
+
+use std::{fmt, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bstr::BStr;
+use sha2::Digest;
+
+use crate::{base32, NarHash, NixString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The name, as nix writes it.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// The size of the digest, in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, HashError> {
+        match s {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(HashError::UnknownAlgorithm(s.to_owned())),
+        }
+    }
+}
+
+/// The ways of writing a hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFormat {
+    Base16,
+    /// Nix's own base32.
+    Nix32,
+    Base64,
+    /// Like `sha256-<base64>`.
+    Sri,
+}
+
+impl fmt::Display for HashFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashFormat::Base16 => "base16",
+            HashFormat::Nix32 => "nix32",
+            HashFormat::Base64 => "base64",
+            HashFormat::Sri => "SRI",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum HashError {
+    #[error("unknown hash algorithm '{0}'")]
+    UnknownAlgorithm(String),
+
+    #[error("hash '{0}' does not include a type")]
+    MissingAlgorithm(String),
+
+    #[error("hash '{hash}' should have type '{expected}'")]
+    WrongAlgorithm {
+        hash: String,
+        expected: HashAlgorithm,
+    },
+
+    #[error("hash '{hash}' has wrong length for hash type '{algorithm}'")]
+    WrongLength {
+        hash: String,
+        algorithm: HashAlgorithm,
+    },
+
+    #[error("invalid {format} encoding in hash '{hash}'")]
+    InvalidEncoding { hash: String, format: HashFormat },
+}
+
+/// A digest, together with the algorithm that made it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hash {
+    algorithm: HashAlgorithm,
+    /// Only the first `algorithm.size()` bytes are used; the rest are zero.
+    digest: [u8; 64],
+}
+
+impl Hash {
+    /// A hash with the given digest, which must have the right size for `algorithm`.
+    pub fn new(algorithm: HashAlgorithm, digest: &[u8]) -> Result<Self, HashError> {
+        if digest.len() != algorithm.size() {
+            return Err(HashError::WrongLength {
+                hash: base16(digest),
+                algorithm,
+            });
+        }
+        let mut hash = Hash {
+            algorithm,
+            digest: [0; 64],
+        };
+        hash.digest[..digest.len()].copy_from_slice(digest);
+        Ok(hash)
+    }
+
+    /// Hashes `data`.
+    pub fn compute(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest[..self.algorithm.size()]
+    }
+
+    /// Parses a hash that says what its algorithm is: either `<algorithm>:<hash>`, with
+    /// the hash in base16, nix32 or base64, or an SRI hash.
+    pub fn parse(s: &str) -> Result<Self, HashError> {
+        Hash::parse_any(s, None)
+    }
+
+    /// Parses a hash like [`Hash::parse`], but also without an algorithm, if `algorithm`
+    /// is given. If both the hash and `algorithm` specify the algorithm, they must agree.
+    pub fn parse_any(s: &str, algorithm: Option<HashAlgorithm>) -> Result<Self, HashError> {
+        let check = |found: HashAlgorithm| match algorithm {
+            Some(expected) if expected != found => Err(HashError::WrongAlgorithm {
+                hash: s.to_owned(),
+                expected,
+            }),
+            _ => Ok(found),
+        };
+        if let Some((name, rest)) = s.split_once(':') {
+            let found = check(name.parse()?)?;
+            return Hash::parse_unprefixed(rest, found).map_err(|e| e.with_hash(s));
+        }
+        if let Some((name, rest)) = s.split_once('-') {
+            if let Ok(found) = name.parse() {
+                let found = check(found)?;
+                return Hash::decode(rest, found, HashFormat::Base64)
+                    .map_err(|e| e.with_hash(s).with_format(HashFormat::Sri));
+            }
+        }
+        match algorithm {
+            Some(algorithm) => Hash::parse_unprefixed(s, algorithm),
+            None => Err(HashError::MissingAlgorithm(s.to_owned())),
+        }
+    }
+
+    /// Parses a hash without an algorithm, guessing the encoding from the length.
+    pub fn parse_unprefixed(s: &str, algorithm: HashAlgorithm) -> Result<Self, HashError> {
+        let size = algorithm.size();
+        let format = if s.len() == 2 * size {
+            HashFormat::Base16
+        } else if s.len() == base32::encoded_len(size) {
+            HashFormat::Nix32
+        } else if s.len() == size.div_ceil(3) * 4 {
+            HashFormat::Base64
+        } else {
+            return Err(HashError::WrongLength {
+                hash: s.to_owned(),
+                algorithm,
+            });
+        };
+        Hash::decode(s, algorithm, format)
+    }
+
+    fn decode(s: &str, algorithm: HashAlgorithm, format: HashFormat) -> Result<Self, HashError> {
+        let invalid = || HashError::InvalidEncoding {
+            hash: s.to_owned(),
+            format,
+        };
+        let digest = match format {
+            HashFormat::Base16 => {
+                if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(invalid());
+                }
+                (0..s.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid()))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            HashFormat::Nix32 => base32::decode(s.as_bytes()).map_err(|_| invalid())?,
+            HashFormat::Base64 | HashFormat::Sri => STANDARD.decode(s).map_err(|_| invalid())?,
+        };
+        Hash::new(algorithm, &digest).map_err(|e| e.with_hash(s))
+    }
+
+    /// Writes the hash in `format`, optionally with `<algorithm>:` in front.
+    ///
+    /// SRI hashes always include the algorithm.
+    pub fn encode(&self, format: HashFormat, with_algorithm: bool) -> String {
+        let digest = match format {
+            HashFormat::Base16 => base16(self.digest()),
+            HashFormat::Nix32 => base32::encode(self.digest()),
+            HashFormat::Base64 => STANDARD.encode(self.digest()),
+            HashFormat::Sri => {
+                return format!("{}-{}", self.algorithm, STANDARD.encode(self.digest()))
+            }
+        };
+        if with_algorithm {
+            format!("{}:{digest}", self.algorithm)
+        } else {
+            digest
+        }
+    }
+
+    pub fn to_sri(&self) -> String {
+        self.encode(HashFormat::Sri, true)
+    }
+
+    /// Parses a hash from the wire, like `ValidPathInfo.hash` or `AddToStoreNar.nar_hash`.
+    ///
+    /// These are sha256 hashes, usually in base16 without the algorithm, but nix accepts
+    /// other forms too.
+    pub fn from_wire(bytes: &[u8]) -> Result<Self, HashError> {
+        let s = std::str::from_utf8(bytes).map_err(|_| HashError::InvalidEncoding {
+            hash: BStr::new(bytes).to_string(),
+            format: HashFormat::Base16,
+        })?;
+        Hash::parse_any(s, Some(HashAlgorithm::Sha256))
+    }
+
+    /// The hash as the wire protocol sends it: base16, without the algorithm.
+    pub fn to_wire(&self) -> NixString {
+        self.encode(HashFormat::Base16, false).into()
+    }
+}
+
+/// Hashes data a piece at a time, for when there is too much of it to hold in memory.
+///
+/// Everything written to it gets hashed.
+#[derive(Clone)]
+pub struct Hasher(HasherState);
+
+#[derive(Clone)]
+enum HasherState {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Hasher(match algorithm {
+            HashAlgorithm::Md5 => HasherState::Md5(md5::Md5::new()),
+            HashAlgorithm::Sha1 => HasherState::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => HasherState::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => HasherState::Sha512(sha2::Sha512::new()),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            HasherState::Md5(h) => h.update(data),
+            HasherState::Sha1(h) => h.update(data),
+            HasherState::Sha256(h) => h.update(data),
+            HasherState::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Hash {
+        let (algorithm, digest) = match self.0 {
+            HasherState::Md5(h) => (HashAlgorithm::Md5, h.finalize().to_vec()),
+            HasherState::Sha1(h) => (HashAlgorithm::Sha1, h.finalize().to_vec()),
+            HasherState::Sha256(h) => (HashAlgorithm::Sha256, h.finalize().to_vec()),
+            HasherState::Sha512(h) => (HashAlgorithm::Sha512, h.finalize().to_vec()),
+        };
+        Hash::new(algorithm, &digest).expect("digests have the right size")
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HashError {
+    /// Reports the error in terms of the whole hash, instead of part of it.
+    fn with_hash(self, s: &str) -> Self {
+        let hash = s.to_owned();
+        match self {
+            HashError::WrongLength { algorithm, .. } => HashError::WrongLength { hash, algorithm },
+            HashError::InvalidEncoding { format, .. } => {
+                HashError::InvalidEncoding { hash, format }
+            }
+            HashError::WrongAlgorithm { expected, .. } => {
+                HashError::WrongAlgorithm { hash, expected }
+            }
+            e => e,
+        }
+    }
+
+    fn with_format(self, format: HashFormat) -> Self {
+        match self {
+            HashError::InvalidEncoding { hash, .. } => HashError::InvalidEncoding { hash, format },
+            e => e,
+        }
+    }
+}
+
+fn base16(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Writes the hash like nix usually does, as `<algorithm>:<nix32>`.
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode(HashFormat::Nix32, true))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash({self})")
+    }
+}
+
+impl FromStr for Hash {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, HashError> {
+        Hash::parse(s)
+    }
+}
+
+impl From<&Hash> for NarHash {
+    fn from(hash: &Hash) -> NarHash {
+        NarHash {
+            data: hash.to_wire().0,
+        }
+    }
+}
+
+impl TryFrom<&NarHash> for Hash {
+    type Error = HashError;
+
+    fn try_from(hash: &NarHash) -> Result<Hash, HashError> {
+        Hash::from_wire(&hash.data)
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for Hash {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let algorithm = HashAlgorithm::arbitrary(u)?;
+        let digest = u.bytes(algorithm.size())?;
+        Ok(Hash::new(algorithm, digest).expect("digest has the right size"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The hashes of the empty string.
+    const EMPTY: [(HashAlgorithm, &str, &str, &str); 4] = [
+        (
+            HashAlgorithm::Md5,
+            "d41d8cd98f00b204e9800998ecf8427e",
+            "3y8bwfr609h3lh9ch0izcqq7fl",
+            "1B2M2Y8AsgTpgAmY7PhCfg==",
+        ),
+        (
+            HashAlgorithm::Sha1,
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+            "143xibwh31h9bvxzalr0sjvbbvpa6ffs",
+            "2jmj7l5rSw0yVb/vlWAYkK/YBwk=",
+        ),
+        (
+            HashAlgorithm::Sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73",
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        ),
+        (
+            HashAlgorithm::Sha512,
+            "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+            "0zdl9zrg8r3i9c1g90lgg9ip5ijzv3yhz91i0zzn3r8ap9ws784gkp9dk9j3aglhgf1amqb0pj21mh7h1nxcl18akqvvf7ggqsy30yg",
+            "z4PhNX7vuL3xVChQ1m2AB9Yg5AULVxXcg/SpIdNs6c5H0NE8XYXysP+DGNKHfuwvY7kxvUdBeoGlODJ6+SfaPg==",
+        ),
+    ];
+
+    #[test]
+    fn encodings() {
+        for (algorithm, base16, nix32, base64) in EMPTY {
+            let hash = Hash::compute(algorithm, b"");
+            assert_eq!(hash.encode(HashFormat::Base16, false), base16);
+            assert_eq!(hash.encode(HashFormat::Nix32, false), nix32);
+            assert_eq!(hash.encode(HashFormat::Base64, false), base64);
+            assert_eq!(hash.to_sri(), format!("{algorithm}-{base64}"));
+            assert_eq!(hash.to_string(), format!("{algorithm}:{nix32}"));
+
+            for s in [base16, nix32, base64] {
+                assert_eq!(Hash::parse(&format!("{algorithm}:{s}")), Ok(hash));
+                assert_eq!(Hash::parse_any(s, Some(algorithm)), Ok(hash));
+                assert_eq!(Hash::parse_unprefixed(s, algorithm), Ok(hash));
+            }
+            assert_eq!(Hash::parse(&hash.to_sri()), Ok(hash));
+        }
+    }
+
+    #[test]
+    fn wire() {
+        let hash = Hash::compute(HashAlgorithm::Sha256, b"");
+        let nar_hash = NarHash::from(&hash);
+        assert_eq!(nar_hash.data.as_slice(), EMPTY[2].1.as_bytes());
+        assert_eq!(Hash::try_from(&nar_hash), Ok(hash));
+        assert_eq!(Hash::from_wire(EMPTY[2].2.as_bytes()), Ok(hash));
+        assert_eq!(
+            Hash::from_wire(format!("sha256:{}", EMPTY[2].1).as_bytes()),
+            Ok(hash)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let sha256 = Some(HashAlgorithm::Sha256);
+        assert_eq!(
+            Hash::parse("sha257:abcd").unwrap_err().to_string(),
+            "unknown hash algorithm 'sha257'"
+        );
+        assert_eq!(
+            Hash::parse(EMPTY[2].1).unwrap_err().to_string(),
+            format!("hash '{}' does not include a type", EMPTY[2].1)
+        );
+        assert_eq!(
+            Hash::parse_any(&format!("sha1:{}", EMPTY[1].1), sha256)
+                .unwrap_err()
+                .to_string(),
+            format!("hash 'sha1:{}' should have type 'sha256'", EMPTY[1].1)
+        );
+        assert_eq!(
+            Hash::parse("sha256:abcd").unwrap_err().to_string(),
+            "hash 'sha256:abcd' has wrong length for hash type 'sha256'"
+        );
+        let bad = format!("sha256:{}", EMPTY[2].1.replace('e', "g"));
+        assert_eq!(
+            Hash::parse(&bad).unwrap_err().to_string(),
+            format!("invalid base16 encoding in hash '{bad}'")
+        );
+        let bad = format!("sha256:{}", EMPTY[2].2.replace('0', "e"));
+        assert_eq!(
+            Hash::parse(&bad).unwrap_err().to_string(),
+            format!("invalid nix32 encoding in hash '{bad}'")
+        );
+        assert_eq!(
+            Hash::parse("sha256-abc").unwrap_err().to_string(),
+            "invalid SRI encoding in hash 'sha256-abc'"
+        );
+    }
+}
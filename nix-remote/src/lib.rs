@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use serialize::NixSerializer;
 use std::{
+    collections::BTreeSet,
     ffi::OsStr,
     io::{Read, Write},
     os::unix::prelude::OsStrExt,
@@ -10,14 +11,28 @@ use std::{
 
 use worker_op::ValidPathInfo;
 
+pub mod base32;
+pub mod content_address;
+pub mod derivation;
+pub mod derived_path;
 pub mod framed_data;
+pub mod handshake;
+pub mod hash;
 pub mod nar;
+pub mod narinfo;
 pub mod nix_client;
 pub mod nix_daemon_proxy;
+pub mod nix_db;
+pub mod realisation;
 pub mod serialize;
+pub mod signature;
 pub mod stderr;
+pub mod store;
+pub mod store_path;
 pub mod worker_op;
 
+pub use derived_path::DerivedPath;
+pub use realisation::Realisation;
 pub use serialize::{NixReadExt, NixWriteExt};
 
 pub fn to_writer<W: std::io::Write, T: ?Sized + Serialize>(
@@ -53,13 +68,38 @@ pub enum Error {
     #[error("(De)serialization error: {0}")]
     Deser(#[from] serialize::Error),
 
+    /// The client sent an op that doesn't exist in the negotiated protocol version.
+    #[error("unsupported operation {0}")]
+    UnknownOp(u64),
+
+    #[error("Handshake failed: {0}")]
+    Handshake(#[from] handshake::HandshakeError),
+
+    #[error("{0}")]
+    StorePath(#[from] store_path::StorePathError),
+
+    #[error("{0}")]
+    Hash(#[from] hash::HashError),
+
+    #[error("{0}")]
+    ContentAddress(#[from] content_address::ContentAddressError),
+
+    #[error("{0}")]
+    Derivation(#[from] derivation::DerivationError),
+
+    #[error("{0}")]
+    Realisation(#[from] realisation::RealisationError),
+
+    #[error("{0}")]
+    Signature(#[from] signature::SignatureError),
+
     #[error("Other error: {0}")]
     Other(#[from] anyhow::Error),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq, Hash)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 #[serde(transparent)]
 pub struct StorePath(pub NixString);
@@ -89,17 +129,6 @@ impl AsRef<OsStr> for Path {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[serde(transparent)]
-pub struct DerivedPath(pub NixString);
-
-impl AsRef<[u8]> for DerivedPath {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
-    }
-}
-
 /// A string from nix.
 ///
 /// Strings in the nix protocol are not necessarily UTF-8, so this is
@@ -148,13 +177,37 @@ impl AsRef<OsStr> for NixString {
     }
 }
 
-const WORKER_MAGIC_1: u64 = 0x6e697863;
-const WORKER_MAGIC_2: u64 = 0x6478696f;
-const PROTOCOL_VERSION: DaemonVersion = DaemonVersion {
+/// The magic number that starts a [`handshake::ClientHello`].
+pub const WORKER_MAGIC_1: u64 = 0x6e697863;
+/// The magic number that starts a [`handshake::ServerHello`].
+pub const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+/// The newest protocol version that we speak.
+pub const PROTOCOL_VERSION: DaemonVersion = DaemonVersion {
+    major: 1,
+    minor: 38,
+};
+
+/// The oldest protocol version that we speak (the one used by Nix 2.3).
+pub const MIN_PROTOCOL_VERSION: DaemonVersion = DaemonVersion {
     major: 1,
-    minor: 35,
+    minor: 21,
 };
 
+/// The protocol features that we support.
+///
+/// Since protocol version 1.38, the client and the daemon tell each other which features
+/// they support during the handshake, and afterwards they may use the features that
+/// both of them support. Nix doesn't define any features yet.
+pub const SUPPORTED_FEATURES: &[&str] = &[];
+
+pub(crate) fn supported_features() -> BTreeSet<NixString> {
+    SUPPORTED_FEATURES
+        .iter()
+        .map(|f| NixString::from_bytes(f.as_bytes()))
+        .collect()
+}
+
 /// A wrapper around a `std::io::Read`, adding support for the nix wire format.
 pub struct NixRead<R> {
     pub inner: R,
@@ -176,22 +229,17 @@ pub struct PathSet {
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct StorePathSet {
-    // TODO: in nix, they call `parseStorePath` to separate store directory from path
+    /// See [`store_path::StoreDir::parse`] for separating the store directory from paths.
     pub paths: Vec<StorePath>,
 }
 
 /// A set of strings.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct StringSet {
     pub paths: Vec<NixString>,
 }
 
-/// A realisation.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct Realisation(pub NixString);
-
 /// A set of realisations.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -199,7 +247,9 @@ pub struct RealisationSet {
     pub realisations: Vec<Realisation>,
 }
 
-/// A nar hash.
+/// A nar hash, as it is sent on the wire.
+///
+/// Use [`hash::Hash`] to parse or produce one.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct NarHash {
     /// This data has not been validated; this is just copied from the wire.
@@ -207,32 +257,10 @@ pub struct NarHash {
 }
 
 impl NarHash {
+    /// Encodes a digest in nix's base32.
     pub fn from_bytes(bytes: &[u8]) -> NarHash {
-        const BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
-
-        let len = (bytes.len() * 8 - 1) / 5 + 1;
-
-        let data = (0..len)
-            .rev()
-            .map(|n| {
-                let b: usize = n * 5;
-                let i: usize = b / 8;
-                let j: usize = b % 8;
-                // bits from the lower byte
-                let v1 = bytes[i].checked_shr(j as u32).unwrap_or(0);
-                // bits from the upper byte
-                let v2 = if i >= bytes.len() - 1 {
-                    0
-                } else {
-                    bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
-                };
-                let v: usize = (v1 | v2) as usize;
-                BASE32_CHARS[v % BASE32_CHARS.len()]
-            })
-            .collect::<Vec<_>>();
-
         NarHash {
-            data: ByteBuf::from(data),
+            data: ByteBuf::from(base32::encode(bytes).into_bytes()),
         }
     }
 }
@@ -271,10 +299,7 @@ impl<W: Write> NixWrite<W> {
 
     /// Write a "string" (really, a byte buffer) to the wire.
     pub fn write_string(&mut self, s: &[u8]) -> serialize::Result<()> {
-        NixSerializer {
-            write: &mut self.inner,
-        }
-        .write_byte_buf(s)
+        NixSerializer::new(&mut self.inner).write_byte_buf(s)
     }
 
     /// Write any serializable type to the wire.
@@ -293,10 +318,34 @@ impl<W: Write> NixWrite<W> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct DaemonVersion {
-    major: u8,
-    minor: u8,
+/// A version of the nix remote protocol.
+///
+/// On the wire, this is a single integer with the major version in the second byte
+/// and the minor version in the first byte. Most of the interesting changes to the
+/// protocol happen in the minor version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "u64", into = "u64")]
+pub struct DaemonVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl DaemonVersion {
+    /// Agree on a protocol version with a peer that supports versions up to `peer`.
+    ///
+    /// Both sides of the connection use the older of the two versions. Returns `None`
+    /// if the peer speaks a different major version, or if the agreed version is older
+    /// than [`MIN_PROTOCOL_VERSION`].
+    pub fn negotiate(self, peer: DaemonVersion) -> Option<DaemonVersion> {
+        let version = self.min(peer);
+        (self.major == peer.major && version >= MIN_PROTOCOL_VERSION).then_some(version)
+    }
+}
+
+impl std::fmt::Display for DaemonVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
 }
 
 impl From<u64> for DaemonVersion {
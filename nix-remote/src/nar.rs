@@ -5,6 +5,8 @@
 //! memory; the `stream` function allows for streaming a `Nar` (represented in the nix wire
 //! format) from a `std::io::Read` to a `std::io::Write`.
 
+use std::{cell::RefCell, io::Read, os::unix::ffi::OsStrExt, path::PathBuf};
+
 use serde::{de::SeqAccess, ser::SerializeTuple, Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
@@ -111,7 +113,7 @@ impl<'a> EntrySink<'a> for &'a mut Nar {
     }
 }
 
-impl<'a> DirectorySinkSuper for &'a mut Vec<NarDirectoryEntry> {
+impl DirectorySinkSuper for &mut Vec<NarDirectoryEntry> {
     type EntrySink<'b> = &'b mut Nar;
 }
 
@@ -131,7 +133,7 @@ impl<'a> DirectorySink<'a> for &'a mut Vec<NarDirectoryEntry> {
     }
 }
 
-impl<'a> std::io::Write for &'a mut NarFile {
+impl std::io::Write for &mut NarFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.add_contents(buf);
         Ok(buf.len())
@@ -142,7 +144,7 @@ impl<'a> std::io::Write for &'a mut NarFile {
     }
 }
 
-impl<'a> FileSink for &'a mut NarFile {
+impl FileSink for &mut NarFile {
     fn set_executable(&mut self, executable: bool) {
         self.executable = executable;
     }
@@ -153,9 +155,9 @@ impl<'a> FileSink for &'a mut NarFile {
 }
 
 #[derive(Default)]
-struct Null;
+pub(crate) struct Null;
 
-impl<'a> std::io::Write for &'a mut Null {
+impl std::io::Write for &mut Null {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         Ok(buf.len())
     }
@@ -165,7 +167,7 @@ impl<'a> std::io::Write for &'a mut Null {
     }
 }
 
-impl<'a> FileSink for &'a mut Null {
+impl FileSink for &mut Null {
     fn set_executable(&mut self, _executable: bool) {}
 
     fn add_contents(&mut self, _contents: &[u8]) {}
@@ -186,7 +188,7 @@ impl<'a> EntrySink<'a> for &'a mut Null {
     fn become_symlink(self, _target: NixString) {}
 }
 
-impl<'a> DirectorySinkSuper for &'a mut Null {
+impl DirectorySinkSuper for &mut Null {
     type EntrySink<'b> = &'b mut Null;
 }
 
@@ -199,6 +201,65 @@ impl<'a> DirectorySink<'a> for &'a mut Null {
     }
 }
 
+/// An [`EntrySink`] that only keeps the contents of a nar that is a single regular
+/// file, by writing them to `W`. Everything else in the nar is dropped.
+pub(crate) struct FileContents<W> {
+    contents: W,
+    is_file: bool,
+    null: Null,
+}
+
+impl<W> FileContents<W> {
+    pub(crate) fn new(contents: W) -> Self {
+        FileContents {
+            contents,
+            is_file: false,
+            null: Null,
+        }
+    }
+
+    /// Was the nar a single regular file?
+    pub(crate) fn is_file(&self) -> bool {
+        self.is_file
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for &mut FileContents<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.contents.flush()
+    }
+}
+
+impl<W: std::io::Write> FileSink for &mut FileContents<W> {
+    fn set_executable(&mut self, _executable: bool) {}
+
+    fn add_contents(&mut self, contents: &[u8]) {
+        // Like `NarFile`, there's nowhere to put an error. The writers we hash into don't
+        // fail.
+        let _ = self.contents.write_all(contents);
+    }
+}
+
+impl<'a, W: std::io::Write + 'a> EntrySink<'a> for &'a mut FileContents<W> {
+    type DirectorySink = &'a mut Null;
+    type FileSink = &'a mut FileContents<W>;
+
+    fn become_directory(self) -> Self::DirectorySink {
+        &mut self.null
+    }
+
+    fn become_file(self) -> Self::FileSink {
+        self.is_file = true;
+        self
+    }
+
+    fn become_symlink(self, _target: NixString) {}
+}
+
 trait SerializeTupleExt: SerializeTuple {
     fn serialize_buf(&mut self, s: impl AsRef<[u8]>) -> Result<(), Self::Error> {
         self.serialize_element(&ByteBuf::from(s.as_ref()))
@@ -335,7 +396,7 @@ impl<'v> StringReader<'v> for NixDeserializer<'v> {
             remaining -= written;
         }
 
-        if len % 8 > 0 {
+        if !len.is_multiple_of(8) {
             let padding = 8 - len % 8;
             self.read.read_exact(&mut buf[..padding])?;
         }
@@ -356,11 +417,16 @@ pub fn stream<R: std::io::Read, W: std::io::Write>(
     read: R,
     write: W,
 ) -> Result<(), crate::serialize::Error> {
-    let mut tee = Tee::new(read, write);
-    let mut de = NixDeserializer { read: &mut tee };
-    de.expect_tag("nix-archive-1")?;
-    read_entry(&mut de, &mut Null)?;
-    Ok(())
+    stream_into(read, write, &mut Null)
+}
+
+/// Like [`stream`], but also sends everything in the nar to `sink` on the way.
+pub fn stream_into<'s, R: std::io::Read, W: std::io::Write, S: EntrySink<'s> + 's>(
+    read: R,
+    write: W,
+    sink: S,
+) -> Result<(), crate::serialize::Error> {
+    read_into(Tee::new(read, write), sink)
 }
 
 impl<'de> Deserialize<'de> for Nar {
@@ -403,7 +469,7 @@ impl Serialize for Nar {
 
 struct Untagged<T>(T);
 
-impl<'a> Serialize for Untagged<&'a Nar> {
+impl Serialize for Untagged<&Nar> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -446,3 +512,229 @@ impl<'a> Serialize for Untagged<&'a Nar> {
         tup.end()
     }
 }
+
+/// Read a nar from `read`, and send everything in it to `sink`.
+///
+/// Unlike deserializing a [`Nar`], this doesn't need to hold the whole nar in memory
+/// (unless `sink` does).
+pub fn read_into<'s, R: std::io::Read, S: EntrySink<'s> + 's>(
+    mut read: R,
+    sink: S,
+) -> Result<(), crate::serialize::Error> {
+    let mut de = NixDeserializer::new(&mut read);
+    de.expect_tag("nix-archive-1")?;
+    read_entry(&mut de, sink)
+}
+
+/// Unpack a nar from `read` into the filesystem at `path`, which must not exist yet.
+pub fn restore<R: std::io::Read>(
+    read: R,
+    path: &std::path::Path,
+) -> Result<(), crate::serialize::Error> {
+    // The sinks can't return errors, so they stash the first one here.
+    let error = RefCell::new(None);
+    let entry = FsEntry {
+        path: Some(path.to_owned()),
+        error: &error,
+    };
+    read_into(read, entry)?;
+    match error.into_inner() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// Write the file, directory or symlink at `path` to `write` as a nar.
+pub fn dump(path: &std::path::Path, mut write: impl std::io::Write) -> std::io::Result<()> {
+    write_str(&mut write, b"nix-archive-1")?;
+    dump_entry(path, &mut write)
+}
+
+fn write_str(write: &mut impl std::io::Write, s: &[u8]) -> std::io::Result<()> {
+    write.write_all(&(s.len() as u64).to_le_bytes())?;
+    write.write_all(s)?;
+    write_padding(write, s.len() as u64)
+}
+
+fn write_padding(write: &mut impl std::io::Write, len: u64) -> std::io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+    write.write_all(&[0; 8][..padding as usize])
+}
+
+fn dump_entry(path: &std::path::Path, write: &mut impl std::io::Write) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    write_str(write, b"(")?;
+    write_str(write, b"type")?;
+    if metadata.is_symlink() {
+        write_str(write, b"symlink")?;
+        write_str(write, b"target")?;
+        write_str(write, std::fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if metadata.is_dir() {
+        write_str(write, b"directory")?;
+        let mut names = std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in names {
+            write_str(write, b"entry")?;
+            write_str(write, b"(")?;
+            write_str(write, b"name")?;
+            write_str(write, name.as_bytes())?;
+            write_str(write, b"node")?;
+            dump_entry(&path.join(name), write)?;
+            write_str(write, b")")?;
+        }
+    } else if metadata.is_file() {
+        write_str(write, b"regular")?;
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(write, b"executable")?;
+            write_str(write, b"")?;
+        }
+        write_str(write, b"contents")?;
+        let len = metadata.len();
+        write.write_all(&len.to_le_bytes())?;
+        let copied = std::io::copy(&mut std::fs::File::open(path)?.take(len), write)?;
+        if copied != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{} changed while it was being dumped", path.display()),
+            ));
+        }
+        write_padding(write, len)?;
+    } else {
+        return Err(std::io::Error::other(format!(
+            "{} has an unsupported file type",
+            path.display()
+        )));
+    }
+    write_str(write, b")")
+}
+
+/// Unpacks a nar entry into the filesystem.
+///
+/// If something goes wrong, the error is stored and `path` becomes `None`, which turns
+/// the rest of the unpacking into a no-op.
+struct FsEntry<'a> {
+    path: Option<PathBuf>,
+    error: &'a RefCell<Option<std::io::Error>>,
+}
+
+impl FsEntry<'_> {
+    fn record<T>(&self, result: std::io::Result<T>) -> Option<T> {
+        match result {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+}
+
+impl<'a> EntrySink<'a> for FsEntry<'a> {
+    type DirectorySink = FsEntry<'a>;
+    type FileSink = FsFile<'a>;
+
+    fn become_directory(mut self) -> Self::DirectorySink {
+        if let Some(path) = self.path.take() {
+            self.path = self.record(std::fs::create_dir(&path)).map(|()| path);
+        }
+        self
+    }
+
+    fn become_file(self) -> Self::FileSink {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = self.path.as_ref().and_then(|path| {
+            self.record(
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o644)
+                    .open(path),
+            )
+        });
+        FsFile {
+            file,
+            error: self.error,
+        }
+    }
+
+    fn become_symlink(self, target: NixString) {
+        if let Some(path) = &self.path {
+            self.record(std::os::unix::fs::symlink(
+                std::ffi::OsStr::from_bytes(&target.0),
+                path,
+            ));
+        }
+    }
+}
+
+impl DirectorySinkSuper for FsEntry<'_> {
+    type EntrySink<'b> = FsEntry<'b>;
+}
+
+impl<'a> DirectorySink<'a> for FsEntry<'a> {
+    fn create_entry<'b>(&'b mut self, name: NixString) -> Self::EntrySink<'b>
+    where
+        'a: 'b,
+    {
+        let name = name.0.as_slice();
+        let path = if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+            self.record::<()>(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid file name {:?} in nar", bstr::BStr::new(name)),
+            )));
+            None
+        } else {
+            self.path
+                .as_ref()
+                .map(|dir| dir.join(std::ffi::OsStr::from_bytes(name)))
+        };
+        FsEntry {
+            path,
+            error: self.error,
+        }
+    }
+}
+
+struct FsFile<'a> {
+    file: Option<std::fs::File>,
+    error: &'a RefCell<Option<std::io::Error>>,
+}
+
+impl std::io::Write for FsFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSink for FsFile<'_> {
+    fn set_executable(&mut self, executable: bool) {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(file) = &self.file {
+            let mode = if executable { 0o755 } else { 0o644 };
+            if let Err(e) = file.set_permissions(std::fs::Permissions::from_mode(mode)) {
+                self.error.borrow_mut().get_or_insert(e);
+            }
+        }
+    }
+
+    fn add_contents(&mut self, contents: &[u8]) {
+        use std::io::Write;
+
+        if let Err(e) = self.write_all(contents) {
+            self.error.borrow_mut().get_or_insert(e);
+        }
+    }
+}
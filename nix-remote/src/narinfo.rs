@@ -0,0 +1,298 @@
+//! The `.narinfo` format that binary caches use for the metadata of store paths.
+//!
+//! A narinfo is a list of `Key: value` lines, like
+//!
+//! ```text
+//! StorePath: /nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1
+//! URL: nar/1w8rhz8di3xxixzazgjxhh4q5h1vyrbbxsvkgpak6mxnikprq5s2.nar.xz
+//! Compression: xz
+//! FileHash: sha256:1w8rhz8di3xxixzazgjxhh4q5h1vyrbbxsvkgpak6mxnikprq5s2
+//! FileSize: 50088
+//! NarHash: sha256:0j6rk5lywfcp6nnxzpwiyfwpr61qmzpwanb38zkcsdw7hsa2pmlj
+//! NarSize: 226560
+//! References: 3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39
+//! Deriver: x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv
+//! Sig: cache.nixos.org-1:...
+//! ```
+//!
+//! References and the deriver are written without the store directory, which is taken
+//! from `StorePath`.
+
+use anyhow::anyhow;
+use bstr::BStr;
+
+use crate::{
+    content_address::ContentAddress, hash::Hash, worker_op::ValidPathInfo, NarHash, NixString,
+    Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+};
+
+/// The contents of a `.narinfo` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    pub store_path: StorePath,
+    /// Where the (compressed) nar is, relative to the cache.
+    pub url: String,
+    /// How the nar is compressed, like `xz` or `zstd` (or `none`).
+    pub compression: String,
+    /// The hash of the compressed nar.
+    pub file_hash: Option<Hash>,
+    /// The size of the compressed nar.
+    pub file_size: Option<u64>,
+    /// The hash of the uncompressed nar.
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    pub references: StorePathSet,
+    pub deriver: Option<StorePath>,
+    pub sigs: Vec<String>,
+    pub ca: Option<ContentAddress>,
+}
+
+/// The part of a store path after the store directory.
+fn base_name(path: &StorePath) -> &BStr {
+    let path: &[u8] = path.as_ref();
+    let start = path.iter().rposition(|&b| b == b'/').map_or(0, |i| i + 1);
+    BStr::new(&path[start..])
+}
+
+fn utf8(s: &NixString) -> Result<String> {
+    s.to_string()
+        .map_err(|_| anyhow!("'{}' is not valid UTF-8", BStr::new(s)).into())
+}
+
+impl NarInfo {
+    /// Parses the text of a narinfo file.
+    ///
+    /// Unknown keys are ignored, like nix does.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |line: &str| anyhow!("invalid narinfo line '{line}'");
+        let number = |line: &str, value: &str| value.parse().map_err(|_| invalid(line));
+        let mut store_path = None;
+        let mut url = None;
+        // Old narinfos may not mention compression, and then it's bzip2.
+        let mut compression = "bzip2";
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = Vec::new();
+        let mut deriver = None;
+        let mut sigs = Vec::new();
+        let mut ca = None;
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            let (key, value) = line.split_once(": ").ok_or_else(|| invalid(line))?;
+            match key {
+                "StorePath" => store_path = Some(value),
+                "URL" => url = Some(value),
+                "Compression" => compression = value,
+                "FileHash" => file_hash = Some(value.parse::<Hash>()?),
+                "FileSize" => file_size = Some(number(line, value)?),
+                "NarHash" => nar_hash = Some(value.parse::<Hash>()?),
+                "NarSize" => nar_size = Some(number(line, value)?),
+                "References" => references = value.split_whitespace().collect(),
+                "Deriver" if value != "unknown-deriver" => deriver = Some(value),
+                "Sig" => sigs.push(value.to_owned()),
+                "CA" => ca = Some(value.parse()?),
+                _ => {}
+            }
+        }
+
+        let missing = |key: &str| anyhow!("narinfo has no {key}");
+        let store_path = store_path.ok_or_else(|| missing("StorePath"))?;
+        let store_dir = match store_path.rsplit_once('/') {
+            Some((dir, name)) if !dir.is_empty() && !name.is_empty() => dir,
+            _ => return Err(anyhow!("invalid StorePath '{store_path}'").into()),
+        };
+        let full_path = |name: &str| StorePath(format!("{store_dir}/{name}").into());
+        Ok(NarInfo {
+            store_path: full_path(&store_path[store_dir.len() + 1..]),
+            url: url.ok_or_else(|| missing("URL"))?.to_owned(),
+            compression: compression.to_owned(),
+            file_hash,
+            file_size,
+            nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+            nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+            references: StorePathSet {
+                paths: references.into_iter().map(full_path).collect(),
+            },
+            deriver: deriver.map(full_path),
+            sigs,
+            ca,
+        })
+    }
+
+    /// Renders the narinfo in the order that nix writes it.
+    pub fn render(&self) -> String {
+        let mut out = format!(
+            "StorePath: {}\nURL: {}\nCompression: {}\n",
+            BStr::new(&self.store_path),
+            self.url,
+            self.compression
+        );
+        if let Some(file_hash) = &self.file_hash {
+            out += &format!("FileHash: {file_hash}\n");
+        }
+        if let Some(file_size) = self.file_size {
+            out += &format!("FileSize: {file_size}\n");
+        }
+        let references: Vec<_> = self
+            .references
+            .paths
+            .iter()
+            .map(|r| base_name(r).to_string())
+            .collect();
+        out += &format!(
+            "NarHash: {}\nNarSize: {}\nReferences: {}\n",
+            self.nar_hash,
+            self.nar_size,
+            references.join(" ")
+        );
+        if let Some(deriver) = &self.deriver {
+            out += &format!("Deriver: {}\n", base_name(deriver));
+        }
+        for sig in &self.sigs {
+            out += &format!("Sig: {sig}\n");
+        }
+        if let Some(ca) = &self.ca {
+            out += &format!("CA: {ca}\n");
+        }
+        out
+    }
+
+    /// The metadata of the path, as the protocol sends it.
+    ///
+    /// Binary caches don't know when paths were registered, so the registration time is 0.
+    pub fn to_path_info(&self) -> Result<ValidPathInfoWithPath> {
+        Ok(ValidPathInfoWithPath {
+            path: self.store_path.clone(),
+            info: ValidPathInfo {
+                deriver: self
+                    .deriver
+                    .clone()
+                    .unwrap_or_else(|| StorePath(NixString::default())),
+                hash: NarHash::from(&self.nar_hash),
+                references: self.references.clone(),
+                registration_time: 0,
+                nar_size: self.nar_size,
+                ultimate: false,
+                sigs: StringSet {
+                    paths: self.sigs.iter().map(|s| s.clone().into()).collect(),
+                },
+                content_address: self.ca.into(),
+            },
+        })
+    }
+
+    /// A narinfo for the path described by `info`.
+    ///
+    /// This doesn't know where the nar will be, so the `url` is empty and the
+    /// compression is `none`, without a file hash or size. Fill those in before rendering.
+    pub fn from_path_info(info: &ValidPathInfoWithPath) -> Result<Self> {
+        let ValidPathInfoWithPath { path, info } = info;
+        Ok(NarInfo {
+            store_path: path.clone(),
+            url: String::new(),
+            compression: "none".to_owned(),
+            file_hash: None,
+            file_size: None,
+            nar_hash: Hash::try_from(&info.hash)?,
+            nar_size: info.nar_size,
+            references: info.references.clone(),
+            deriver: Some(info.deriver.clone()).filter(|d| !d.0 .0.is_empty()),
+            sigs: info.sigs.paths.iter().map(utf8).collect::<Result<_>>()?,
+            ca: info.content_address.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "StorePath: /nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1
+URL: nar/1w8rhz8di3xxixzazgjxhh4q5h1vyrbbxsvkgpak6mxnikprq5s2.nar.xz
+Compression: xz
+FileHash: sha256:1w8rhz8di3xxixzazgjxhh4q5h1vyrbbxsvkgpak6mxnikprq5s2
+FileSize: 50088
+NarHash: sha256:0j6rk5lywfcp6nnxzpwiyfwpr61qmzpwanb38zkcsdw7hsa2pmlj
+NarSize: 226560
+References: 3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39 fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1
+Deriver: x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv
+Sig: cache.nixos.org-1:c2ln
+";
+
+    fn path(p: &str) -> StorePath {
+        StorePath(NixString::from_bytes(p.as_bytes()))
+    }
+
+    #[test]
+    fn parse_and_render() {
+        let info = NarInfo::parse(HELLO).unwrap();
+        assert_eq!(
+            info.store_path,
+            path("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1")
+        );
+        assert_eq!(info.compression, "xz");
+        assert_eq!(info.file_size, Some(50088));
+        assert_eq!(info.nar_size, 226560);
+        assert_eq!(
+            info.references.paths,
+            vec![
+                path("/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39"),
+                path("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"),
+            ]
+        );
+        assert_eq!(
+            info.deriver,
+            Some(path(
+                "/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv"
+            ))
+        );
+        assert_eq!(info.ca, None);
+        assert_eq!(info.render(), HELLO);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(NarInfo::parse("StorePath /nix/store/x").is_err());
+        assert!(NarInfo::parse("URL: nar/x.nar\nNarHash: sha256:x\nNarSize: 1\n").is_err());
+        let no_size = HELLO.replace("NarSize: 226560\n", "");
+        assert!(NarInfo::parse(&no_size).is_err());
+        let bad_size = HELLO.replace("NarSize: 226560", "NarSize: big");
+        assert!(NarInfo::parse(&bad_size).is_err());
+        let bad_hash = HELLO.replace("NarHash: sha256:0j6r", "NarHash: sha256:ej6r");
+        assert_eq!(
+            NarInfo::parse(&bad_hash).unwrap_err().to_string(),
+            "invalid nix32 encoding in hash \
+             'sha256:ej6rk5lywfcp6nnxzpwiyfwpr61qmzpwanb38zkcsdw7hsa2pmlj'"
+        );
+
+        // Unknown keys are fine.
+        let extra = format!("{HELLO}System: x86_64-linux\n");
+        assert_eq!(
+            NarInfo::parse(&extra).unwrap(),
+            NarInfo::parse(HELLO).unwrap()
+        );
+    }
+
+    #[test]
+    fn path_info() {
+        let narinfo = NarInfo::parse(HELLO).unwrap();
+        let info = narinfo.to_path_info().unwrap();
+        assert_eq!(info.path, narinfo.store_path);
+        assert_eq!(info.info.hash.data.len(), 64);
+        assert_eq!(info.info.references, narinfo.references);
+        assert_eq!(info.info.sigs.paths.len(), 1);
+
+        let back = NarInfo::from_path_info(&info).unwrap();
+        assert_eq!(
+            back,
+            NarInfo {
+                url: String::new(),
+                compression: "none".to_owned(),
+                file_hash: None,
+                file_size: None,
+                ..narinfo
+            }
+        );
+    }
+}
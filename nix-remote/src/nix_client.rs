@@ -5,63 +5,139 @@ use crate::{
     worker_op::{Resp, WorkerOp},
     NixString,
 };
-use anyhow::anyhow;
 use serde::Serialize;
 use std::{
+    collections::BTreeSet,
     fmt::Debug,
     io::{BufRead, Read, Write},
 };
 
-use crate::{NixRead, NixWrite, Result, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2};
+use crate::{
+    handshake::{ClientHello, HandshakeError, ServerHello, TrustedFlag},
+    DaemonVersion, NixRead, NixWrite, Result, PROTOCOL_VERSION, WORKER_MAGIC_2,
+};
 
 pub struct NixDaemonClient<R, W> {
     rx_from_daemon: NixRead<R>,
     tx_to_daemon: NixWrite<W>,
     tx_op_count: u64,
+    protocol_version: DaemonVersion,
+    features: BTreeSet<NixString>,
+    trusted: TrustedFlag,
 }
 
 impl<R: Read, W: Write> NixDaemonClient<R, W> {
     pub fn new(r: R, w: W) -> Result<Self> {
+        Self::with_version(r, w, PROTOCOL_VERSION)
+    }
+
+    /// Connect to a daemon, offering protocol versions up to `version` instead of
+    /// [`PROTOCOL_VERSION`].
+    ///
+    /// This is useful for proxies, which want to talk to the daemon using the same
+    /// version that their own client negotiated.
+    pub fn with_version(r: R, w: W, version: DaemonVersion) -> Result<Self> {
         let mut daemon = Self {
             rx_from_daemon: NixRead { inner: r },
             tx_to_daemon: NixWrite { inner: w },
             tx_op_count: 0,
+            protocol_version: version,
+            features: BTreeSet::new(),
+            trusted: TrustedFlag::Unknown,
         };
-        // handshake
-        daemon.handshake_with_daemon().unwrap();
+        daemon.handshake_with_daemon()?;
         Ok(daemon)
     }
 
+    /// The protocol version that was agreed on with the daemon.
+    pub fn protocol_version(&self) -> DaemonVersion {
+        self.protocol_version
+    }
+
+    /// The protocol features that both we and the daemon support.
+    pub fn features(&self) -> &BTreeSet<NixString> {
+        &self.features
+    }
+
+    /// Whether both we and the daemon support the given protocol feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features
+            .contains(&NixString::from_bytes(feature.as_bytes()))
+    }
+
+    /// Whether the daemon says that it trusts us.
+    pub fn trusted(&self) -> TrustedFlag {
+        self.trusted
+    }
+
     #[tracing::instrument(skip(self))]
-    fn handshake_with_daemon(&mut self) -> Result<()> {
-        self.tx_to_daemon.inner.write_nix(&WORKER_MAGIC_1)?;
+    fn handshake_with_daemon(&mut self) -> Result<(), HandshakeError> {
+        let hello = ClientHello {
+            version: self.protocol_version,
+            ..ClientHello::default()
+        };
+
+        self.tx_to_daemon.write_nix(&hello.magic)?;
         self.tx_to_daemon.inner.flush()?;
         let magic: u64 = self.rx_from_daemon.inner.read_nix()?;
         if magic != WORKER_MAGIC_2 {
-            Err(anyhow!("unexpected WORKER_MAGIC_2: got {magic:x}"))?;
+            return Err(HandshakeError::BadMagic {
+                expected: WORKER_MAGIC_2,
+                got: magic,
+            });
         }
-        let protocol_version: u64 = self.rx_from_daemon.inner.read_nix()?;
-        if protocol_version < PROTOCOL_VERSION.into() {
-            Err(anyhow!(
-                "unexpected protocol version: got {protocol_version}"
-            ))?;
+        let daemon_version: DaemonVersion = self.rx_from_daemon.inner.read_nix()?;
+        self.protocol_version = hello
+            .version
+            .negotiate(daemon_version)
+            .ok_or(HandshakeError::UnsupportedVersion(daemon_version))?;
+        tracing::info!("Negotiated protocol version {}", self.protocol_version);
+
+        // We send the newest version we support; the daemon does its own negotiation.
+        self.tx_to_daemon.write_nix(&hello.version)?;
+        let mut daemon_features = BTreeSet::new();
+        if self.protocol_version.minor >= 38 {
+            self.tx_to_daemon.write_nix(&hello.features)?;
+            self.tx_to_daemon.inner.flush()?;
+            daemon_features = self.rx_from_daemon.inner.read_nix()?;
+            self.features = hello
+                .features
+                .intersection(&daemon_features)
+                .cloned()
+                .collect();
+            tracing::info!("Agreed on features {:?}", self.features);
         }
-        let protocol_version: u64 = PROTOCOL_VERSION.into();
-        self.tx_to_daemon.inner.write_nix(&protocol_version)?;
-        self.tx_to_daemon.inner.write_nix(&0u64)?; // cpu affinity, obsolete
-        self.tx_to_daemon.inner.write_nix(&0u64)?; // reserve space, obsolete
+        // Obsolete fields, which we always leave unset.
+        self.tx_to_daemon.write_u64(0)?;
+        self.tx_to_daemon.write_nix(&hello.reserve_space)?;
         self.tx_to_daemon.inner.flush()?;
-        let proxy_daemon_version: NixString = self.rx_from_daemon.inner.read_nix()?;
-        tracing::info!(
-            "Proxy daemon is: {}",
-            String::from_utf8_lossy(proxy_daemon_version.0.as_ref())
-        );
-        let _trusted_flag: u64 = self.rx_from_daemon.inner.read_nix()?;
+        tracing::debug!(client_hello = ?hello);
+
+        let mut server_hello = ServerHello {
+            magic,
+            version: daemon_version,
+            features: daemon_features,
+            daemon_version: NixString::default(),
+            trusted: TrustedFlag::Unknown,
+        };
+        if self.protocol_version.minor >= 33 {
+            server_hello.daemon_version = self.rx_from_daemon.inner.read_nix()?;
+            tracing::info!("Proxy daemon is: {:?}", server_hello.daemon_version);
+        }
+        if self.protocol_version.minor >= 35 {
+            server_hello.trusted = self.rx_from_daemon.inner.read_nix()?;
+            self.trusted = server_hello.trusted;
+        }
+        tracing::debug!(?server_hello);
         loop {
-            let err_msg = self.read_error_msg().unwrap();
-            if err_msg == Msg::Last(()) {
-                // we are done
-                break;
+            let msg: Msg = self
+                .rx_from_daemon
+                .inner
+                .read_nix_with_version(self.protocol_version)?;
+            match msg {
+                Msg::Last(()) => break,
+                Msg::Error(e) => return Err(HandshakeError::Daemon(e)),
+                msg => tracing::debug!(?msg, "message during handshake"),
             }
         }
         Ok(())
@@ -69,18 +145,14 @@ impl<R: Read, W: Write> NixDaemonClient<R, W> {
 
     #[tracing::instrument(skip(self))]
     pub fn streaming_write_buff(&mut self, buf: &[u8], chunk_len: usize) -> Result<()> {
-        let ser = crate::serialize::NixSerializer {
-            write: &mut self.tx_to_daemon.inner,
-        };
+        let ser = crate::serialize::NixSerializer::new(&mut self.tx_to_daemon.inner);
         ser.write.write_all(&buf[..chunk_len])?;
         Ok(())
     }
 
     #[tracing::instrument(skip(self))]
     pub fn streaming_write_len(&mut self, len: u64) -> Result<()> {
-        let mut ser = crate::serialize::NixSerializer {
-            write: &mut self.tx_to_daemon.inner,
-        };
+        let mut ser = crate::serialize::NixSerializer::new(&mut self.tx_to_daemon.inner);
         len.serialize(&mut ser)?;
         Ok(())
     }
@@ -88,18 +160,29 @@ impl<R: Read, W: Write> NixDaemonClient<R, W> {
     #[tracing::instrument(skip(self))]
     pub fn send_worker_op_to_daemon(&mut self, worker_op: &WorkerOp) -> Result<()> {
         self.tx_op_count += 1;
-        self.tx_to_daemon.inner.write_nix(&worker_op)?;
+        self.tx_to_daemon
+            .inner
+            .write_nix_with_version(&worker_op, self.protocol_version)?;
         self.tx_to_daemon.inner.flush()?;
         Ok(())
     }
 
+    /// Replies to a [`stderr::Msg::Read`] from the daemon.
+    pub fn write_tunnel_data(&mut self, data: &NixString) -> Result<()> {
+        self.tx_to_daemon.write_nix(data)?;
+        self.tx_to_daemon.flush()
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         self.tx_to_daemon.flush()?;
         Ok(())
     }
 
     pub fn read_error_msg(&mut self) -> Result<stderr::Msg> {
-        let msg: stderr::Msg = self.rx_from_daemon.inner.read_nix()?;
+        let msg: stderr::Msg = self
+            .rx_from_daemon
+            .inner
+            .read_nix_with_version(self.protocol_version)?;
         Ok(msg)
     }
 
@@ -107,9 +190,8 @@ impl<R: Read, W: Write> NixDaemonClient<R, W> {
     where
         T: Debug + for<'a> serde::Deserialize<'a>,
     {
-        let mut deser: NixDeserializer = NixDeserializer {
-            read: &mut self.rx_from_daemon.inner,
-        };
+        let mut deser =
+            NixDeserializer::with_version(&mut self.rx_from_daemon.inner, self.protocol_version);
 
         let reply: T = resp.ty(T::deserialize(&mut deser)?);
         Ok(reply)
@@ -134,7 +216,10 @@ impl<R: BufRead, W: Write> NixDaemonClient<R, W> {
         if self.rx_from_daemon.inner.fill_buf()?.is_empty() {
             Ok(None)
         } else {
-            let msg: stderr::Msg = self.rx_from_daemon.inner.read_nix()?;
+            let msg: stderr::Msg = self
+                .rx_from_daemon
+                .inner
+                .read_nix_with_version(self.protocol_version)?;
             Ok(Some(msg))
         }
     }
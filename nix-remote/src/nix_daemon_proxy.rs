@@ -1,79 +1,299 @@
 pub use crate::serialize::{NixReadExt, NixWriteExt};
-use crate::{serialize::NixSerializer, stderr, worker_op::WorkerOp, Error};
-use anyhow::anyhow;
+use crate::{
+    framed_data::FramedReader,
+    serialize::NixSerializer,
+    signature::{PublicKeys, SignatureError},
+    stderr,
+    store_path::StoreDir,
+    worker_op::WorkerOp,
+    Error,
+};
 use serde::Deserialize;
-use std::io::{Read, Write};
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+};
 
-use crate::{NixRead, NixWrite, Result, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2};
+use crate::{
+    handshake::{ClientHello, HandshakeError, ServerHello, TrustedFlag},
+    stderr::StderrError,
+    DaemonVersion, NixRead, NixString, NixWrite, Result, PROTOCOL_VERSION, WORKER_MAGIC_1,
+};
+
+/// How a [`NixDaemonProxy`] presents itself to its client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The newest protocol version that we offer the client.
+    ///
+    /// A proxy that forwards ops to another daemon should cap this to the version that
+    /// daemon speaks, because ops are encoded differently in different versions.
+    pub protocol_version: DaemonVersion,
+    /// The daemon version string that we advertise, since protocol version 1.33.
+    pub daemon_version: NixString,
+    /// Whether the client is trusted.
+    ///
+    /// This is advertised to the client (since protocol version 1.35), but it is
+    /// also up to the proxy to enforce it: an untrusted client shouldn't be allowed
+    /// to do things that only trusted users may do.
+    pub trust: TrustedFlag,
+    /// The keys whose signatures we accept on paths from untrusted clients.
+    ///
+    /// Like nix-daemon with `require-sigs`, we don't take paths from untrusted clients
+    /// unless they are signed by one of these keys, or are content-addressed.
+    pub trusted_public_keys: PublicKeys,
+    /// The store directory, which we need to check the paths of content-addressed
+    /// uploads.
+    pub store_dir: StoreDir,
+}
+
+impl ProxyConfig {
+    /// Is the client trusted?
+    ///
+    /// Only [`TrustedFlag::Trusted`] counts: if we don't know whether the client is
+    /// trusted, it gets treated as untrusted.
+    pub fn is_trusted(&self) -> bool {
+        self.trust == TrustedFlag::Trusted
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        let hello = ServerHello::default();
+        ProxyConfig {
+            protocol_version: hello.version,
+            daemon_version: hello.daemon_version,
+            trust: hello.trusted,
+            trusted_public_keys: PublicKeys::new(),
+            store_dir: StoreDir::default(),
+        }
+    }
+}
 
 pub struct NixDaemonProxy<R, W> {
     rx_from_client: NixRead<R>,
     tx_to_client: NixWrite<W>,
     rx_op_count: u64,
+    protocol_version: DaemonVersion,
+    features: BTreeSet<NixString>,
+    config: ProxyConfig,
 }
 
 impl<R: Read, W: Write> NixDaemonProxy<R, W> {
-    pub fn new(r: R, w: W) -> Result<Self> {
+    pub fn new(r: R, w: W, config: ProxyConfig) -> Result<Self> {
         let mut daemon = Self {
             rx_from_client: NixRead { inner: r },
             tx_to_client: NixWrite { inner: w },
             rx_op_count: 0,
+            protocol_version: PROTOCOL_VERSION,
+            features: BTreeSet::new(),
+            config,
         };
-        // handshake
-        daemon.handshake_with_client().unwrap();
+        if let Err(e) = daemon.handshake_with_client() {
+            tracing::error!("handshake failed: {e}");
+            daemon.report_handshake_error(&e);
+            return Err(e.into());
+        }
         Ok(daemon)
     }
 
+    /// The protocol version that was agreed on with the client.
+    pub fn protocol_version(&self) -> DaemonVersion {
+        self.protocol_version
+    }
+
+    /// The protocol features that both we and the client support.
+    pub fn features(&self) -> &BTreeSet<NixString> {
+        &self.features
+    }
+
+    /// Whether both we and the client support the given protocol feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features
+            .contains(&NixString::from_bytes(feature.as_bytes()))
+    }
+
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    /// How much we trust the client.
+    pub fn trust(&self) -> TrustedFlag {
+        self.config.trust
+    }
+
+    /// Is the client trusted? See [`ProxyConfig::is_trusted`].
+    pub fn is_trusted(&self) -> bool {
+        self.config.is_trusted()
+    }
+
     #[tracing::instrument(skip(self))]
-    fn handshake_with_client(&mut self) -> Result<()> {
+    fn handshake_with_client(&mut self) -> Result<(), HandshakeError> {
+        let hello = ServerHello {
+            version: self.config.protocol_version,
+            daemon_version: self.config.daemon_version.clone(),
+            trusted: self.config.trust,
+            ..ServerHello::default()
+        };
+
         let magic = self.rx_from_client.read_u64()?;
         if magic != WORKER_MAGIC_1 {
-            tracing::error!("Got magic {magic:x}, expected magic {WORKER_MAGIC_1:x}");
-            todo!("handle error: protocol mismatch 1");
+            return Err(HandshakeError::BadMagic {
+                expected: WORKER_MAGIC_1,
+                got: magic,
+            });
         }
 
-        self.tx_to_client.write_u64(WORKER_MAGIC_2)?;
-        self.tx_to_client.write_u64(PROTOCOL_VERSION.into())?;
-        self.tx_to_client.flush()?;
+        self.tx_to_client.write_nix(&hello.magic)?;
+        self.tx_to_client.write_nix(&hello.version)?;
+        self.tx_to_client.inner.flush()?;
 
-        let client_version = self.rx_from_client.read_u64()?;
+        let client_version: DaemonVersion = self.rx_from_client.inner.read_nix()?;
+        self.protocol_version = hello
+            .version
+            .negotiate(client_version)
+            .ok_or(HandshakeError::UnsupportedVersion(client_version))?;
+        tracing::info!("Negotiated protocol version {}", self.protocol_version);
 
-        if client_version < PROTOCOL_VERSION.into() {
-            Err(anyhow!("Client version {client_version} is too old"))?;
+        let mut client_features = BTreeSet::new();
+        if self.protocol_version.minor >= 38 {
+            client_features = self.rx_from_client.inner.read_nix()?;
+            self.tx_to_client.write_nix(&hello.features)?;
+            self.tx_to_client.inner.flush()?;
+            self.features = hello
+                .features
+                .intersection(&client_features)
+                .cloned()
+                .collect();
+            tracing::info!("Agreed on features {:?}", self.features);
         }
 
-        let _obsolete_cpu_affinity = self.rx_from_client.read_u64()?;
-        let _obsolete_reserve_space = self.rx_from_client.read_u64()?;
-        // Version
-        self.tx_to_client
-            .write_string("rust-nix-bazel-0.1.0".as_bytes())?;
-        // Trusted flag. FIXME(jadel): configurability?
-        self.tx_to_client.write_u64(1)?;
-        self.tx_to_client.flush()?;
+        // The cpu affinity is followed by the actual affinity, if it was set.
+        let mut cpu_affinity = None;
+        if self.rx_from_client.read_u64()? != 0 {
+            cpu_affinity = Some(self.rx_from_client.read_u64()?);
+        }
+        let reserve_space: bool = self.rx_from_client.inner.read_nix()?;
+        let client_hello = ClientHello {
+            magic,
+            version: client_version,
+            features: client_features,
+            cpu_affinity,
+            reserve_space,
+        };
+        tracing::debug!(?client_hello);
+
+        if self.protocol_version.minor >= 33 {
+            self.tx_to_client.write_nix(&hello.daemon_version)?;
+        }
+        if self.protocol_version.minor >= 35 {
+            self.tx_to_client.write_nix(&hello.trusted)?;
+        }
+        self.tx_to_client.inner.flush()?;
+        tracing::debug!(server_hello = ?hello);
 
         self.tx_to_client.write_nix(&stderr::Msg::Last(()))?;
-        self.tx_to_client.flush()?;
+        self.tx_to_client.inner.flush()?;
         Ok(())
     }
 
+    /// Tell the client why the handshake failed, if it can understand us.
+    fn report_handshake_error(&mut self, e: &HandshakeError) {
+        let version = match e {
+            HandshakeError::UnsupportedVersion(v) => *v,
+            HandshakeError::Deser(_) => self.protocol_version,
+            // Either the client doesn't speak the nix protocol, or we can't reach it anymore.
+            _ => return,
+        };
+        let msg = stderr::Msg::Error(StderrError::new(e.to_string()));
+        let result = self
+            .tx_to_client
+            .inner
+            .write_nix_with_version(&msg, version)
+            .map_err(Error::from)
+            .and_then(|()| self.flush_tx_to_client());
+        if let Err(e) = result {
+            // We're closing the connection anyway.
+            tracing::warn!("failed to report handshake error: {e}");
+        }
+    }
+
+    /// Reads the next op from the client.
+    ///
+    /// If the client sends an op that doesn't exist in the negotiated protocol version,
+    /// we tell the client about it (like nix-daemon does) and return [`Error::UnknownOp`].
+    /// The connection should be closed after that, because we can't tell where the next
+    /// op starts.
+    ///
+    /// Ops that the client isn't allowed to do are refused with an error message, and
+    /// then we wait for the next one. Like nix-daemon, we clear `dont_check_sigs` on the
+    /// ops from untrusted clients, so that whoever handles them checks signatures.
     #[tracing::instrument(skip(self))]
     pub fn receive_next_op_from_client(&mut self) -> Result<WorkerOp> {
-        match self.rx_from_client.inner.read_nix::<WorkerOp>() {
+        loop {
+            let mut worker_op = self.receive_op()?;
+            let Err(e) = self.check_allowed(&worker_op) else {
+                if !self.is_trusted() {
+                    match &mut worker_op {
+                        WorkerOp::AddToStoreNar(op, _) => op.0.dont_check_sigs = false,
+                        WorkerOp::AddMultipleToStore(op, _) => op.0.dont_check_sigs = false,
+                        _ => {}
+                    }
+                }
+                return Ok(worker_op);
+            };
+            tracing::info!("refusing {}: {e}", worker_op.name());
+            if worker_op.requires_streaming_at(self.protocol_version) {
+                FramedReader::new(&mut self.rx_from_client.inner).drain()?;
+            }
+            self.send_error_to_client(&stderr::Msg::Error(StderrError::new(e.to_string())))?;
+            self.flush_tx_to_client()?;
+        }
+    }
+
+    /// Checks that an untrusted client is allowed to do `op`.
+    ///
+    /// The paths in an `AddMultipleToStore` are in its framed source, so we can't check
+    /// them here; [`crate::ValidPathInfoWithPath::check_untrusted`] checks them one at a time.
+    /// `ImportPaths` never carries signatures, so it is refused outright.
+    fn check_allowed(&self, op: &WorkerOp) -> Result<(), SignatureError> {
+        if self.is_trusted() {
+            return Ok(());
+        }
+        match op {
+            WorkerOp::AddToStoreNar(op, _) => {
+                op.0.path_info()
+                    .check_untrusted(&self.config.store_dir, &self.config.trusted_public_keys)
+            }
+            WorkerOp::ImportPaths(..) => Err(SignatureError::UnsignedImport),
+            _ => Ok(()),
+        }
+    }
+
+    fn receive_op(&mut self) -> Result<WorkerOp> {
+        let opcode = match self.rx_from_client.read_u64() {
             Err(crate::serialize::Error::Io(e))
                 if e.kind() == std::io::ErrorKind::UnexpectedEof =>
             {
                 eprintln!("EOF, closing");
-                Err(Error::Deser(crate::serialize::Error::Io(e)))
-            }
-            Err(e) => {
-                eprintln!("EOF, closing");
-                Err(Error::Deser(e))
-            }
-            Ok(worker_op) => {
-                self.rx_op_count += 1;
-                Ok(worker_op)
+                return Err(Error::Deser(crate::serialize::Error::Io(e)));
             }
+            opcode => opcode?,
+        };
+
+        if !WorkerOp::is_available(opcode, self.protocol_version) {
+            let msg = format!("unsupported operation {opcode}");
+            self.send_error_to_client(&stderr::Msg::Error(StderrError::new(msg)))?;
+            self.flush_tx_to_client()?;
+            return Err(Error::UnknownOp(opcode));
         }
+
+        let worker_op = WorkerOp::read_body(
+            opcode,
+            &mut self.rx_from_client.inner,
+            self.protocol_version,
+        )?;
+        self.rx_op_count += 1;
+        Ok(worker_op)
     }
 
     pub fn flush_tx_to_client(&mut self) -> Result<()> {
@@ -83,43 +303,47 @@ impl<R: Read, W: Write> NixDaemonProxy<R, W> {
 
     #[tracing::instrument(skip(self))]
     pub fn send_error_to_client(&mut self, error_msg: &crate::stderr::Msg) -> Result<()> {
-        self.tx_to_client.inner.write_nix(error_msg)?;
+        self.tx_to_client
+            .inner
+            .write_nix_with_version(error_msg, self.protocol_version)?;
         Ok(())
     }
 
     #[tracing::instrument(skip(self), ret)]
     pub fn streaming_length(&mut self) -> Result<usize> {
-        let mut de = crate::serialize::NixDeserializer {
-            read: &mut self.rx_from_client.inner,
-        };
+        let mut de = crate::serialize::NixDeserializer::new(&mut self.rx_from_client.inner);
         let len = u64::deserialize(&mut de)? as usize;
         Ok(len)
     }
 
     pub fn get_stream(&mut self, chunk_len: usize, buff: &mut [u8]) -> Result<()> {
-        let de = crate::serialize::NixDeserializer {
-            read: &mut self.rx_from_client.inner,
-        };
+        let de = crate::serialize::NixDeserializer::new(&mut self.rx_from_client.inner);
         de.read.read_exact(&mut buff[..chunk_len])?;
         Ok(())
     }
 
+    /// Reads the client's reply to a [`stderr::Msg::Read`].
+    pub fn read_tunnel_data(&mut self) -> Result<NixString> {
+        Ok(self.rx_from_client.read_string()?)
+    }
+
     pub fn write_build_response_to_client<T>(&mut self, resp: &T) -> Result<()>
     where
         T: serde::Serialize,
     {
-        let mut ser = NixSerializer {
-            write: &mut self.tx_to_client.inner,
-        };
+        let mut ser =
+            NixSerializer::with_version(&mut self.tx_to_client.inner, self.protocol_version);
         let mut dbg_buf = Vec::new();
-        let mut dbg_ser = NixSerializer {
-            write: &mut dbg_buf,
-        };
+        let mut dbg_ser = NixSerializer::with_version(&mut dbg_buf, self.protocol_version);
         resp.serialize(&mut dbg_ser)?;
         resp.serialize(&mut ser)?;
         Ok(())
     }
 
+    pub fn reader(&mut self) -> &mut R {
+        &mut self.rx_from_client.inner
+    }
+
     pub fn writer(&mut self) -> &mut W {
         &mut self.tx_to_client.inner
     }
@@ -0,0 +1,350 @@
+//! Reading nix's store database.
+//!
+//! Nix keeps the metadata of its store in a sqlite database, usually at
+//! `/nix/var/nix/db/db.sqlite`. [`NixDb`] answers the common queries from that database
+//! directly, in terms of the types that the protocol uses, so that they can be answered
+//! without a running `nix-daemon`.
+
+use std::{collections::BTreeSet, path::Path as FsPath};
+
+use anyhow::anyhow;
+use bstr::BStr;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    hash::HashFormat,
+    realisation::DrvOutput,
+    store_path::ParsedStorePath,
+    worker_op::{DerivationOutputMap, ValidPathInfo},
+    Error, NarHash, NixString, Realisation, Result, StorePath, StorePathSet, StringSet,
+};
+
+/// The parts of nix's database schema that we use. This is compatible with nix's own
+/// `db.sqlite`, so nix can read a store that we wrote (and vice versa).
+pub(crate) const SCHEMA: &str = r#"
+create table if not exists ValidPaths (
+    id               integer primary key autoincrement not null,
+    path             text unique not null,
+    hash             text not null,
+    registrationTime integer not null,
+    deriver          text,
+    narSize          integer,
+    ultimate         integer,
+    sigs             text,
+    ca               text
+);
+
+create table if not exists Refs (
+    referrer  integer not null,
+    reference integer not null,
+    primary key (referrer, reference),
+    foreign key (referrer) references ValidPaths(id) on delete cascade,
+    foreign key (reference) references ValidPaths(id) on delete restrict
+);
+
+create index if not exists IndexReferrer on Refs(referrer);
+create index if not exists IndexReference on Refs(reference);
+
+create trigger if not exists DeleteSelfRefs before delete on ValidPaths
+  begin
+    delete from Refs where referrer = old.id and reference = old.id;
+  end;
+
+create table if not exists DerivationOutputs (
+    drv  integer not null,
+    id   text not null,
+    path text not null,
+    primary key (drv, id),
+    foreign key (drv) references ValidPaths(id) on delete cascade
+);
+
+create index if not exists IndexDerivationOutputs on DerivationOutputs(path);
+
+create table if not exists Realisations (
+    id integer primary key autoincrement not null,
+    drvPath text not null,
+    outputName text not null,
+    outputPath integer not null,
+    signatures text,
+    foreign key (outputPath) references ValidPaths(id) on delete cascade
+);
+
+create index if not exists IndexRealisations on Realisations(drvPath, outputName);
+
+create table if not exists RealisationsRefs (
+    referrer integer not null,
+    realisationReference integer,
+    foreign key (referrer) references Realisations(id) on delete cascade,
+    foreign key (realisationReference) references Realisations(id) on delete restrict
+);
+
+create index if not exists IndexRealisationsRefs on RealisationsRefs(referrer);
+"#;
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Other(e.into())
+    }
+}
+
+pub(crate) fn path_str(path: &StorePath) -> Result<&str> {
+    std::str::from_utf8(path.as_ref())
+        .map_err(|_| anyhow!("path '{}' is not valid UTF-8", BStr::new(path)).into())
+}
+
+pub(crate) fn store_path(s: String) -> StorePath {
+    StorePath(s.into())
+}
+
+pub(crate) fn path_set(paths: impl IntoIterator<Item = StorePath>) -> StorePathSet {
+    StorePathSet {
+        paths: paths.into_iter().collect(),
+    }
+}
+
+/// A connection to a nix store database.
+///
+/// Paths that aren't in the database are reported as `None` (or empty sets), rather than
+/// as errors.
+pub struct NixDb {
+    conn: Connection,
+}
+
+impl NixDb {
+    /// Opens an existing database without modifying it.
+    pub fn open(path: impl AsRef<FsPath>) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(std::time::Duration::from_secs(60))?;
+        Ok(NixDb { conn })
+    }
+
+    /// Opens a database for writing, creating it (and the tables) if necessary.
+    pub(crate) fn create(path: impl AsRef<FsPath>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(60))?;
+        conn.pragma_update(None, "foreign_keys", "on")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(NixDb { conn })
+    }
+
+    pub(crate) fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub(crate) fn conn_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    pub(crate) fn path_id(&self, path: &StorePath) -> Result<Option<i64>> {
+        Ok(self
+            .conn
+            .query_row(
+                "select id from ValidPaths where path = ?",
+                [path_str(path)?],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn query_paths(&self, sql: &str, params: impl rusqlite::Params) -> Result<StorePathSet> {
+        let mut stmt = self.conn.prepare_cached(sql)?;
+        let paths = stmt
+            .query_map(params, |row| row.get::<_, String>(0))?
+            .map(|path| Ok(store_path(path?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(path_set(paths))
+    }
+
+    /// Is `path` in the database?
+    pub fn is_valid(&self, path: &StorePath) -> Result<bool> {
+        Ok(self.path_id(path)?.is_some())
+    }
+
+    /// The metadata of `path`, as returned by `QueryPathInfo`.
+    pub fn path_info(&self, path: &StorePath) -> Result<Option<ValidPathInfo>> {
+        let row = self
+            .conn
+            .query_row(
+                "select id, hash, registrationTime, deriver, narSize, ultimate, sigs, ca
+                 from ValidPaths where path = ?",
+                [path_str(path)?],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<i64>>(4)?,
+                        row.get::<_, Option<i64>>(5)?,
+                        row.get::<_, Option<String>>(6)?,
+                        row.get::<_, Option<String>>(7)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((id, hash, registration_time, deriver, nar_size, ultimate, sigs, ca)) = row else {
+            return Ok(None);
+        };
+
+        let references = self.query_paths(
+            "select v.path from Refs r join ValidPaths v on r.reference = v.id
+             where r.referrer = ? order by v.path",
+            params![id],
+        )?;
+        // The database has a `sha256:` prefix, but the protocol doesn't.
+        let hash = hash.strip_prefix("sha256:").unwrap_or(&hash);
+        Ok(Some(ValidPathInfo {
+            deriver: store_path(deriver.unwrap_or_default()),
+            hash: NarHash {
+                data: hash.as_bytes().to_vec().into(),
+            },
+            references,
+            registration_time: registration_time as u64,
+            nar_size: nar_size.unwrap_or(0) as u64,
+            ultimate: ultimate.unwrap_or(0) != 0,
+            sigs: StringSet {
+                paths: sigs
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|s| NixString::from_bytes(s.as_bytes()))
+                    .collect(),
+            },
+            content_address: ca.map(|ca| ca.parse()).transpose()?.into(),
+        }))
+    }
+
+    /// The paths that `path` refers to.
+    pub fn references(&self, path: &StorePath) -> Result<StorePathSet> {
+        self.query_paths(
+            "select v.path from Refs r join ValidPaths v on r.reference = v.id
+             where r.referrer = (select id from ValidPaths where path = ?)
+             order by v.path",
+            [path_str(path)?],
+        )
+    }
+
+    /// The paths that refer to `path`.
+    pub fn referrers(&self, path: &StorePath) -> Result<StorePathSet> {
+        self.query_paths(
+            "select v.path from Refs r join ValidPaths v on r.referrer = v.id
+             where r.reference = (select id from ValidPaths where path = ?)
+             order by v.path",
+            [path_str(path)?],
+        )
+    }
+
+    /// The valid derivations that have `path` as an output.
+    pub fn valid_derivers(&self, path: &StorePath) -> Result<StorePathSet> {
+        self.query_paths(
+            "select v.path from DerivationOutputs d join ValidPaths v on d.drv = v.id
+             where d.path = ? order by v.path",
+            [path_str(path)?],
+        )
+    }
+
+    /// The outputs of the derivation `drv`, sorted by name.
+    pub fn derivation_output_map(&self, drv: &StorePath) -> Result<DerivationOutputMap> {
+        let mut stmt = self.conn.prepare_cached(
+            "select d.id, d.path from DerivationOutputs d join ValidPaths v on d.drv = v.id
+             where v.path = ? order by d.id",
+        )?;
+        let paths = stmt
+            .query_map([path_str(drv)?], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (name, path) = row?;
+                Ok((name.into(), store_path(path)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(DerivationOutputMap { paths })
+    }
+
+    /// All the paths in the database.
+    pub fn all_valid_paths(&self) -> Result<StorePathSet> {
+        self.query_paths("select path from ValidPaths order by path", [])
+    }
+
+    /// The valid path in `store_dir` whose hash part is `hash_part`.
+    pub fn path_from_hash_part(
+        &self,
+        store_dir: &[u8],
+        hash_part: &[u8],
+    ) -> Result<Option<StorePath>> {
+        let prefix = [store_dir, b"/", hash_part, b"-"].concat();
+        let prefix = String::from_utf8(prefix)
+            .map_err(|_| anyhow!("invalid hash part '{}'", BStr::new(hash_part)))?;
+        let found: Option<String> = self
+            .conn
+            .query_row(
+                "select path from ValidPaths where path >= ? order by path limit 1",
+                [&prefix],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(found.filter(|p| p.starts_with(&prefix)).map(store_path))
+    }
+
+    /// The realisation of a derivation output.
+    pub fn realisation(&self, id: &DrvOutput) -> Result<Option<Realisation>> {
+        let drv_hash = id.drv_hash.encode(HashFormat::Base16, true);
+        let found: Option<(i64, String, Option<String>)> = self
+            .conn
+            .query_row(
+                "select r.id, v.path, r.signatures from Realisations r
+                 join ValidPaths v on r.outputPath = v.id
+                 where r.drvPath = ? and r.outputName = ?",
+                params![drv_hash, id.output_name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((realisation_id, out_path, signatures)) = found else {
+            return Ok(None);
+        };
+
+        let mut stmt = self.conn.prepare_cached(
+            "select r.drvPath, r.outputName, v.path from RealisationsRefs rr
+             join Realisations r on rr.realisationReference = r.id
+             join ValidPaths v on r.outputPath = v.id
+             where rr.referrer = ?",
+        )?;
+        let dependent_realisations = stmt
+            .query_map([realisation_id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .map(|row| {
+                let (drv_hash, output_name, path) = row?;
+                let id = DrvOutput {
+                    drv_hash: drv_hash.parse()?,
+                    output_name,
+                };
+                Ok((id, parsed_path(&path)?))
+            })
+            .collect::<Result<_>>()?;
+
+        let signatures: BTreeSet<_> = signatures
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+        Ok(Some(Realisation {
+            id: id.clone(),
+            out_path: parsed_path(&out_path)?,
+            signatures,
+            dependent_realisations,
+        }))
+    }
+}
+
+/// Parses a path from the database, without needing to know the store directory.
+fn parsed_path(path: &str) -> Result<ParsedStorePath> {
+    let base_name = path.rsplit('/').next().unwrap_or(path);
+    Ok(ParsedStorePath::from_base_name(base_name.as_bytes())?)
+}
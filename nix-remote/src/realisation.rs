@@ -0,0 +1,225 @@
+//! Realisations, which record where the outputs of content-addressed derivations ended up.
+//!
+//! An output of a content-addressed derivation is identified by a [`DrvOutput`], like
+//! `sha256:<base16>!out`, where the hash is the derivation's hash modulo fixed-output
+//! derivations. On the wire, a [`Realisation`] is a JSON string like
+//!
+//! ```text
+//! {"dependentRealisations":{},"id":"sha256:15e3...!out","outPath":"g1w7...-foo","signatures":[]}
+//! ```
+//!
+//! where the store paths don't include the store directory.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+};
+
+use bstr::BStr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::{Hash, HashError, HashFormat},
+    store_path::{ParsedStorePath, StorePathError},
+    NixString,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RealisationError {
+    #[error("invalid DrvOutput '{0}'")]
+    InvalidDrvOutput(String),
+
+    #[error("invalid realisation: {0}")]
+    InvalidJson(String),
+
+    #[error(transparent)]
+    Hash(#[from] HashError),
+
+    #[error(transparent)]
+    StorePath(#[from] StorePathError),
+}
+
+/// An output of a content-addressed derivation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct DrvOutput {
+    /// The hash of the derivation, modulo fixed-output derivations.
+    pub drv_hash: Hash,
+    pub output_name: String,
+}
+
+impl DrvOutput {
+    /// Parses the wire form of a `DrvOutput`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RealisationError> {
+        std::str::from_utf8(bytes)
+            .map_err(|_| RealisationError::InvalidDrvOutput(BStr::new(bytes).to_string()))?
+            .parse()
+    }
+}
+
+impl fmt::Display for DrvOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}!{}",
+            self.drv_hash.encode(HashFormat::Base16, true),
+            self.output_name
+        )
+    }
+}
+
+impl FromStr for DrvOutput {
+    type Err = RealisationError;
+
+    fn from_str(s: &str) -> Result<Self, RealisationError> {
+        let (drv_hash, output_name) = s
+            .split_once('!')
+            .ok_or_else(|| RealisationError::InvalidDrvOutput(s.to_owned()))?;
+        Ok(DrvOutput {
+            drv_hash: Hash::parse(drv_hash)?,
+            output_name: output_name.to_owned(),
+        })
+    }
+}
+
+impl Serialize for DrvOutput {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NixString::from(self.to_string()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DrvOutput {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = NixString::deserialize(deserializer)?;
+        DrvOutput::from_bytes(&s.0).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where an output of a content-addressed derivation is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Realisation {
+    pub id: DrvOutput,
+    pub out_path: ParsedStorePath,
+    pub signatures: BTreeSet<String>,
+    /// The realisations of the derivation's inputs that this one was built with.
+    pub dependent_realisations: BTreeMap<DrvOutput, ParsedStorePath>,
+}
+
+/// The JSON form of a realisation. Nix writes the fields in alphabetical order.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RealisationJson {
+    #[serde(default)]
+    dependent_realisations: BTreeMap<String, String>,
+    id: String,
+    out_path: String,
+    #[serde(default)]
+    signatures: BTreeSet<String>,
+}
+
+impl Realisation {
+    pub fn from_json(json: &[u8]) -> Result<Self, RealisationError> {
+        let json: RealisationJson = serde_json::from_slice(json)
+            .map_err(|e| RealisationError::InvalidJson(e.to_string()))?;
+        let path = |p: &str| ParsedStorePath::from_base_name(p.as_bytes());
+        Ok(Realisation {
+            id: json.id.parse()?,
+            out_path: path(&json.out_path)?,
+            signatures: json.signatures,
+            dependent_realisations: json
+                .dependent_realisations
+                .iter()
+                .map(|(id, out_path)| Ok((id.parse()?, path(out_path)?)))
+                .collect::<Result<_, RealisationError>>()?,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let json = RealisationJson {
+            dependent_realisations: self
+                .dependent_realisations
+                .iter()
+                .map(|(id, out_path)| (id.to_string(), out_path.to_string()))
+                .collect(),
+            id: self.id.to_string(),
+            out_path: self.out_path.to_string(),
+            signatures: self.signatures.clone(),
+        };
+        serde_json::to_string(&json).expect("realisations can be serialized")
+    }
+}
+
+impl Serialize for Realisation {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NixString::from(self.to_json()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Realisation {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = NixString::deserialize(deserializer)?;
+        Realisation::from_json(&s.0).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for Realisation {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Realisation {
+            id: u.arbitrary()?,
+            out_path: u.arbitrary()?,
+            signatures: u.arbitrary()?,
+            dependent_realisations: u.arbitrary()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!baz";
+
+    #[test]
+    fn drv_output() {
+        let id: DrvOutput = ID.parse().unwrap();
+        assert_eq!(id.output_name, "baz");
+        assert_eq!(id.to_string(), ID);
+        assert_eq!(
+            "sha256:15e3!baz"
+                .parse::<DrvOutput>()
+                .unwrap_err()
+                .to_string(),
+            "hash 'sha256:15e3' has wrong length for hash type 'sha256'"
+        );
+        assert_eq!(
+            "sha256:15e3".parse::<DrvOutput>(),
+            Err(RealisationError::InvalidDrvOutput("sha256:15e3".to_owned()))
+        );
+    }
+
+    #[test]
+    fn json() {
+        let json = format!(
+            r#"{{"dependentRealisations":{{"{ID}":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"}},"id":"{ID}","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","signatures":["asdf"]}}"#
+        );
+        let realisation = Realisation::from_json(json.as_bytes()).unwrap();
+        assert_eq!(realisation.out_path.name(), "foo");
+        assert_eq!(realisation.dependent_realisations.len(), 1);
+        assert_eq!(realisation.to_json(), json);
+
+        // Missing optional fields are fine.
+        let minimal =
+            format!(r#"{{"id":"{ID}","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"}}"#);
+        let realisation = Realisation::from_json(minimal.as_bytes()).unwrap();
+        assert!(realisation.signatures.is_empty());
+
+        assert!(Realisation::from_json(b"{}").is_err());
+        let bad_path = format!(r#"{{"id":"{ID}","outPath":"foo"}}"#);
+        assert!(matches!(
+            Realisation::from_json(bad_path.as_bytes()),
+            Err(RealisationError::StorePath(_))
+        ));
+    }
+}
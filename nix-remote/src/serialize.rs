@@ -32,11 +32,21 @@
 //! followed by a body. This serializer does not have built-in support for that, because serde
 //! enums are built on string-valued tags (whereas the nix protocol wants integer tags).
 //! Instead, we have a separate `tagged_serde` macro for transforming enums into tuples.
+//!
+//! Finally, some types change their layout depending on the protocol version (for example,
+//! `BuildResult` gained some fields in version 1.29). The serializer and deserializer know
+//! which version was negotiated, and such types can look at it by implementing [`Versioned`].
 
-use std::io::{Read, Write};
+use std::{
+    cell::Cell,
+    io::{Read, Write},
+    marker::PhantomData,
+};
 
 use serde::{de, ser, Serialize};
 
+use crate::{DaemonVersion, PROTOCOL_VERSION};
+
 pub struct Tee<R, W> {
     read: R,
     write: W,
@@ -88,22 +98,53 @@ impl ser::Error for Error {
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub trait NixReadExt {
+    /// Read a value, using the layout of [`PROTOCOL_VERSION`].
     fn read_nix<'de, 'a: 'de, D: serde::Deserialize<'de>>(&'a mut self) -> Result<D>;
+
+    /// Read a value, using the layout of the given protocol version.
+    fn read_nix_with_version<'de, 'a: 'de, D: serde::Deserialize<'de>>(
+        &'a mut self,
+        version: DaemonVersion,
+    ) -> Result<D>;
 }
 
 impl<R: Read> NixReadExt for R {
     fn read_nix<'de, 'a: 'de, D: serde::Deserialize<'de>>(&'a mut self) -> Result<D> {
-        D::deserialize(&mut NixDeserializer { read: self })
+        D::deserialize(&mut NixDeserializer::new(self))
+    }
+
+    fn read_nix_with_version<'de, 'a: 'de, D: serde::Deserialize<'de>>(
+        &'a mut self,
+        version: DaemonVersion,
+    ) -> Result<D> {
+        D::deserialize(&mut NixDeserializer::with_version(self, version))
     }
 }
 
 pub trait NixWriteExt {
+    /// Write a value, using the layout of [`PROTOCOL_VERSION`].
     fn write_nix<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()>;
+
+    /// Write a value, using the layout of the given protocol version.
+    fn write_nix_with_version<T: Serialize + ?Sized>(
+        &mut self,
+        val: &T,
+        version: DaemonVersion,
+    ) -> Result<()>;
 }
 
 impl<W: Write> NixWriteExt for W {
     fn write_nix<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()> {
-        val.serialize(&mut NixSerializer { write: self })?;
+        val.serialize(&mut NixSerializer::new(self))?;
+        Ok(())
+    }
+
+    fn write_nix_with_version<T: Serialize + ?Sized>(
+        &mut self,
+        val: &T,
+        version: DaemonVersion,
+    ) -> Result<()> {
+        val.serialize(&mut NixSerializer::with_version(self, version))?;
         Ok(())
     }
 }
@@ -112,13 +153,136 @@ impl<W: Write> NixWriteExt for W {
 // TODO: should decouple the lifetime of the &mut ref from the lifetime of the Read
 pub struct NixDeserializer<'de> {
     pub read: &'de mut dyn Read,
+    /// The protocol version whose layout we expect.
+    pub version: DaemonVersion,
 }
 
 /// A serializer for the nix remote protocol.
 pub struct NixSerializer<'se> {
     pub write: &'se mut dyn Write,
+    /// The protocol version whose layout we write.
+    pub version: DaemonVersion,
+}
+
+/// Types whose wire format depends on the protocol version.
+///
+/// This doesn't make a type (de)serializable by itself: its `Serialize` and `Deserialize`
+/// implementations need to go through [`serialize_versioned`] and [`deserialize_versioned`]
+/// (the `versioned_serde` macro generates them). When the type is (de)serialized by a
+/// [`NixSerializer`] or [`NixDeserializer`], it gets to see their version; other serializers
+/// get the layout of [`PROTOCOL_VERSION`].
+pub trait Versioned: Sized {
+    fn serialize_versioned<S: ser::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>;
+
+    fn deserialize_versioned<'de, D: de::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error>;
+}
+
+// Serde doesn't let us pass any information from the serializer to the value being
+// serialized, so we smuggle the version through a thread-local instead. Versioned types
+// serialize themselves as a newtype struct with this magic name, and our (de)serializers
+// respond by setting the thread-local for the duration of the inner call.
+const VERSIONED: &str = "$nix_remote::Versioned";
+
+thread_local! {
+    static CURRENT_VERSION: Cell<Option<DaemonVersion>> = const { Cell::new(None) };
+}
+
+struct VersionGuard(Option<DaemonVersion>);
+
+impl VersionGuard {
+    fn set(version: DaemonVersion) -> Self {
+        VersionGuard(CURRENT_VERSION.replace(Some(version)))
+    }
+}
+
+impl Drop for VersionGuard {
+    fn drop(&mut self) {
+        CURRENT_VERSION.set(self.0);
+    }
+}
+
+fn current_version() -> DaemonVersion {
+    CURRENT_VERSION.get().unwrap_or(PROTOCOL_VERSION)
+}
+
+struct WithVersion<'a, T>(&'a T);
+
+impl<T: Versioned> Serialize for WithVersion<'_, T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_versioned(current_version(), serializer)
+    }
 }
 
+/// Serialize a [`Versioned`] value, using the version of the serializer.
+pub fn serialize_versioned<T: Versioned, S: ser::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct(VERSIONED, &WithVersion(value))
+}
+
+/// Deserialize a [`Versioned`] value, using the version of the deserializer.
+pub fn deserialize_versioned<'de, T: Versioned, D: de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    struct Visitor<T>(PhantomData<T>);
+
+    impl<'de, T: Versioned> de::Visitor<'de> for Visitor<T> {
+        type Value = T;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a versioned value")
+        }
+
+        fn visit_newtype_struct<D: de::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<T, D::Error> {
+            T::deserialize_versioned(current_version(), deserializer)
+        }
+    }
+
+    deserializer.deserialize_newtype_struct(VERSIONED, Visitor(PhantomData))
+}
+
+/// Implement `Serialize` and `Deserialize` for types implementing [`Versioned`].
+macro_rules! versioned_serde {
+    ($($ty:ty),*) => {
+        $(
+            impl ::serde::Serialize for $ty {
+                fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error> {
+                    $crate::serialize::serialize_versioned(self, serializer)
+                }
+            }
+
+            impl<'de> ::serde::Deserialize<'de> for $ty {
+                fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::core::result::Result<Self, D::Error> {
+                    $crate::serialize::deserialize_versioned(deserializer)
+                }
+            }
+        )*
+    };
+}
+pub(crate) use versioned_serde;
+
+/// Helpers for implementing [`Versioned::deserialize_versioned`] in terms of `SeqAccess`.
+pub trait SeqAccessExt<'de>: de::SeqAccess<'de> {
+    /// Read the next element, failing if there isn't one.
+    fn expect_element<T: de::Deserialize<'de>>(&mut self) -> Result<T, Self::Error> {
+        self.next_element()?
+            .ok_or_else(|| de::Error::custom("unexpected end of sequence"))
+    }
+}
+
+impl<'de, A: de::SeqAccess<'de>> SeqAccessExt<'de> for A {}
+
 struct Seq<'a, 'de: 'a> {
     deserializer: &'a mut NixDeserializer<'de>,
     len: usize,
@@ -148,6 +312,15 @@ impl<'a, 'de: 'a> de::SeqAccess<'de> for Seq<'a, 'de> {
 }
 
 impl<'de> NixDeserializer<'de> {
+    /// A deserializer expecting the layout of [`PROTOCOL_VERSION`].
+    pub fn new(read: &'de mut dyn Read) -> Self {
+        Self::with_version(read, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(read: &'de mut dyn Read, version: DaemonVersion) -> Self {
+        NixDeserializer { read, version }
+    }
+
     #[tracing::instrument(skip(self), ret)]
     pub fn read_u64(&mut self) -> Result<u64> {
         let mut buf = [0u8; 8];
@@ -167,7 +340,7 @@ impl<'de> NixDeserializer<'de> {
         let mut buf = vec![0; len];
         self.read.read_exact(&mut buf)?;
 
-        if len % 8 > 0 {
+        if !len.is_multiple_of(8) {
             let padding = 8 - len % 8;
             let mut pad_buf = [0; 8];
             self.read.read_exact(&mut pad_buf[..padding])?;
@@ -178,6 +351,15 @@ impl<'de> NixDeserializer<'de> {
 }
 
 impl<'se> NixSerializer<'se> {
+    /// A serializer writing the layout of [`PROTOCOL_VERSION`].
+    pub fn new(write: &'se mut dyn Write) -> Self {
+        Self::with_version(write, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(write: &'se mut dyn Write, version: DaemonVersion) -> Self {
+        NixSerializer { write, version }
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn write_byte_buf(&mut self, s: &[u8]) -> Result<()> {
         let len = s.len();
@@ -185,7 +367,7 @@ impl<'se> NixSerializer<'se> {
         self.write.write_all(&len.to_le_bytes())?;
         self.write.write_all(s)?;
 
-        if len % 8 > 0 {
+        if !len.is_multiple_of(8) {
             let padding = 8 - len % 8;
             let pad_buf = [0; 8];
             self.write.write_all(&pad_buf[..padding])?;
@@ -195,7 +377,7 @@ impl<'se> NixSerializer<'se> {
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut NixDeserializer<'de> {
     type Error = Error;
 
     fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
@@ -349,12 +531,13 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
 
     fn deserialize_newtype_struct<V>(
         self,
-        _name: &'static str,
+        name: &'static str,
         visitor: V,
     ) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
+        let _guard = (name == VERSIONED).then(|| VersionGuard::set(self.version));
         visitor.visit_newtype_struct(self)
     }
 
@@ -666,12 +849,13 @@ impl<'se> serde::Serializer for &mut NixSerializer<'se> {
 
     fn serialize_newtype_struct<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: Serialize + ?Sized,
     {
+        let _guard = (name == VERSIONED).then(|| VersionGuard::set(self.version));
         value.serialize(self)
     }
 
@@ -0,0 +1,434 @@
+//! Ed25519 signatures on store paths.
+//!
+//! Nix signs a path by signing its *fingerprint*,
+//!
+//! ```text
+//! 1;/nix/store/<hash>-foo;sha256:<nix32 nar hash>;<nar size>;/nix/store/<hash>-bar,...
+//! ```
+//!
+//! and the signature is stored in `sigs` as `<key name>:<base64 signature>`. Keys are
+//! written the same way, like `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bstr::BStr;
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::{
+    hash::{Hash, HashError, HashFormat},
+    store_path::{StoreDir, StoreReferences},
+    NixString, StorePath, StorePathSet, ValidPathInfoWithPath,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    // Keys are secret, so they don't go into error messages.
+    #[error("key is corrupt")]
+    CorruptKey,
+
+    #[error("secret key is not valid")]
+    InvalidSecretKey,
+
+    #[error("public key is not valid")]
+    InvalidPublicKey,
+
+    #[error("cannot calculate fingerprint of path '{0}' because its size is not known")]
+    UnknownNarSize(String),
+
+    #[error("cannot add path '{0}' because it lacks a signature by a trusted key")]
+    Unsigned(String),
+
+    #[error("cannot import paths because exports lack signatures by a trusted key")]
+    UnsignedImport,
+
+    #[error(transparent)]
+    Hash(#[from] HashError),
+}
+
+/// Splits a key into its name and its decoded bytes.
+fn split_key(s: &str) -> Result<(&str, Vec<u8>), SignatureError> {
+    let (name, key) = s.split_once(':').ok_or(SignatureError::CorruptKey)?;
+    let key = STANDARD
+        .decode(key)
+        .map_err(|_| SignatureError::CorruptKey)?;
+    if name.is_empty() || key.is_empty() {
+        return Err(SignatureError::CorruptKey);
+    }
+    Ok((name, key))
+}
+
+/// A key for signing store paths, like the ones made by `nix key generate-secret`.
+#[derive(Clone)]
+pub struct SecretKey {
+    pub name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    /// Makes a key from the 32-byte seed that ed25519 keys are derived from.
+    pub fn from_seed(name: impl Into<String>, seed: &[u8; 32]) -> Self {
+        SecretKey {
+            name: name.into(),
+            key: SigningKey::from_bytes(seed),
+        }
+    }
+
+    /// Signs `data`, returning the signature in the form that goes into `sigs`.
+    pub fn sign(&self, data: &[u8]) -> String {
+        let signature = self.key.sign(data);
+        format!("{}:{}", self.name, STANDARD.encode(signature.to_bytes()))
+    }
+
+    pub fn to_public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+}
+
+/// Doesn't show the key itself.
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Nix stores the public key after the seed.
+        let key = STANDARD.encode(self.key.to_keypair_bytes());
+        write!(f, "{}:{key}", self.name)
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, SignatureError> {
+        let (name, key) = split_key(s.trim())?;
+        let key = key
+            .try_into()
+            .ok()
+            .and_then(|key| SigningKey::from_keypair_bytes(&key).ok())
+            .ok_or(SignatureError::InvalidSecretKey)?;
+        Ok(SecretKey {
+            name: name.to_owned(),
+            key,
+        })
+    }
+}
+
+/// A key for checking signatures on store paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Checks a signature in the form that goes into `sigs`.
+    ///
+    /// Signatures by other keys are never valid.
+    pub fn verify(&self, data: &[u8], signature: &str) -> bool {
+        let Some((name, signature)) = signature.split_once(':') else {
+            return false;
+        };
+        let Some(signature) = STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|sig| ed25519_dalek::Signature::from_slice(&sig).ok())
+        else {
+            return false;
+        };
+        name == self.name && self.key.verify(data, &signature).is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, STANDARD.encode(self.key.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, SignatureError> {
+        let (name, key) = split_key(s.trim())?;
+        let key = key
+            .try_into()
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .ok_or(SignatureError::InvalidPublicKey)?;
+        Ok(PublicKey {
+            name: name.to_owned(),
+            key,
+        })
+    }
+}
+
+/// A set of trusted public keys, like nix's `trusted-public-keys` setting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublicKeys {
+    keys: BTreeMap<String, PublicKey>,
+}
+
+impl PublicKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key, replacing any key with the same name.
+    pub fn insert(&mut self, key: PublicKey) {
+        self.keys.insert(key.name.clone(), key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Is `signature` a valid signature of `data` by one of these keys?
+    pub fn verify(&self, data: &[u8], signature: &str) -> bool {
+        let Some((name, _)) = signature.split_once(':') else {
+            return false;
+        };
+        self.keys
+            .get(name)
+            .is_some_and(|key| key.verify(data, signature))
+    }
+
+    /// The number of different signatures in `sigs` that are valid for `fingerprint`.
+    pub fn count_valid<'a>(
+        &self,
+        fingerprint: &str,
+        sigs: impl IntoIterator<Item = &'a NixString>,
+    ) -> usize {
+        let mut sigs: Vec<&[u8]> = sigs.into_iter().map(|sig| sig.0.as_slice()).collect();
+        sigs.sort();
+        sigs.dedup();
+        sigs.into_iter()
+            .filter_map(|sig| std::str::from_utf8(sig).ok())
+            .filter(|sig| self.verify(fingerprint.as_bytes(), sig))
+            .count()
+    }
+}
+
+impl FromIterator<PublicKey> for PublicKeys {
+    fn from_iter<I: IntoIterator<Item = PublicKey>>(iter: I) -> Self {
+        let mut keys = PublicKeys::new();
+        for key in iter {
+            keys.insert(key);
+        }
+        keys
+    }
+}
+
+/// Computes the fingerprint of a store path, which is what its signatures sign.
+pub fn fingerprint(
+    path: &StorePath,
+    nar_hash: &Hash,
+    nar_size: u64,
+    references: &StorePathSet,
+) -> Result<String, SignatureError> {
+    let path = BStr::new(&path.0 .0);
+    if nar_size == 0 {
+        return Err(SignatureError::UnknownNarSize(path.to_string()));
+    }
+    let mut references: Vec<_> = references
+        .paths
+        .iter()
+        .map(|r| BStr::new(&r.0 .0).to_string())
+        .collect();
+    references.sort();
+    Ok(format!(
+        "1;{path};{};{nar_size};{}",
+        nar_hash.encode(HashFormat::Nix32, true),
+        references.join(",")
+    ))
+}
+
+impl ValidPathInfoWithPath {
+    /// The fingerprint of this path, which is what its signatures sign.
+    pub fn fingerprint(&self) -> Result<String, SignatureError> {
+        let nar_hash = Hash::try_from(&self.info.hash)?;
+        fingerprint(
+            &self.path,
+            &nar_hash,
+            self.info.nar_size,
+            &self.info.references,
+        )
+    }
+
+    /// Adds a signature by `key`.
+    pub fn sign(&mut self, key: &SecretKey) -> Result<(), SignatureError> {
+        let sig = NixString::from(key.sign(self.fingerprint()?.as_bytes()));
+        if !self.info.sigs.paths.contains(&sig) {
+            self.info.sigs.paths.push(sig);
+        }
+        Ok(())
+    }
+
+    /// The number of signatures on this path by one of `keys`.
+    pub fn check_signatures(&self, keys: &PublicKeys) -> Result<usize, SignatureError> {
+        Ok(keys.count_valid(&self.fingerprint()?, &self.info.sigs.paths))
+    }
+
+    /// Is this a content-addressed path, whose store path really follows from its
+    /// content address and references?
+    pub fn is_content_addressed(&self, store_dir: &StoreDir) -> bool {
+        let Some(ca) = &self.info.content_address.0 else {
+            return false;
+        };
+        let Ok(path) = store_dir.parse(&self.path) else {
+            return false;
+        };
+        let mut references = StoreReferences::default();
+        for r in &self.info.references.paths {
+            if r == &self.path {
+                references.self_reference = true;
+            } else if let Ok(r) = store_dir.parse(r) {
+                references.others.insert(r);
+            } else {
+                return false;
+            }
+        }
+        store_dir
+            .make_content_addressed_path(path.name(), ca, &references)
+            .is_ok_and(|ca_path| ca_path == path)
+    }
+
+    /// Checks that we can take this path from an untrusted client, which needs a signature
+    /// by one of `keys`.
+    ///
+    /// Like nix, we don't need a signature on content-addressed paths, because the store
+    /// can check their contents against their content address.
+    pub fn check_untrusted(
+        &self,
+        store_dir: &StoreDir,
+        keys: &PublicKeys,
+    ) -> Result<(), SignatureError> {
+        if !self.is_content_addressed(store_dir) && self.check_signatures(keys)? == 0 {
+            return Err(SignatureError::Unsigned(
+                BStr::new(&self.path.0 .0).to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        content_address::OptionalContentAddress, worker_op::ValidPathInfo, NarHash, StringSet,
+    };
+
+    fn path(p: &str) -> StorePath {
+        StorePath(NixString::from_bytes(p.as_bytes()))
+    }
+
+    fn info() -> ValidPathInfoWithPath {
+        let hash = Hash::compute(crate::hash::HashAlgorithm::Sha256, b"");
+        ValidPathInfoWithPath {
+            path: path("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"),
+            info: ValidPathInfo {
+                deriver: path(""),
+                hash: NarHash::from(&hash),
+                references: StorePathSet {
+                    paths: vec![
+                        path("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"),
+                        path("/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39"),
+                    ],
+                },
+                registration_time: 0,
+                nar_size: 226560,
+                ultimate: false,
+                sigs: StringSet::default(),
+                content_address: OptionalContentAddress::default(),
+            },
+        }
+    }
+
+    fn key(name: &str) -> SecretKey {
+        SecretKey::from_seed(name, &[7; 32])
+    }
+
+    #[test]
+    fn fingerprint() {
+        assert_eq!(
+            info().fingerprint().unwrap(),
+            "1;/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1;\
+             sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73;226560;\
+             /nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39,\
+             /nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"
+        );
+
+        let mut info = info();
+        info.info.nar_size = 0;
+        assert!(matches!(
+            info.fingerprint(),
+            Err(SignatureError::UnknownNarSize(_))
+        ));
+    }
+
+    #[test]
+    fn keys() {
+        let secret = key("test-1");
+        let parsed: SecretKey = secret.to_string().parse().unwrap();
+        assert_eq!(parsed.to_string(), secret.to_string());
+        let public = secret.to_public_key();
+        assert_eq!(public.to_string().parse::<PublicKey>().unwrap(), public);
+        assert!(!format!("{secret:?}").contains(&secret.to_string()));
+
+        assert_eq!(
+            "test-1".parse::<PublicKey>(),
+            Err(SignatureError::CorruptKey)
+        );
+        assert_eq!(
+            ":AAAA".parse::<PublicKey>(),
+            Err(SignatureError::CorruptKey)
+        );
+        assert_eq!(
+            "test-1:AAAA".parse::<PublicKey>(),
+            Err(SignatureError::InvalidPublicKey)
+        );
+        assert!(matches!(
+            public.to_string().parse::<SecretKey>(),
+            Err(SignatureError::InvalidSecretKey)
+        ));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let secret = key("test-1");
+        let keys: PublicKeys = [secret.to_public_key()].into_iter().collect();
+        let mut info = info();
+        assert_eq!(info.check_signatures(&keys).unwrap(), 0);
+
+        info.sign(&secret).unwrap();
+        info.sign(&secret).unwrap();
+        assert_eq!(info.info.sigs.paths.len(), 1);
+        assert_eq!(info.check_signatures(&keys).unwrap(), 1);
+
+        // Duplicate signatures only count once, and untrusted ones don't count.
+        let sig = info.info.sigs.paths[0].clone();
+        info.info.sigs.paths.push(sig);
+        info.sign(&key("other-1")).unwrap();
+        info.info.sigs.paths.push("garbage".to_owned().into());
+        assert_eq!(info.check_signatures(&keys).unwrap(), 1);
+
+        // A key with the right name but the wrong secret doesn't help.
+        let impostor = SecretKey::from_seed("test-1", &[8; 32]);
+        let mut forged = self::info();
+        forged.sign(&impostor).unwrap();
+        assert_eq!(forged.check_signatures(&keys).unwrap(), 0);
+
+        // Changing the path invalidates the signature.
+        info.info.nar_size += 1;
+        assert_eq!(info.check_signatures(&keys).unwrap(), 0);
+    }
+}
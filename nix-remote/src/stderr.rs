@@ -10,19 +10,23 @@ use bstr::BString;
 use serde::{Deserialize, Serialize};
 use tagged_serde::TaggedSerde;
 
-use crate::{NixString, Result};
+use crate::serialize::{versioned_serde, Versioned};
+use crate::{DaemonVersion, NixString, Result};
 
 /// The different stderr messages.
 ///
 /// On the wire, they are represented as the opcode followed by the body.
-///
-// STDERR_READ is... interesting. Fortunately, it appears to have been superceded by FramedSource.
-// It is not used in the current version of the nix protocol
-// Read = 0x64617461,
 #[derive(Debug, TaggedSerde, PartialEq, Clone, Eq)]
 pub enum Msg {
     #[tagged_serde = 0x64617416]
     Write(NixString),
+    /// The daemon wants (up to) this many bytes of data from the client, which replies
+    /// with a string.
+    ///
+    /// This is only used by old ops: `ImportPaths`, and `AddToStoreNar` before protocol
+    /// version 1.23. Newer ops send their data as a framed source.
+    #[tagged_serde = 0x64617461]
+    Read(u64),
     #[tagged_serde = 0x63787470]
     Error(StderrError),
     #[tagged_serde = 0x6f6c6d67]
@@ -37,7 +41,12 @@ pub enum Msg {
     Last(()),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+/// An error reported by the daemon.
+///
+/// Before protocol version 1.26, errors were just a message and an exit status. When
+/// reading one of those, we fill in the other fields the way nix would; when writing one,
+/// everything apart from the message is lost.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StderrError {
     pub typ: BString,
     pub level: u64,
@@ -47,6 +56,66 @@ pub struct StderrError {
     pub traces: Vec<Trace>,
 }
 
+impl StderrError {
+    /// An error with just a message, like the ones nix makes from a plain `Error`.
+    pub fn new(message: impl Into<BString>) -> Self {
+        StderrError {
+            typ: "Error".into(),
+            level: 0,
+            name: "Error".into(),
+            message: message.into(),
+            have_pos: 0,
+            traces: Vec::new(),
+        }
+    }
+}
+
+impl Versioned for StderrError {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if version.minor >= 26 {
+            (
+                &self.typ,
+                self.level,
+                &self.name,
+                &self.message,
+                self.have_pos,
+                &self.traces,
+            )
+                .serialize(serializer)
+        } else {
+            // The exit status. Nix uses 1 for generic errors.
+            (&self.message, 1u64).serialize(serializer)
+        }
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        if version.minor >= 26 {
+            let (typ, level, name, message, have_pos, traces) =
+                Deserialize::deserialize(deserializer)?;
+            Ok(StderrError {
+                typ,
+                level,
+                name,
+                message,
+                have_pos,
+                traces,
+            })
+        } else {
+            let (message, _status): (BString, u64) = Deserialize::deserialize(deserializer)?;
+            Ok(StderrError::new(message))
+        }
+    }
+}
+
+versioned_serde!(StderrError);
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct StderrStartActivity {
     pub act: u64,
@@ -0,0 +1,857 @@
+//! Serving the nix protocol from a custom store.
+//!
+//! Implement [`Store`] and pass it to [`serve`], which takes care of the protocol: the
+//! handshake, reading ops, feeding framed sources to the store, and sending the store's
+//! responses (or errors) back to the client.
+
+use std::{
+    ffi::OsString,
+    io::{Read, Write},
+    path::{Path as FsPath, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use anyhow::anyhow;
+use bstr::BStr;
+
+use crate::{
+    content_address::{
+        ContentAddress, ContentAddressMethod, ContentAddressMethodWithAlgo, OptionalContentAddress,
+    },
+    framed_data::FramedReader,
+    hash::{Hash, HashAlgorithm, Hasher},
+    nar::Nar,
+    nix_daemon_proxy::{NixDaemonProxy, ProxyConfig},
+    realisation::DrvOutput,
+    stderr::{Msg, StderrError},
+    store_path::{StoreDir, StoreReferences},
+    worker_op::{
+        AddMultipleToStore, AddPermRoot, AddSignatures, AddTextToStore, AddToStore,
+        AddToStoreLegacy, AddToStoreNar, BuildDerivation, BuildPaths, BuildResult, BuildStatus,
+        CollectGarbage, CollectGarbageResponse, DerivationOutputMap, DrvOutputs, ExportPath,
+        FindRootsResponse, QueryMissing, QueryMissingResponse, QueryPathInfoResponse,
+        QueryRealisationResponse, QuerySubstitutablePathInfos, QueryValidPaths, SetOptions,
+        SubstitutablePathInfo, ValidPathInfo, VerifyStore, WorkerOp,
+    },
+    DerivedPath, Error, NarHash, NixReadExt, NixString, NixWriteExt, Path, Realisation,
+    RealisationSet, Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+};
+
+mod binary_cache;
+mod local;
+mod memory;
+#[cfg(test)]
+mod testing;
+
+pub use binary_cache::{BinaryCacheStore, BinaryCacheStoreConfig, Compression};
+pub use local::{LocalStore, LocalStoreConfig};
+pub use memory::InMemoryStore;
+
+fn unsupported<T>(op: &str) -> Result<T> {
+    Err(anyhow!("{op} is not supported by this store").into())
+}
+
+/// Passes everything written to it on to `inner`, counting the bytes.
+struct CountingWriter<W> {
+    inner: W,
+    len: u64,
+}
+
+impl CountingWriter<Hasher> {
+    /// Hashes a nar with sha256 (like nix does), and counts its size.
+    fn nar() -> Self {
+        CountingWriter {
+            inner: Hasher::new(HashAlgorithm::Sha256),
+            len: 0,
+        }
+    }
+
+    fn finish(self) -> (Hash, u64) {
+        (self.inner.finish(), self.len)
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the sha256 of the serialized nar, and its size.
+fn hash_nar(nar: &Nar) -> Result<(Hash, u64)> {
+    let mut w = CountingWriter::nar();
+    w.write_nix(nar)?;
+    Ok(w.finish())
+}
+
+/// Checks a sha256 hash sent by a client, which may be in base16 or nix's base32, and
+/// may have a `sha256:` prefix.
+fn hash_matches(expected: &NarHash, hash: &Hash) -> bool {
+    Hash::try_from(expected).as_ref() == Ok(hash)
+}
+
+/// Checks that the nar we got for `path` has the hash and size that `info` says.
+fn check_nar(path: &StorePath, info: &ValidPathInfo, hash: &Hash, size: u64) -> Result<()> {
+    if info.nar_size != size {
+        return Err(anyhow!(
+            "size mismatch importing path '{}': expected {}, got {}",
+            BStr::new(path),
+            info.nar_size,
+            size
+        )
+        .into());
+    }
+    if !hash_matches(&info.hash, hash) {
+        return Err(anyhow!(
+            "hash mismatch importing path '{}': expected {}, got {}",
+            BStr::new(path),
+            BStr::new(&info.hash.data),
+            BStr::new(&NarHash::from(hash).data)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that `hash` is the hash in the content address `ca` of `path`, like nix does
+/// before it adds a content-addressed path.
+fn check_content_address(path: &StorePath, ca: &ContentAddress, hash: &Hash) -> Result<()> {
+    if ca.hash() != hash {
+        return Err(anyhow!(
+            "ca hash mismatch importing path '{}': expected {}, got {}",
+            BStr::new(path),
+            ca.hash(),
+            hash
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The error for a content address that `path` can't have, because it isn't a regular
+/// file or because we don't support the method.
+fn bad_content_address<T>(path: &StorePath, ca: &ContentAddress) -> Result<T> {
+    Err(anyhow!(
+        "cannot check content address '{ca}' of path '{}'",
+        BStr::new(path)
+    )
+    .into())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn build_result(status: BuildStatus, error_msg: String) -> BuildResult {
+    BuildResult {
+        status,
+        error_msg: error_msg.into(),
+        times_built: 0,
+        is_non_deterministic: false,
+        start_time: 0,
+        stop_time: 0,
+        cpu_user: None,
+        cpu_system: None,
+        built_outputs: DrvOutputs::default(),
+    }
+}
+
+/// Works out the path that `AddToStore` makes, and its info, like nix's
+/// `addToStoreFromDump`.
+///
+/// The store hashes the data that follows the op while it reads it: `ca_hash` is its hash
+/// with the op's algorithm (the data is the file itself for text and flat hashing, or a
+/// nar otherwise), and `nar_hash` and `nar_size` are for its contents as a nar.
+pub fn add_to_store_info(
+    store_dir: &StoreDir,
+    op: &AddToStore,
+    ca_hash: Hash,
+    nar_hash: Hash,
+    nar_size: u64,
+) -> Result<ValidPathInfoWithPath> {
+    let name = std::str::from_utf8(&op.name.0 .0)
+        .map_err(|_| anyhow!("invalid name '{}'", BStr::new(&op.name.0 .0)))?;
+    let ca = ContentAddress::new(op.cam_str.method, ca_hash)?;
+    let references = StoreReferences {
+        others: op
+            .refs
+            .paths
+            .iter()
+            .map(|path| store_dir.parse(path))
+            .collect::<Result<_, _>>()?,
+        self_reference: false,
+    };
+    let path = store_dir.make_content_addressed_path(name, &ca, &references)?;
+    let info = ValidPathInfo {
+        deriver: StorePath(NixString::default()),
+        hash: NarHash::from(&nar_hash),
+        references: op.refs.clone(),
+        registration_time: now(),
+        nar_size,
+        ultimate: false,
+        sigs: StringSet::default(),
+        content_address: OptionalContentAddress(Some(ca)),
+    };
+    Ok(ValidPathInfoWithPath {
+        path: store_dir.print(&path),
+        info,
+    })
+}
+
+/// A hidden file next to `path`, to write to before renaming it into place.
+///
+/// Its name is different for every call, so that concurrent uploads of the same path
+/// (from this process or another one) don't write to the same file.
+fn temp_file(path: &FsPath) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// The `AddToStore` that does the same as an `AddTextToStore`.
+fn text_to_add_to_store(op: &AddTextToStore) -> AddToStore {
+    AddToStore {
+        name: StorePath(op.suffix.clone()),
+        cam_str: ContentAddressMethodWithAlgo {
+            method: ContentAddressMethod::Text,
+            algorithm: HashAlgorithm::Sha256,
+        },
+        refs: op.refs.clone(),
+        repair: false,
+    }
+}
+
+/// A nix store, with one method per worker op.
+///
+/// Every method has a default implementation that fails, so implementations only need to
+/// provide the ops they support. The exception is `SetOptions`, which succeeds by default.
+///
+/// Some ops come with data that is too big to hold in memory (like a nar). Those methods
+/// get a reader for that data. They don't have to read all of it, but they must not read
+/// past its end.
+#[allow(unused_variables)]
+pub trait Store {
+    /// The directory that the paths of this store are in.
+    ///
+    /// This isn't an op, but some ops need it to talk to old clients.
+    fn store_dir(&self) -> StoreDir {
+        StoreDir::default()
+    }
+
+    fn is_valid_path(&mut self, path: StorePath) -> Result<bool> {
+        unsupported("IsValidPath")
+    }
+
+    fn has_substitutes(&mut self, path: StorePath) -> Result<bool> {
+        unsupported("HasSubstitutes")
+    }
+
+    fn query_path_hash(&mut self, path: StorePath) -> Result<NixString> {
+        unsupported("QueryPathHash")
+    }
+
+    fn query_references(&mut self, path: StorePath) -> Result<StorePathSet> {
+        unsupported("QueryReferences")
+    }
+
+    fn query_referrers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        unsupported("QueryReferrers")
+    }
+
+    fn add_to_store_legacy(&mut self, op: AddToStoreLegacy) -> Result<StorePath> {
+        unsupported("AddToStore")
+    }
+
+    fn add_to_store(
+        &mut self,
+        op: AddToStore,
+        source: &mut dyn Read,
+    ) -> Result<ValidPathInfoWithPath> {
+        unsupported("AddToStore")
+    }
+
+    fn add_text_to_store(&mut self, op: AddTextToStore) -> Result<StorePath> {
+        unsupported("AddTextToStore")
+    }
+
+    fn build_paths(&mut self, op: BuildPaths) -> Result<u64> {
+        unsupported("BuildPaths")
+    }
+
+    fn ensure_path(&mut self, path: StorePath) -> Result<u64> {
+        unsupported("EnsurePath")
+    }
+
+    fn add_temp_root(&mut self, path: StorePath) -> Result<u64> {
+        unsupported("AddTempRoot")
+    }
+
+    fn add_indirect_root(&mut self, path: Path) -> Result<u64> {
+        unsupported("AddIndirectRoot")
+    }
+
+    fn sync_with_gc(&mut self) -> Result<u64> {
+        unsupported("SyncWithGC")
+    }
+
+    fn find_roots(&mut self) -> Result<FindRootsResponse> {
+        unsupported("FindRoots")
+    }
+
+    /// Writes the export of `op.path` to `sink`.
+    fn export_path(&mut self, op: ExportPath, sink: &mut dyn Write) -> Result<u64> {
+        unsupported("ExportPath")
+    }
+
+    /// Returns the deriver of `path`, or an empty path if it has none.
+    fn query_deriver(&mut self, path: StorePath) -> Result<StorePath> {
+        unsupported("QueryDeriver")
+    }
+
+    fn set_options(&mut self, options: SetOptions) -> Result<()> {
+        Ok(())
+    }
+
+    fn collect_garbage(&mut self, op: CollectGarbage) -> Result<CollectGarbageResponse> {
+        unsupported("CollectGarbage")
+    }
+
+    fn query_substitutable_path_info(
+        &mut self,
+        path: StorePath,
+    ) -> Result<Option<SubstitutablePathInfo>> {
+        unsupported("QuerySubstitutablePathInfo")
+    }
+
+    fn query_derivation_outputs(&mut self, path: StorePath) -> Result<StorePathSet> {
+        unsupported("QueryDerivationOutputs")
+    }
+
+    fn query_all_valid_paths(&mut self) -> Result<StorePathSet> {
+        unsupported("QueryAllValidPaths")
+    }
+
+    fn query_failed_paths(&mut self) -> Result<StorePathSet> {
+        unsupported("QueryFailedPaths")
+    }
+
+    fn clear_failed_paths(&mut self, paths: StorePathSet) -> Result<u64> {
+        unsupported("ClearFailedPaths")
+    }
+
+    fn query_path_info(&mut self, path: StorePath) -> Result<QueryPathInfoResponse> {
+        unsupported("QueryPathInfo")
+    }
+
+    /// Imports paths in the format written by `export_path`, one after the other.
+    fn import_paths(&mut self, source: &mut dyn Read) -> Result<StorePathSet> {
+        unsupported("ImportPaths")
+    }
+
+    fn query_derivation_output_names(&mut self, path: StorePath) -> Result<StringSet> {
+        unsupported("QueryDerivationOutputNames")
+    }
+
+    /// Returns the path with the given hash part, or an empty path if there is none.
+    fn query_path_from_hash_part(&mut self, hash_part: NixString) -> Result<StorePath> {
+        unsupported("QueryPathFromHashPart")
+    }
+
+    fn query_substitutable_path_infos(
+        &mut self,
+        op: QuerySubstitutablePathInfos,
+    ) -> Result<Vec<(StorePath, SubstitutablePathInfo)>> {
+        unsupported("QuerySubstitutablePathInfos")
+    }
+
+    fn query_valid_paths(&mut self, op: QueryValidPaths) -> Result<StorePathSet> {
+        unsupported("QueryValidPaths")
+    }
+
+    fn query_substitutable_paths(&mut self, paths: StorePathSet) -> Result<StorePathSet> {
+        unsupported("QuerySubstitutablePaths")
+    }
+
+    fn query_valid_derivers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        unsupported("QueryValidDerivers")
+    }
+
+    fn optimise_store(&mut self) -> Result<u64> {
+        unsupported("OptimiseStore")
+    }
+
+    fn verify_store(&mut self, op: VerifyStore) -> Result<bool> {
+        unsupported("VerifyStore")
+    }
+
+    fn build_derivation(&mut self, op: BuildDerivation) -> Result<BuildResult> {
+        unsupported("BuildDerivation")
+    }
+
+    fn add_signatures(&mut self, op: AddSignatures) -> Result<u64> {
+        unsupported("AddSignatures")
+    }
+
+    /// Writes the nar of `path` to `sink`.
+    fn nar_from_path(&mut self, path: StorePath, sink: &mut dyn Write) -> Result<()> {
+        unsupported("NarFromPath")
+    }
+
+    fn add_to_store_nar(&mut self, op: AddToStoreNar, source: &mut dyn Read) -> Result<()> {
+        unsupported("AddToStoreNar")
+    }
+
+    fn query_missing(&mut self, op: QueryMissing) -> Result<QueryMissingResponse> {
+        unsupported("QueryMissing")
+    }
+
+    fn query_derivation_output_map(&mut self, path: StorePath) -> Result<DerivationOutputMap> {
+        unsupported("QueryDerivationOutputMap")
+    }
+
+    fn register_drv_output(&mut self, realisation: Realisation) -> Result<()> {
+        unsupported("RegisterDrvOutput")
+    }
+
+    fn query_realisation(&mut self, drv_output: DrvOutput) -> Result<RealisationSet> {
+        unsupported("QueryRealisation")
+    }
+
+    /// Adds paths in the format written by `nix copy`: the number of paths, and then
+    /// for each one its `StorePath`, its `ValidPathInfo` and its nar.
+    fn add_multiple_to_store(
+        &mut self,
+        op: AddMultipleToStore,
+        source: &mut dyn Read,
+    ) -> Result<()> {
+        unsupported("AddMultipleToStore")
+    }
+
+    fn add_build_log(&mut self, path: StorePath, log: &mut dyn Read) -> Result<u64> {
+        unsupported("AddBuildLog")
+    }
+
+    fn build_paths_with_results(
+        &mut self,
+        op: BuildPaths,
+    ) -> Result<Vec<(DerivedPath, BuildResult)>> {
+        unsupported("BuildPathsWithResults")
+    }
+
+    /// Returns the path of the new gc root.
+    fn add_perm_root(&mut self, op: AddPermRoot) -> Result<Path> {
+        unsupported("AddPermRoot")
+    }
+}
+
+/// Reads data from the client by asking for it with [`Msg::Read`].
+struct TunnelSource<'a, R, W> {
+    proxy: &'a mut NixDaemonProxy<R, W>,
+}
+
+impl<R: Read, W: Write> Read for TunnelSource<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let to_io = |e: Error| std::io::Error::other(e.to_string());
+        self.proxy
+            .send_error_to_client(&Msg::Read(buf.len() as u64))
+            .map_err(to_io)?;
+        self.proxy.flush_tx_to_client().map_err(to_io)?;
+        let data = self.proxy.read_tunnel_data().map_err(to_io)?;
+        if data.0.len() > buf.len() {
+            return Err(std::io::Error::other("client sent too much data"));
+        }
+        buf[..data.0.len()].copy_from_slice(&data.0);
+        Ok(data.0.len())
+    }
+}
+
+/// Sends data to the client with [`Msg::Write`].
+struct TunnelSink<'a, R, W> {
+    proxy: &'a mut NixDaemonProxy<R, W>,
+}
+
+impl<R: Read, W: Write> Write for TunnelSink<'_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.proxy
+            .send_error_to_client(&Msg::Write(NixString::from_bytes(buf)))
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.proxy
+            .flush_tx_to_client()
+            .map_err(std::io::Error::other)
+    }
+}
+
+/// Writes a response to the client as it is produced, sending [`Msg::Last`] first.
+struct ResponseSink<'a, R, W> {
+    proxy: &'a mut NixDaemonProxy<R, W>,
+    started: bool,
+}
+
+impl<R: Read, W: Write> Write for ResponseSink<'_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.started {
+            self.proxy
+                .send_error_to_client(&Msg::Last(()))
+                .map_err(std::io::Error::other)?;
+            self.started = true;
+        }
+        self.proxy.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.proxy.writer().flush()
+    }
+}
+
+/// Send the outcome of an op to the client.
+fn respond<R: Read, W: Write, T: serde::Serialize>(
+    proxy: &mut NixDaemonProxy<R, W>,
+    result: Result<T>,
+) -> Result<()> {
+    match result {
+        Ok(resp) => {
+            proxy.send_error_to_client(&Msg::Last(()))?;
+            proxy.write_build_response_to_client(&resp)?;
+        }
+        Err(e) => {
+            tracing::info!("op failed: {e}");
+            // Errors from the store are for the client's eyes, so leave off our prefix.
+            let message = match e {
+                Error::Other(e) => e.to_string(),
+                e => e.to_string(),
+            };
+            proxy.send_error_to_client(&Msg::Error(StderrError::new(message)))?;
+        }
+    }
+    proxy.flush_tx_to_client()
+}
+
+/// Run `f` on the framed source that follows an op, and skip whatever it didn't read.
+fn with_framed_source<R: Read, W: Write, T>(
+    proxy: &mut NixDaemonProxy<R, W>,
+    f: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<Result<T>> {
+    let mut source = FramedReader::new(proxy.reader());
+    let result = f(&mut source);
+    source.drain()?;
+    Ok(result)
+}
+
+/// Adds the paths of an `AddMultipleToStore` from an untrusted client one at a time, so
+/// that each one can be checked before it goes into `store`.
+///
+/// Our stores don't check signatures themselves, so this is where the proxy's rule for
+/// untrusted clients gets applied to these paths.
+fn add_multiple_untrusted<S: Store + ?Sized>(
+    store: &mut S,
+    op: AddMultipleToStore,
+    mut source: &mut dyn Read,
+    config: &ProxyConfig,
+) -> Result<()> {
+    let count: u64 = source.read_nix()?;
+    for _ in 0..count {
+        let path = ValidPathInfoWithPath {
+            path: source.read_nix()?,
+            info: source.read_nix()?,
+        };
+        path.check_untrusted(&config.store_dir, &config.trusted_public_keys)?;
+        let ValidPathInfoWithPath { path, info } = path;
+        let add = AddToStoreNar {
+            path,
+            deriver: info.deriver,
+            nar_hash: NixString(info.hash.data),
+            references: info.references,
+            registration_time: info.registration_time,
+            nar_size: info.nar_size,
+            ultimate: false,
+            sigs: info.sigs,
+            content_address: info.content_address,
+            repair: op.repair,
+            dont_check_sigs: false,
+        };
+        store.add_to_store_nar(add, source)?;
+    }
+    Ok(())
+}
+
+/// Serve a client on `read` and `write`, using `store` to answer its requests.
+///
+/// Returns when the client hangs up.
+pub fn serve<S: Store + ?Sized>(store: &mut S, read: impl Read, write: impl Write) -> Result<()> {
+    let config = ProxyConfig {
+        store_dir: store.store_dir(),
+        ..ProxyConfig::default()
+    };
+    serve_with_config(store, read, write, config)
+}
+
+/// Like [`serve`], but with a custom configuration for the handshake.
+///
+/// `config.store_dir` should be the store's [`Store::store_dir`].
+pub fn serve_with_config<S: Store + ?Sized>(
+    store: &mut S,
+    read: impl Read,
+    write: impl Write,
+    config: ProxyConfig,
+) -> Result<()> {
+    let mut proxy = NixDaemonProxy::new(read, write, config)?;
+    let version = proxy.protocol_version();
+
+    loop {
+        let op = match proxy.receive_next_op_from_client() {
+            Ok(op) => op,
+            Err(Error::Deser(crate::serialize::Error::Io(e)))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        tracing::debug!(op = op.name(), "serving op");
+
+        match op {
+            WorkerOp::IsValidPath(path, _) => {
+                let result = store.is_valid_path(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::HasSubstitutes(path, _) => {
+                let result = store.has_substitutes(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryPathHash(path, _) => {
+                let result = store.query_path_hash(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryReferences(path, _) => {
+                let result = store.query_references(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryReferrers(path, _) => {
+                let result = store.query_referrers(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddToStoreLegacy(op, _) => {
+                let result = store.add_to_store_legacy(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddToStore(op, _) => {
+                let result =
+                    with_framed_source(&mut proxy, |source| store.add_to_store(op.0, source))?;
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddTextToStore(op, _) => {
+                let result = store.add_text_to_store(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::BuildPaths(op, _) => {
+                let result = store.build_paths(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::EnsurePath(path, _) => {
+                let result = store.ensure_path(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddTempRoot(path, _) => {
+                let result = store.add_temp_root(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddIndirectRoot(path, _) => {
+                let result = store.add_indirect_root(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::SyncWithGC(_, _) => {
+                let result = store.sync_with_gc();
+                respond(&mut proxy, result)
+            }
+            WorkerOp::FindRoots(_, _) => {
+                let result = store.find_roots();
+                respond(&mut proxy, result)
+            }
+            WorkerOp::ExportPath(op, _) => {
+                let mut sink = TunnelSink { proxy: &mut proxy };
+                let result = store.export_path(op.0, &mut sink);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryDeriver(path, _) => {
+                let result = store.query_deriver(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::SetOptions(options, _) => {
+                let result = store.set_options(options.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::CollectGarbage(op, _) => {
+                let result = store.collect_garbage(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QuerySubstitutablePathInfo(path, _) => {
+                let result = store.query_substitutable_path_info(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryDerivationOutputs(path, _) => {
+                let result = store.query_derivation_outputs(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryAllValidPaths(_, _) => {
+                let result = store.query_all_valid_paths();
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryFailedPaths(_, _) => {
+                let result = store.query_failed_paths();
+                respond(&mut proxy, result)
+            }
+            WorkerOp::ClearFailedPaths(paths, _) => {
+                let result = store.clear_failed_paths(paths.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryPathInfo(path, _) => {
+                let result = store.query_path_info(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::ImportPaths(_, _) => {
+                let mut source = TunnelSource { proxy: &mut proxy };
+                let result = store.import_paths(&mut source);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryDerivationOutputNames(path, _) => {
+                let result = store.query_derivation_output_names(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryPathFromHashPart(hash_part, _) => {
+                let result = store.query_path_from_hash_part(hash_part.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QuerySubstitutablePathInfos(op, _) => {
+                let result = store.query_substitutable_path_infos(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryValidPaths(op, _) => {
+                let result = store.query_valid_paths(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QuerySubstitutablePaths(paths, _) => {
+                let result = store.query_substitutable_paths(paths.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryValidDerivers(path, _) => {
+                let result = store.query_valid_derivers(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::OptimiseStore(_, _) => {
+                let result = store.optimise_store();
+                respond(&mut proxy, result)
+            }
+            WorkerOp::VerifyStore(op, _) => {
+                let result = store.verify_store(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::BuildDerivation(op, _) => {
+                let result = store.build_derivation(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddSignatures(op, _) => {
+                let result = store.add_signatures(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::NarFromPath(path, _) => {
+                let mut sink = ResponseSink {
+                    proxy: &mut proxy,
+                    started: false,
+                };
+                let result = store.nar_from_path(path.0, &mut sink);
+                match (result, sink.started) {
+                    // It's too late to tell the client, because it's already reading the nar.
+                    (Err(e), true) => Err(e),
+                    (Ok(()), true) => proxy.flush_tx_to_client(),
+                    (result, false) => respond(&mut proxy, result),
+                }
+            }
+            WorkerOp::AddToStoreNar(op, _) if version.minor >= 23 => {
+                let result =
+                    with_framed_source(&mut proxy, |source| store.add_to_store_nar(op.0, source))?;
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddToStoreNar(op, _) => {
+                let mut source = TunnelSource { proxy: &mut proxy };
+                let result = store.add_to_store_nar(op.0, &mut source);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryMissing(op, _) => {
+                let result = store.query_missing(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryDerivationOutputMap(path, _) => {
+                let result = store.query_derivation_output_map(path.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::RegisterDrvOutput(op, _) => {
+                let result = store.register_drv_output(op.0.realisation);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::QueryRealisation(drv_output, _) => {
+                let result = store.query_realisation(drv_output.0).map(|set| {
+                    if version.minor >= 31 {
+                        return QueryRealisationResponse::Realisations(set);
+                    }
+                    let store_dir = store.store_dir();
+                    let paths = set.realisations.iter();
+                    QueryRealisationResponse::OutPaths(StorePathSet {
+                        paths: paths.map(|r| store_dir.print(&r.out_path)).collect(),
+                    })
+                });
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddMultipleToStore(op, _) if !proxy.is_trusted() => {
+                let config = proxy.config().clone();
+                let result = with_framed_source(&mut proxy, |source| {
+                    add_multiple_untrusted(store, op.0, source, &config)
+                })?;
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddMultipleToStore(op, _) => {
+                let result = with_framed_source(&mut proxy, |source| {
+                    store.add_multiple_to_store(op.0, source)
+                })?;
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddBuildLog(op, _) => {
+                let result =
+                    with_framed_source(&mut proxy, |log| store.add_build_log(op.0.path, log))?;
+                respond(&mut proxy, result)
+            }
+            WorkerOp::BuildPathsWithResults(op, _) => {
+                let result = store.build_paths_with_results(op.0);
+                respond(&mut proxy, result)
+            }
+            WorkerOp::AddPermRoot(op, _) => {
+                // Like nix-daemon: the root can be anywhere, and we create it with our
+                // own privileges.
+                let result = if !proxy.is_trusted() {
+                    Err(anyhow!("you are not privileged to create perm roots").into())
+                } else {
+                    store.add_perm_root(op.0)
+                };
+                respond(&mut proxy, result)
+            }
+        }?;
+    }
+}
@@ -0,0 +1,567 @@
+//! A store that reads and writes a binary cache directory, like nix's `file://` stores.
+//!
+//! The layout is the one that nix uses for all its binary caches (including the ones on
+//! S3 or behind http):
+//!
+//! - `nix-cache-info` describes the cache, and in particular which store directory it is
+//!   for;
+//! - `<hash part>.narinfo` holds the metadata of a store path, and says where its nar is;
+//! - `nar/<file hash>.nar.xz` (or `.nar.zst`, or just `.nar`) holds the (compressed) nar.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Component, Path as FsPath, PathBuf},
+};
+
+use anyhow::anyhow;
+use bstr::BStr;
+
+use super::{
+    bad_content_address, check_content_address, check_nar, temp_file, CountingWriter, Store,
+};
+use crate::{
+    content_address::ContentAddressMethod,
+    hash::{HashFormat, Hasher},
+    nar::{self, EntrySink, FileContents},
+    narinfo::NarInfo,
+    serialize::Tee,
+    store_path::StoreDir,
+    worker_op::{
+        AddMultipleToStore, AddToStoreNar, QueryPathInfoResponse, QueryValidPaths, ValidPathInfo,
+    },
+    NarHash, NixReadExt, NixString, Result, StorePath, StorePathSet, ValidPathInfoWithPath,
+};
+
+/// How nars are compressed in a binary cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[default]
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// The name of the compression in narinfo files and store urls.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Parses the name of a compression method.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Compression::None),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!("unsupported compression method '{name}'").into()),
+        }
+    }
+
+    /// The extension of compressed nars, after `.nar`.
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Xz => ".xz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Where a [`BinaryCacheStore`] keeps things.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryCacheStoreConfig {
+    /// The directory containing `nix-cache-info`.
+    pub cache_dir: PathBuf,
+    /// How to compress the nars that we write. Existing nars can use any compression.
+    pub compression: Compression,
+    /// The store directory of a new cache. An existing cache knows its store directory.
+    pub store_dir: PathBuf,
+}
+
+impl BinaryCacheStoreConfig {
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        BinaryCacheStoreConfig {
+            cache_dir: cache_dir.into(),
+            compression: Compression::default(),
+            store_dir: PathBuf::from("/nix/store"),
+        }
+    }
+
+    /// Parses a store url like `file:///tmp/cache?compression=zstd`.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("file://") else {
+            return Err(anyhow!("'{url}' is not a file:// store url").into());
+        };
+        let (dir, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut config = BinaryCacheStoreConfig::new(dir);
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("compression", value)) => config.compression = Compression::from_name(value)?,
+                Some(("store", value)) => config.store_dir = value.into(),
+                _ => return Err(anyhow!("unknown binary cache parameter '{param}'").into()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn utf8(s: &NixString) -> Result<String> {
+    s.to_string()
+        .map_err(|_| anyhow!("'{}' is not valid UTF-8", BStr::new(s)).into())
+}
+
+/// A store that keeps its paths in a binary cache directory.
+///
+/// This only supports the ops that make sense for a cache: adding paths, querying their
+/// metadata, and fetching their nars. It doesn't know about referrers, derivers or roots.
+pub struct BinaryCacheStore {
+    config: BinaryCacheStoreConfig,
+    store_dir: StoreDir,
+}
+
+impl BinaryCacheStore {
+    /// Opens a binary cache, creating it if it doesn't exist.
+    pub fn open(config: BinaryCacheStoreConfig) -> Result<Self> {
+        std::fs::create_dir_all(config.cache_dir.join("nar"))?;
+        let info_file = config.cache_dir.join("nix-cache-info");
+        let store_dir = match std::fs::read_to_string(&info_file) {
+            Ok(info) => info
+                .lines()
+                .find_map(|line| line.strip_prefix("StoreDir: "))
+                .map(|dir| dir.trim().to_owned())
+                .ok_or_else(|| anyhow!("{} has no StoreDir", info_file.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let store_dir = config.store_dir.to_string_lossy().into_owned();
+                let info = format!("StoreDir: {store_dir}\nWantMassQuery: 1\nPriority: 40\n");
+                std::fs::write(&info_file, info)?;
+                store_dir
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(BinaryCacheStore {
+            store_dir: StoreDir::new(store_dir)?,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &BinaryCacheStoreConfig {
+        &self.config
+    }
+
+    fn narinfo_file(&self, hash_part: &str) -> PathBuf {
+        self.config.cache_dir.join(format!("{hash_part}.narinfo"))
+    }
+
+    fn read_narinfo(&self, hash_part: &str) -> Result<Option<NarInfo>> {
+        match std::fs::read_to_string(self.narinfo_file(hash_part)) {
+            Ok(text) => Ok(Some(NarInfo::parse(&text)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The file that a narinfo's url points to.
+    ///
+    /// The url comes from a file in the cache, which anyone who can write to the cache
+    /// controls, so it isn't allowed to point outside the cache.
+    fn nar_file(&self, url: &str) -> Result<PathBuf> {
+        let relative = FsPath::new(url);
+        let normal = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if url.is_empty() || !normal {
+            return Err(anyhow!("invalid nar url '{url}'").into());
+        }
+        Ok(self.config.cache_dir.join(relative))
+    }
+
+    /// The narinfo of `path`, if the cache has it.
+    fn narinfo(&self, path: &StorePath) -> Result<Option<NarInfo>> {
+        let info = self.read_narinfo(&self.store_dir.parse(path)?.hash_part())?;
+        // The hash part could belong to a different name.
+        Ok(info.filter(|info| &info.store_path == path))
+    }
+
+    fn is_valid(&self, path: &StorePath) -> Result<bool> {
+        Ok(self.narinfo(path)?.is_some())
+    }
+
+    /// Compresses a nar from `source` into the cache, and writes its narinfo.
+    fn add(
+        &mut self,
+        path: &StorePath,
+        info: ValidPathInfo,
+        source: &mut dyn Read,
+        repair: bool,
+    ) -> Result<()> {
+        let parsed = self.store_dir.parse(path)?;
+        if !repair && self.is_valid(path)? {
+            nar::stream(source, std::io::sink())?;
+            return Ok(());
+        }
+
+        let compression = self.config.compression;
+        let tmp = temp_file(&self.config.cache_dir.join(format!("nar/{parsed}")));
+        let mut nar_hasher = CountingWriter::nar();
+
+        // A content address covers the whole nar if it's recursive, and otherwise the
+        // contents of the one file in it.
+        let ca = info.content_address.0;
+        let mut ca_hasher = ca.map(|ca| Hasher::new(ca.hash().algorithm()));
+        let (mut no_nar, mut no_file) = (std::io::sink(), std::io::sink());
+        let (ca_nar, ca_file): (&mut dyn Write, &mut dyn Write) =
+            match (&mut ca_hasher, ca.map(|ca| ca.method())) {
+                (Some(hasher), Some(ContentAddressMethod::Recursive)) => (hasher, &mut no_file),
+                (Some(hasher), _) => (&mut no_nar, hasher),
+                (None, _) => (&mut no_nar, &mut no_file),
+            };
+        let mut file = FileContents::new(ca_file);
+
+        let result = compress(
+            Tee::new(Tee::new(source, &mut nar_hasher), ca_nar),
+            &tmp,
+            compression,
+            &mut file,
+        );
+        let is_file = file.is_file();
+        let result = result.and_then(|()| {
+            let (nar_hash, nar_size) = nar_hasher.finish();
+            check_nar(path, &info, &nar_hash, nar_size)?;
+            let (Some(ca), Some(ca_hasher)) = (ca, ca_hasher) else {
+                return Ok(());
+            };
+            match ca.method() {
+                ContentAddressMethod::Recursive => {}
+                ContentAddressMethod::Text | ContentAddressMethod::Flat if is_file => {}
+                _ => return bad_content_address(path, &ca),
+            }
+            check_content_address(path, &ca, &ca_hasher.finish())
+        });
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        let mut file_hasher = CountingWriter::nar();
+        std::io::copy(&mut File::open(&tmp)?, &mut file_hasher)?;
+        let (file_hash, file_size) = file_hasher.finish();
+        let url = format!(
+            "nar/{}.nar{}",
+            file_hash.encode(HashFormat::Nix32, false),
+            compression.extension()
+        );
+        std::fs::rename(&tmp, self.config.cache_dir.join(&url))?;
+
+        let narinfo = NarInfo {
+            url,
+            compression: compression.name().to_owned(),
+            file_hash: Some(file_hash),
+            file_size: Some(file_size),
+            ..NarInfo::from_path_info(&ValidPathInfoWithPath {
+                path: path.clone(),
+                info,
+            })?
+        };
+
+        // Write the narinfo last, so that the path only becomes valid once it's complete.
+        let narinfo_file = self.narinfo_file(&parsed.hash_part());
+        let tmp = temp_file(&narinfo_file);
+        std::fs::write(&tmp, narinfo.render())?;
+        std::fs::rename(&tmp, narinfo_file)?;
+        Ok(())
+    }
+}
+
+/// Copies a nar from `source` to a new file at `dest`, compressing it, and sends its
+/// entries to `sink` on the way.
+fn compress<'s>(
+    source: impl Read,
+    dest: &FsPath,
+    compression: Compression,
+    sink: impl EntrySink<'s> + 's,
+) -> Result<()> {
+    let file = BufWriter::new(File::create(dest)?);
+    match compression {
+        Compression::None => {
+            let mut file = file;
+            nar::stream_into(source, &mut file, sink)?;
+            file.flush()?;
+        }
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, 6);
+            nar::stream_into(source, &mut encoder, sink)?;
+            encoder.finish()?.flush()?;
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, 0)?;
+            nar::stream_into(source, &mut encoder, sink)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+impl Store for BinaryCacheStore {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+
+    fn is_valid_path(&mut self, path: StorePath) -> Result<bool> {
+        self.is_valid(&path)
+    }
+
+    fn query_path_info(&mut self, path: StorePath) -> Result<QueryPathInfoResponse> {
+        let info = self.narinfo(&path)?;
+        Ok(QueryPathInfoResponse {
+            path: info
+                .map(|info| info.to_path_info())
+                .transpose()?
+                .map(|i| i.info),
+        })
+    }
+
+    fn query_valid_paths(&mut self, op: QueryValidPaths) -> Result<StorePathSet> {
+        let mut paths = Vec::new();
+        for path in op.paths.paths {
+            if self.is_valid(&path)? {
+                paths.push(path);
+            }
+        }
+        Ok(StorePathSet { paths })
+    }
+
+    fn query_path_from_hash_part(&mut self, hash_part: NixString) -> Result<StorePath> {
+        let hash_part = utf8(&hash_part)?;
+        if hash_part.len() != 32 || hash_part.contains('/') {
+            return Err(anyhow!("invalid hash part '{hash_part}'").into());
+        }
+        let info = self.read_narinfo(&hash_part)?;
+        Ok(info.map_or_else(|| StorePath(NixString::default()), |info| info.store_path))
+    }
+
+    fn nar_from_path(&mut self, path: StorePath, sink: &mut dyn Write) -> Result<()> {
+        let info = self
+            .narinfo(&path)?
+            .ok_or_else(|| anyhow!("path '{}' is not valid", BStr::new(&path)))?;
+        let file = BufReader::new(File::open(self.nar_file(&info.url)?)?);
+        match info.compression.as_str() {
+            "none" | "" => nar::stream(file, sink)?,
+            "xz" => nar::stream(xz2::read::XzDecoder::new(file), sink)?,
+            "zstd" => nar::stream(zstd::Decoder::with_buffer(file)?, sink)?,
+            other => return Err(anyhow!("unsupported compression method '{other}'").into()),
+        }
+        Ok(())
+    }
+
+    fn add_to_store_nar(&mut self, op: AddToStoreNar, source: &mut dyn Read) -> Result<()> {
+        let info = ValidPathInfo {
+            deriver: op.deriver,
+            hash: NarHash {
+                data: op.nar_hash.0,
+            },
+            references: op.references,
+            registration_time: op.registration_time,
+            nar_size: op.nar_size,
+            ultimate: false,
+            sigs: op.sigs,
+            content_address: op.content_address,
+        };
+        self.add(&op.path, info, source, op.repair)
+    }
+
+    fn add_multiple_to_store(
+        &mut self,
+        op: AddMultipleToStore,
+        mut source: &mut dyn Read,
+    ) -> Result<()> {
+        let count: u64 = source.read_nix()?;
+        for _ in 0..count {
+            let path: StorePath = source.read_nix()?;
+            let info: ValidPathInfo = source.read_nix()?;
+            self.add(&path, info, source, op.repair)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        content_address::{ContentAddress, OptionalContentAddress},
+        hash::{Hash, HashAlgorithm},
+        nar::{Nar, NarDirectoryEntry, NarFile},
+        StringSet,
+    };
+
+    use super::{
+        super::{
+            hash_nar,
+            testing::{path, temp_dir},
+        },
+        *,
+    };
+    use tempfile::TempDir;
+
+    /// A fresh, empty store, which lives as long as the returned directory.
+    fn store(compression: Compression) -> (TempDir, BinaryCacheStore) {
+        let dir = temp_dir();
+        let store = BinaryCacheStore::open(BinaryCacheStoreConfig {
+            compression,
+            ..BinaryCacheStoreConfig::new(dir.path().to_owned())
+        })
+        .unwrap();
+        (dir, store)
+    }
+
+    fn nar() -> Nar {
+        Nar::Directory(vec![NarDirectoryEntry {
+            name: NixString::from_bytes(b"hello"),
+            node: Nar::Contents(NarFile {
+                contents: NixString::from_bytes(b"hello, world"),
+                executable: true,
+            }),
+        }])
+    }
+
+    fn add(store: &mut BinaryCacheStore, path: &StorePath, references: &[&StorePath]) {
+        let nar = nar();
+        let (nar_hash, nar_size) = hash_nar(&nar).unwrap();
+        let info = ValidPathInfo {
+            deriver: StorePath(NixString::default()),
+            hash: NarHash::from(&nar_hash),
+            references: StorePathSet {
+                paths: references.iter().map(|&p| p.clone()).collect(),
+            },
+            registration_time: 0,
+            nar_size,
+            ultimate: false,
+            sigs: StringSet {
+                paths: vec![NixString::from_bytes(b"cache:c2ln")],
+            },
+            content_address: OptionalContentAddress::default(),
+        };
+        let bytes = crate::to_vec(&nar).unwrap();
+        store.add(path, info, &mut bytes.as_slice(), false).unwrap();
+    }
+
+    #[test]
+    fn roundtrip() {
+        for compression in [Compression::None, Compression::Xz, Compression::Zstd] {
+            let (_dir, mut store) = store(compression);
+            let (a, b) = (path("a"), path("b"));
+            add(&mut store, &b, &[]);
+            add(&mut store, &a, &[&a, &b]);
+
+            assert!(store.is_valid_path(a.clone()).unwrap());
+            assert!(!store.is_valid_path(path("c")).unwrap());
+            let info = store.query_path_info(a.clone()).unwrap().path.unwrap();
+            assert_eq!(info.hash, NarHash::from(&hash_nar(&nar()).unwrap().0));
+            assert_eq!(info.references.paths, vec![a.clone(), b.clone()]);
+            assert_eq!(info.sigs.paths, vec![NixString::from_bytes(b"cache:c2ln")]);
+            assert_eq!(
+                store
+                    .query_path_from_hash_part(NixString::from_bytes(&[b'a'; 32]))
+                    .unwrap(),
+                a
+            );
+
+            let mut dumped = Vec::new();
+            store.nar_from_path(a, &mut dumped).unwrap();
+            assert_eq!(dumped.as_slice().read_nix::<Nar>().unwrap(), nar());
+        }
+    }
+
+    #[test]
+    fn content_address() {
+        let (_dir, mut store) = store(Compression::Xz);
+        let nar = nar();
+        let (nar_hash, nar_size) = hash_nar(&nar).unwrap();
+        let bytes = crate::to_vec(&nar).unwrap();
+        let mut info = ValidPathInfo {
+            deriver: StorePath(NixString::default()),
+            hash: NarHash::from(&nar_hash),
+            references: StorePathSet { paths: vec![] },
+            registration_time: 0,
+            nar_size,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: OptionalContentAddress(Some(ContentAddress::Flat(nar_hash))),
+        };
+
+        // A directory can't have a flat content address...
+        let a = path("a");
+        assert!(store
+            .add(&a, info.clone(), &mut bytes.as_slice(), false)
+            .is_err());
+        assert!(!store.is_valid_path(a.clone()).unwrap());
+
+        // ...and this one's nar hashes to something else.
+        let hash = Hash::compute(HashAlgorithm::Sha256, b"hello, world");
+        info.content_address = OptionalContentAddress(Some(ContentAddress::Recursive(hash)));
+        assert!(store
+            .add(&a, info.clone(), &mut bytes.as_slice(), false)
+            .is_err());
+        assert!(!store.is_valid_path(a.clone()).unwrap());
+
+        info.content_address = OptionalContentAddress(Some(ContentAddress::Recursive(nar_hash)));
+        store
+            .add(&a, info.clone(), &mut bytes.as_slice(), false)
+            .unwrap();
+        assert!(store.is_valid_path(a).unwrap());
+
+        // A flat content address is the hash of the file, whatever algorithm it uses.
+        let nar = Nar::Contents(NarFile {
+            contents: NixString::from_bytes(b"hello, world"),
+            executable: false,
+        });
+        let (nar_hash, nar_size) = hash_nar(&nar).unwrap();
+        let bytes = crate::to_vec(&nar).unwrap();
+        let mut info = ValidPathInfo {
+            hash: NarHash::from(&nar_hash),
+            nar_size,
+            content_address: OptionalContentAddress(Some(ContentAddress::Flat(Hash::compute(
+                HashAlgorithm::Sha256,
+                b"hello",
+            )))),
+            ..info
+        };
+        let b = path("b");
+        assert!(store
+            .add(&b, info.clone(), &mut bytes.as_slice(), false)
+            .is_err());
+        assert!(!store.is_valid_path(b.clone()).unwrap());
+
+        let hash = Hash::compute(HashAlgorithm::Sha1, b"hello, world");
+        info.content_address = OptionalContentAddress(Some(ContentAddress::Flat(hash)));
+        store.add(&b, info, &mut bytes.as_slice(), false).unwrap();
+        assert!(store.is_valid_path(b).unwrap());
+    }
+
+    #[test]
+    fn nar_urls() {
+        let (_dir, store) = store(Compression::None);
+        let cache_dir = &store.config().cache_dir;
+        assert_eq!(
+            store.nar_file("nar/abc.nar").unwrap(),
+            cache_dir.join("nar/abc.nar")
+        );
+        for url in [
+            "",
+            "/etc/shadow",
+            "../secret",
+            "nar/../../secret",
+            "./nar/abc.nar",
+        ] {
+            assert!(store.nar_file(url).is_err(), "{url}");
+        }
+
+        let tmp = temp_file(&cache_dir.join("nar/foo"));
+        assert_eq!(tmp.parent(), Some(cache_dir.join("nar").as_path()));
+        assert_ne!(tmp, temp_file(&cache_dir.join("nar/foo")));
+    }
+}
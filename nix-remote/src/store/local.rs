@@ -0,0 +1,1198 @@
+//! A store that keeps its paths on disk, like nix's local store.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, PermissionsExt},
+    },
+    path::{Path as FsPath, PathBuf},
+};
+
+use anyhow::anyhow;
+use bstr::BStr;
+use rusqlite::{params, OptionalExtension};
+
+use super::{
+    add_to_store_info, bad_content_address, build_result, check_content_address, check_nar,
+    hash_matches, now, temp_file, text_to_add_to_store, unsupported, CountingWriter, Store,
+};
+use crate::{
+    content_address::{ContentAddress, ContentAddressMethod},
+    derived_path::OutputsSpec,
+    hash::{Hash, HashAlgorithm, HashFormat, Hasher},
+    nar,
+    nix_db::{path_set, path_str, NixDb},
+    realisation::DrvOutput,
+    serialize::Tee,
+    store_path::StoreDir,
+    worker_op::{
+        AddMultipleToStore, AddPermRoot, AddSignatures, AddTextToStore, AddToStore, AddToStoreNar,
+        BuildPaths, BuildResult, BuildStatus, CollectGarbage, CollectGarbageResponse,
+        DerivationOutputMap, FindRootsResponse, GcAction, QueryMissing, QueryMissingResponse,
+        QueryPathInfoResponse, QuerySubstitutablePathInfos, QueryValidPaths, SubstitutablePathInfo,
+        ValidPathInfo, VerifyStore,
+    },
+    DerivedPath, Error, NarHash, NixReadExt, NixString, Path, PathSet, Realisation, RealisationSet,
+    Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+};
+
+/// Where a [`LocalStore`] keeps things.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalStoreConfig {
+    /// Everything is stored under this directory, like the `root` setting of nix's
+    /// local store.
+    pub root: PathBuf,
+    /// The store directory that clients see. The store paths themselves live at the
+    /// same location under `root`.
+    pub store_dir: PathBuf,
+    /// Where the database and the gc roots live, under `root`.
+    pub state_dir: PathBuf,
+}
+
+impl Default for LocalStoreConfig {
+    fn default() -> Self {
+        LocalStoreConfig {
+            root: PathBuf::from("/"),
+            store_dir: PathBuf::from("/nix/store"),
+            state_dir: PathBuf::from("/nix/var/nix"),
+        }
+    }
+}
+
+impl LocalStoreConfig {
+    /// The default layout, but under `root`.
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        LocalStoreConfig {
+            root: root.into(),
+            ..LocalStoreConfig::default()
+        }
+    }
+
+    /// Parses a store url like `local?root=/tmp/x`, with the `root`, `store` and `state`
+    /// parameters that nix's local store understands.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let (scheme, query) = url.split_once('?').unwrap_or((url, ""));
+        if scheme != "local" {
+            return Err(anyhow!("'{url}' is not a local store url").into());
+        }
+        let mut config = LocalStoreConfig::default();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("root", value)) => config.root = value.into(),
+                Some(("store", value)) => config.store_dir = value.into(),
+                Some(("state", value)) => config.state_dir = value.into(),
+                _ => return Err(anyhow!("unknown local store parameter '{param}'").into()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A store that unpacks its paths on disk and keeps their metadata in a sqlite database.
+///
+/// This doesn't need any privileges: with [`LocalStoreConfig::with_root`], it can live
+/// in any directory. It can't build or substitute anything, so paths only get in by being
+/// added by a client.
+///
+/// Temporary roots belong to a `LocalStore`, so they go away when it is dropped. Permanent
+/// roots are symlinks in the `gcroots` directory under the state directory, like in nix.
+pub struct LocalStore {
+    config: LocalStoreConfig,
+    store_dir: StoreDir,
+    db: NixDb,
+    temp_roots: BTreeSet<StorePath>,
+}
+
+fn not_valid(path: &StorePath) -> Error {
+    anyhow!("path '{}' is not valid", BStr::new(path)).into()
+}
+
+/// Where `path` really is, if `root` is the root directory.
+fn real_path(root: &FsPath, path: &FsPath) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn make_writable(path: &FsPath) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+        for entry in std::fs::read_dir(path)? {
+            make_writable(&entry?.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the unpacked contents of `path` at `real` the way that `ca` says to.
+fn hash_content(path: &StorePath, real: &FsPath, ca: &ContentAddress) -> Result<Hash> {
+    let mut hasher = Hasher::new(ca.hash().algorithm());
+    match ca.method() {
+        ContentAddressMethod::Text | ContentAddressMethod::Flat => {
+            if !std::fs::symlink_metadata(real)?.is_file() {
+                return bad_content_address(path, ca);
+            }
+            std::io::copy(&mut std::fs::File::open(real)?, &mut hasher)?;
+        }
+        ContentAddressMethod::Recursive => nar::dump(real, &mut hasher)?,
+        ContentAddressMethod::Git => return bad_content_address(path, ca),
+    }
+    Ok(hasher.finish())
+}
+
+/// Unpacks the data that follows an `AddToStore` at `real`, hashing it into `ca_hasher`
+/// and hashing its contents as a nar into `nar_hasher`.
+fn unpack_dump(
+    method: ContentAddressMethod,
+    source: &mut dyn Read,
+    real: &FsPath,
+    ca_hasher: &mut Hasher,
+    nar_hasher: &mut CountingWriter<Hasher>,
+) -> Result<()> {
+    match method {
+        ContentAddressMethod::Text | ContentAddressMethod::Flat => {
+            let mut file = std::fs::File::create(real)?;
+            std::io::copy(&mut Tee::new(source, ca_hasher), &mut file)?;
+            nar::dump(real, nar_hasher)?;
+        }
+        ContentAddressMethod::Recursive => {
+            nar::restore(Tee::new(Tee::new(source, ca_hasher), nar_hasher), real)?;
+        }
+        ContentAddressMethod::Git => return unsupported("AddToStore with git hashing"),
+    }
+    Ok(())
+}
+
+/// Removes `path` (which may be read-only), if it exists.
+fn remove_tree(path: &FsPath) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        Ok(metadata) if metadata.is_dir() => {
+            make_writable(path)?;
+            std::fs::remove_dir_all(path)
+        }
+        Ok(_) => std::fs::remove_file(path),
+    }
+}
+
+/// Makes everything under `path` read-only, like nix does.
+fn make_read_only(path: &FsPath) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let mode = if metadata.is_symlink() {
+        return Ok(());
+    } else if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            make_read_only(&entry?.path())?;
+        }
+        0o555
+    } else if metadata.permissions().mode() & 0o100 != 0 {
+        0o555
+    } else {
+        0o444
+    };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+impl LocalStore {
+    /// Opens the store, creating it if it doesn't exist.
+    pub fn open(config: LocalStoreConfig) -> Result<Self> {
+        let real_path = |path: &FsPath| real_path(&config.root, path);
+        let db_dir = real_path(&config.state_dir.join("db"));
+        std::fs::create_dir_all(real_path(&config.store_dir))?;
+        std::fs::create_dir_all(real_path(&config.state_dir.join("gcroots/auto")))?;
+        std::fs::create_dir_all(&db_dir)?;
+
+        let store_dir = config.store_dir.to_str().ok_or_else(|| {
+            anyhow!(
+                "store directory {} is not valid UTF-8",
+                config.store_dir.display()
+            )
+        })?;
+        Ok(LocalStore {
+            store_dir: StoreDir::new(store_dir)?,
+            db: NixDb::create(db_dir.join("db.sqlite"))?,
+            temp_roots: BTreeSet::new(),
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &LocalStoreConfig {
+        &self.config
+    }
+
+    /// Where a path outside the store (like the state directory) really is.
+    fn real_path(&self, path: &FsPath) -> PathBuf {
+        real_path(&self.config.root, path)
+    }
+
+    /// Where a store path really is.
+    fn real_store_path(&self, path: &StorePath) -> Result<PathBuf> {
+        let parsed = self.store_dir.parse(path)?;
+        Ok(self
+            .real_path(&self.config.store_dir)
+            .join(parsed.to_string()))
+    }
+
+    /// The store path that `path` is in, if any (`path` can point inside a store path).
+    fn to_store_path(&self, path: &FsPath) -> Option<StorePath> {
+        let (parsed, _rest) = self
+            .store_dir
+            .parse_prefix(path.as_os_str().as_bytes())
+            .ok()?;
+        Some(self.store_dir.print(&parsed))
+    }
+
+    fn derivation_outputs(&self, drv: &StorePath) -> Result<BTreeMap<NixString, StorePath>> {
+        Ok(self
+            .db
+            .derivation_output_map(drv)?
+            .paths
+            .into_iter()
+            .collect())
+    }
+
+    /// Records the outputs of a derivation, for `QueryDerivationOutputMap` and friends.
+    ///
+    /// The derivation must be valid.
+    pub fn add_derivation_outputs(
+        &mut self,
+        drv: &StorePath,
+        outputs: impl IntoIterator<Item = (NixString, StorePath)>,
+    ) -> Result<()> {
+        let id = self.db.path_id(drv)?.ok_or_else(|| not_valid(drv))?;
+        let tx = self.db.conn_mut().transaction()?;
+        for (name, path) in outputs {
+            let name = name
+                .to_string()
+                .map_err(|_| anyhow!("output names must be valid UTF-8"))?;
+            tx.execute(
+                "insert or replace into DerivationOutputs (drv, id, path) values (?, ?, ?)",
+                params![id, name, path_str(&path)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Adds (or replaces) a path's metadata in the database.
+    fn register(&mut self, path: &StorePath, info: &ValidPathInfo) -> Result<()> {
+        let path = path_str(path)?;
+        let deriver = path_str(&info.deriver)?;
+        let sigs = info
+            .sigs
+            .paths
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("signatures must be valid UTF-8"))?
+            .join(" ");
+        let ca = info.content_address.0.map(|ca| ca.to_string());
+
+        let tx = self.db.conn_mut().transaction()?;
+        tx.execute(
+            "insert into ValidPaths
+                 (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca)
+             values (?, ?, ?, ?, ?, ?, ?, ?)
+             on conflict (path) do update set
+                 hash = excluded.hash,
+                 registrationTime = excluded.registrationTime,
+                 deriver = excluded.deriver,
+                 narSize = excluded.narSize,
+                 ultimate = excluded.ultimate,
+                 sigs = excluded.sigs,
+                 ca = excluded.ca",
+            params![
+                path,
+                format!("sha256:{}", BStr::new(&info.hash.data)),
+                info.registration_time as i64,
+                (!deriver.is_empty()).then_some(deriver),
+                info.nar_size as i64,
+                info.ultimate as i64,
+                (!sigs.is_empty()).then_some(sigs),
+                ca,
+            ],
+        )?;
+        let id: i64 = tx.query_row("select id from ValidPaths where path = ?", [path], |row| {
+            row.get(0)
+        })?;
+        tx.execute("delete from Refs where referrer = ?", [id])?;
+        for r in &info.references.paths {
+            tx.execute(
+                "insert or ignore into Refs (referrer, reference)
+                 select ?, id from ValidPaths where path = ?",
+                params![id, path_str(r)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Unpacks a nar from `source` and registers it as `path`.
+    fn add(
+        &mut self,
+        path: &StorePath,
+        mut info: ValidPathInfo,
+        source: &mut dyn Read,
+        repair: bool,
+    ) -> Result<()> {
+        let real = self.real_store_path(path)?;
+        if self.db.is_valid(path)? && !repair {
+            nar::stream(source, std::io::sink())?;
+            return Ok(());
+        }
+        self.check_references(path, &info.references)?;
+
+        // Unpack next to the final location, so that nobody sees a half-unpacked path.
+        let tmp = temp_file(&real);
+
+        let mut hasher = CountingWriter::nar();
+        let result = nar::restore(Tee::new(source, &mut hasher), &tmp)
+            .map_err(Error::from)
+            .and_then(|()| {
+                let (hash, size) = hasher.finish();
+                check_nar(path, &info, &hash, size)?;
+                if let Some(ca) = &info.content_address.0 {
+                    check_content_address(path, ca, &hash_content(path, &tmp, ca)?)?;
+                }
+                info.hash = NarHash::from(&hash);
+                Ok(())
+            });
+        if let Err(e) = result {
+            remove_tree(&tmp)?;
+            return Err(e);
+        }
+
+        self.install(path, info, &tmp)
+    }
+
+    /// Checks that everything that `path` refers to (apart from itself) is valid.
+    fn check_references(&self, path: &StorePath, references: &StorePathSet) -> Result<()> {
+        for r in &references.paths {
+            if r != path && !self.db.is_valid(r)? {
+                return Err(anyhow!(
+                    "cannot add path '{}' because it references path '{}' which is not valid",
+                    BStr::new(path),
+                    BStr::new(r)
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a path that was unpacked at `tmp` into place as `path`, and registers it.
+    fn install(&mut self, path: &StorePath, mut info: ValidPathInfo, tmp: &FsPath) -> Result<()> {
+        let real = self.real_store_path(path)?;
+        make_read_only(tmp)?;
+        remove_tree(&real)?;
+        std::fs::rename(tmp, &real)?;
+        if info.registration_time == 0 {
+            info.registration_time = now();
+        }
+        self.register(path, &info)
+    }
+
+    /// Deletes a path from the database and from the disk, returning its nar size.
+    fn delete(&mut self, path: &StorePath) -> Result<u64> {
+        let nar_size: Option<i64> = self
+            .db
+            .conn()
+            .query_row(
+                "select narSize from ValidPaths where path = ?",
+                [path_str(path)?],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        self.db
+            .conn()
+            .execute("delete from ValidPaths where path = ?", [path_str(path)?])?;
+        remove_tree(&self.real_store_path(path)?)?;
+        self.temp_roots.remove(path);
+        Ok(nar_size.unwrap_or(0) as u64)
+    }
+
+    fn gc_roots_dir(&self) -> PathBuf {
+        self.real_path(&self.config.state_dir.join("gcroots"))
+    }
+
+    fn roots(&self) -> Result<Vec<(Path, StorePath)>> {
+        let mut roots = Vec::new();
+        self.find_roots_in(&self.gc_roots_dir(), &mut roots)?;
+        roots.retain(|(_root, path)| self.db.is_valid(path).unwrap_or(false));
+        Ok(roots)
+    }
+
+    fn find_roots_in(&self, dir: &FsPath, roots: &mut Vec<(Path, StorePath)>) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.find_roots_in(&entry.path(), roots)?;
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                if let Some(path) = self.to_store_path(&target) {
+                    let root = entry.path();
+                    let root = root.strip_prefix(&self.config.root).unwrap_or(&root);
+                    let root = FsPath::new("/").join(root);
+                    roots.push((Path(root.as_os_str().as_bytes().to_vec().into()), path));
+                } else if let Ok(indirect) = std::fs::read_link(&target) {
+                    // An indirect root: a symlink to a symlink into the store.
+                    if let Some(path) = self.to_store_path(&indirect) {
+                        roots.push((Path(target.as_os_str().as_bytes().to_vec().into()), path));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All paths that are reachable from a gc root.
+    fn live_paths(&self) -> Result<BTreeSet<StorePath>> {
+        let mut live = BTreeSet::new();
+        let mut todo: Vec<_> = self.roots()?.into_iter().map(|(_, p)| p).collect();
+        todo.extend(self.temp_roots.iter().cloned());
+        while let Some(path) = todo.pop() {
+            if live.contains(&path) {
+                continue;
+            }
+            if let Some(info) = self.db.path_info(&path)? {
+                todo.extend(info.references.paths);
+                live.insert(path);
+            }
+        }
+        Ok(live)
+    }
+
+    fn is_realised(&self, path: &DerivedPath) -> Result<bool> {
+        match path {
+            DerivedPath::Opaque(path) => self.db.is_valid(path),
+            DerivedPath::Built {
+                drv_path,
+                outputs: wanted,
+            } => {
+                let outputs = self.derivation_outputs(drv_path)?;
+                if outputs.is_empty() {
+                    return Ok(false);
+                }
+                let names: Vec<_> = match wanted {
+                    OutputsSpec::All => outputs.keys().collect(),
+                    OutputsSpec::Names(names) => names.iter().collect(),
+                };
+                for name in names {
+                    match outputs.get(name) {
+                        Some(out) if self.db.is_valid(out)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl Store for LocalStore {
+    fn store_dir(&self) -> StoreDir {
+        self.store_dir.clone()
+    }
+
+    fn is_valid_path(&mut self, path: StorePath) -> Result<bool> {
+        self.db.is_valid(&path)
+    }
+
+    fn has_substitutes(&mut self, _path: StorePath) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn query_path_hash(&mut self, path: StorePath) -> Result<NixString> {
+        let info = self.db.path_info(&path)?.ok_or_else(|| not_valid(&path))?;
+        Ok(NixString(info.hash.data))
+    }
+
+    fn query_references(&mut self, path: StorePath) -> Result<StorePathSet> {
+        let info = self.db.path_info(&path)?.ok_or_else(|| not_valid(&path))?;
+        Ok(info.references)
+    }
+
+    fn query_referrers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        self.db.referrers(&path)
+    }
+
+    fn build_paths(&mut self, op: BuildPaths) -> Result<u64> {
+        for path in op.paths {
+            if !self.is_realised(&path)? {
+                return Err(anyhow!("cannot build '{path}': this store can't build").into());
+            }
+        }
+        Ok(1)
+    }
+
+    fn ensure_path(&mut self, path: StorePath) -> Result<u64> {
+        if !self.db.is_valid(&path)? {
+            return Err(not_valid(&path));
+        }
+        Ok(1)
+    }
+
+    fn add_temp_root(&mut self, path: StorePath) -> Result<u64> {
+        self.temp_roots.insert(path);
+        Ok(1)
+    }
+
+    fn add_indirect_root(&mut self, path: Path) -> Result<u64> {
+        // The same name that nix gives it, so that there is only one link per root.
+        let hash = Hash::compute(HashAlgorithm::Sha1, &path.0 .0);
+        let name = hash.encode(HashFormat::Nix32, false);
+        let link = self.gc_roots_dir().join("auto").join(name);
+        remove_tree(&link)?;
+        symlink(OsStr::from_bytes(path.as_ref()), link)?;
+        Ok(1)
+    }
+
+    fn sync_with_gc(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+
+    fn find_roots(&mut self) -> Result<FindRootsResponse> {
+        Ok(FindRootsResponse {
+            roots: self.roots()?,
+        })
+    }
+
+    fn query_deriver(&mut self, path: StorePath) -> Result<StorePath> {
+        let info = self.db.path_info(&path)?.ok_or_else(|| not_valid(&path))?;
+        Ok(info.deriver)
+    }
+
+    fn collect_garbage(&mut self, op: CollectGarbage) -> Result<CollectGarbageResponse> {
+        let live = if op.ignore_liveness {
+            BTreeSet::new()
+        } else {
+            self.live_paths()?
+        };
+        let dead = || -> Result<Vec<StorePath>> {
+            let all = self.db.all_valid_paths()?.paths;
+            Ok(all.into_iter().filter(|p| !live.contains(p)).collect())
+        };
+
+        let to_delete = match op.action {
+            GcAction::ReturnLive => {
+                let paths = live.into_iter().map(|p| Path(p.0)).collect();
+                return Ok(CollectGarbageResponse::new(PathSet { paths }, 0));
+            }
+            GcAction::ReturnDead => {
+                let paths = dead()?.into_iter().map(|p| Path(p.0)).collect();
+                return Ok(CollectGarbageResponse::new(PathSet { paths }, 0));
+            }
+            GcAction::DeleteDead => dead()?,
+            GcAction::DeleteSpecific => {
+                let specific: BTreeSet<_> = op.paths_to_delete.paths.into_iter().collect();
+                for path in &specific {
+                    if live.contains(path) {
+                        return Err(anyhow!(
+                            "cannot delete path '{}' since it is still alive",
+                            BStr::new(path)
+                        )
+                        .into());
+                    }
+                    let referrers = self.db.referrers(path)?.paths;
+                    if let Some(r) = referrers.iter().find(|r| !specific.contains(*r)) {
+                        return Err(anyhow!(
+                            "cannot delete path '{}' because it is referenced by '{}'",
+                            BStr::new(path),
+                            BStr::new(r)
+                        )
+                        .into());
+                    }
+                }
+                let mut valid = Vec::new();
+                for path in specific {
+                    if self.db.is_valid(&path)? {
+                        valid.push(path);
+                    }
+                }
+                valid
+            }
+        };
+
+        // Delete referrers before the paths that they refer to.
+        let mut deleted = Vec::new();
+        let mut bytes_freed = 0;
+        let mut remaining: BTreeSet<_> = to_delete.into_iter().collect();
+        while bytes_freed < op.max_freed {
+            let mut next = None;
+            for path in &remaining {
+                if self.db.referrers(path)?.paths.iter().all(|r| r == path) {
+                    next = Some(path.clone());
+                    break;
+                }
+            }
+            let Some(path) = next else {
+                break;
+            };
+            remaining.remove(&path);
+            bytes_freed += self.delete(&path)?;
+            deleted.push(Path(path.0));
+        }
+        Ok(CollectGarbageResponse::new(
+            PathSet { paths: deleted },
+            bytes_freed,
+        ))
+    }
+
+    fn query_substitutable_path_info(
+        &mut self,
+        _path: StorePath,
+    ) -> Result<Option<SubstitutablePathInfo>> {
+        Ok(None)
+    }
+
+    fn query_derivation_outputs(&mut self, path: StorePath) -> Result<StorePathSet> {
+        Ok(path_set(self.derivation_outputs(&path)?.into_values()))
+    }
+
+    fn query_all_valid_paths(&mut self) -> Result<StorePathSet> {
+        self.db.all_valid_paths()
+    }
+
+    fn query_path_info(&mut self, path: StorePath) -> Result<QueryPathInfoResponse> {
+        Ok(QueryPathInfoResponse {
+            path: self.db.path_info(&path)?,
+        })
+    }
+
+    fn query_derivation_output_names(&mut self, path: StorePath) -> Result<StringSet> {
+        Ok(StringSet {
+            paths: self.derivation_outputs(&path)?.into_keys().collect(),
+        })
+    }
+
+    fn query_path_from_hash_part(&mut self, hash_part: NixString) -> Result<StorePath> {
+        let store_dir = self.store_dir.as_str().as_bytes();
+        let found = self.db.path_from_hash_part(store_dir, &hash_part.0)?;
+        Ok(found.unwrap_or_else(|| StorePath(NixString::default())))
+    }
+
+    fn query_substitutable_path_infos(
+        &mut self,
+        _op: QuerySubstitutablePathInfos,
+    ) -> Result<Vec<(StorePath, SubstitutablePathInfo)>> {
+        Ok(Vec::new())
+    }
+
+    fn query_valid_paths(&mut self, op: QueryValidPaths) -> Result<StorePathSet> {
+        let mut valid = Vec::new();
+        for path in op.paths.paths {
+            if self.db.is_valid(&path)? {
+                valid.push(path);
+            }
+        }
+        Ok(path_set(valid))
+    }
+
+    fn query_substitutable_paths(&mut self, _paths: StorePathSet) -> Result<StorePathSet> {
+        Ok(path_set([]))
+    }
+
+    fn query_valid_derivers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        self.db.valid_derivers(&path)
+    }
+
+    fn verify_store(&mut self, op: VerifyStore) -> Result<bool> {
+        let mut errors = false;
+        for path in self.db.all_valid_paths()?.paths {
+            let real = self.real_store_path(&path)?;
+            if std::fs::symlink_metadata(&real).is_err() {
+                tracing::warn!("path '{}' disappeared", BStr::new(&path));
+                errors = true;
+            } else if op.check_contents {
+                let info = self.db.path_info(&path)?.ok_or_else(|| not_valid(&path))?;
+                let mut hasher = CountingWriter::nar();
+                nar::dump(&real, &mut hasher)?;
+                let (hash, size) = hasher.finish();
+                if size != info.nar_size || !hash_matches(&info.hash, &hash) {
+                    tracing::warn!("path '{}' was modified!", BStr::new(&path));
+                    errors = true;
+                }
+            }
+        }
+        Ok(errors)
+    }
+
+    fn add_signatures(&mut self, op: AddSignatures) -> Result<u64> {
+        let mut info = self
+            .db
+            .path_info(&op.path)?
+            .ok_or_else(|| not_valid(&op.path))?;
+        for sig in op.signatures.paths {
+            if !info.sigs.paths.contains(&sig) {
+                info.sigs.paths.push(sig);
+            }
+        }
+        self.register(&op.path, &info)?;
+        Ok(1)
+    }
+
+    fn nar_from_path(&mut self, path: StorePath, sink: &mut dyn Write) -> Result<()> {
+        if !self.db.is_valid(&path)? {
+            return Err(not_valid(&path));
+        }
+        nar::dump(&self.real_store_path(&path)?, sink)?;
+        Ok(())
+    }
+
+    fn add_to_store(
+        &mut self,
+        op: AddToStore,
+        source: &mut dyn Read,
+    ) -> Result<ValidPathInfoWithPath> {
+        // We don't know the path until we have hashed everything, so unpack it next to the
+        // store paths first.
+        let tmp = temp_file(&self.real_path(&self.config.store_dir).join("add-to-store"));
+        let mut ca_hasher = Hasher::new(op.cam_str.algorithm);
+        let mut nar_hasher = CountingWriter::nar();
+        let result = unpack_dump(
+            op.cam_str.method,
+            source,
+            &tmp,
+            &mut ca_hasher,
+            &mut nar_hasher,
+        )
+        .and_then(|()| {
+            let (nar_hash, nar_size) = nar_hasher.finish();
+            let info =
+                add_to_store_info(&self.store_dir, &op, ca_hasher.finish(), nar_hash, nar_size)?;
+            if !self.db.is_valid(&info.path)? || op.repair {
+                self.check_references(&info.path, &info.info.references)?;
+                self.install(&info.path, info.info, &tmp)?;
+            }
+            Ok(info.path)
+        });
+        // This is only still there if we didn't need it, or something went wrong.
+        remove_tree(&tmp)?;
+        let path = result?;
+        let stored = self
+            .db
+            .path_info(&path)?
+            .ok_or_else(|| anyhow!("path '{}' is not valid", BStr::new(&path)))?;
+        Ok(ValidPathInfoWithPath { path, info: stored })
+    }
+
+    fn add_text_to_store(&mut self, op: AddTextToStore) -> Result<StorePath> {
+        let text = op.text.clone();
+        let info = self.add_to_store(text_to_add_to_store(&op), &mut text.0.as_slice())?;
+        Ok(info.path)
+    }
+
+    fn add_to_store_nar(&mut self, op: AddToStoreNar, source: &mut dyn Read) -> Result<()> {
+        let info = ValidPathInfo {
+            deriver: op.deriver,
+            hash: NarHash {
+                data: op.nar_hash.0,
+            },
+            references: op.references,
+            registration_time: op.registration_time,
+            nar_size: op.nar_size,
+            // Paths added by clients are never ultimately trusted.
+            ultimate: false,
+            sigs: op.sigs,
+            content_address: op.content_address,
+        };
+        self.add(&op.path, info, source, op.repair)
+    }
+
+    fn query_missing(&mut self, op: QueryMissing) -> Result<QueryMissingResponse> {
+        let mut will_build = BTreeSet::new();
+        let mut unknown = BTreeSet::new();
+        for path in op.paths {
+            if self.is_realised(&path)? {
+                continue;
+            }
+            match path {
+                DerivedPath::Built { drv_path, .. } if self.db.is_valid(&drv_path)? => {
+                    will_build.insert(drv_path);
+                }
+                path => {
+                    unknown.insert(path.store_path().clone());
+                }
+            }
+        }
+        Ok(QueryMissingResponse {
+            will_build: path_set(will_build),
+            will_substitute: path_set([]),
+            unknown: path_set(unknown),
+            download_size: 0,
+            nar_size: 0,
+        })
+    }
+
+    fn query_derivation_output_map(&mut self, path: StorePath) -> Result<DerivationOutputMap> {
+        if !self.db.is_valid(&path)? {
+            return Err(not_valid(&path));
+        }
+        self.db.derivation_output_map(&path)
+    }
+
+    fn register_drv_output(&mut self, realisation: Realisation) -> Result<()> {
+        let out_path = self.store_dir.print(&realisation.out_path);
+        let out_id = self
+            .db
+            .path_id(&out_path)?
+            .ok_or_else(|| not_valid(&out_path))?;
+        let drv_hash = realisation.id.drv_hash.encode(HashFormat::Base16, true);
+        let output = &realisation.id.output_name;
+        let signatures = Vec::from_iter(realisation.signatures).join(" ");
+
+        let tx = self.db.conn_mut().transaction()?;
+        tx.execute(
+            "delete from Realisations where drvPath = ? and outputName = ?",
+            params![drv_hash, output],
+        )?;
+        tx.execute(
+            "insert into Realisations (drvPath, outputName, outputPath, signatures)
+             values (?, ?, ?, ?)",
+            params![drv_hash, output, out_id, signatures],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn query_realisation(&mut self, drv_output: DrvOutput) -> Result<RealisationSet> {
+        Ok(RealisationSet {
+            realisations: self.db.realisation(&drv_output)?.into_iter().collect(),
+        })
+    }
+
+    fn add_multiple_to_store(
+        &mut self,
+        op: AddMultipleToStore,
+        mut source: &mut dyn Read,
+    ) -> Result<()> {
+        let count: u64 = source.read_nix()?;
+        for _ in 0..count {
+            let path: StorePath = source.read_nix()?;
+            let mut info: ValidPathInfo = source.read_nix()?;
+            info.ultimate = false;
+            // `nix copy` sends references before referrers, so we can add them one by one.
+            self.add(&path, info, source, op.repair)?;
+        }
+        Ok(())
+    }
+
+    fn build_paths_with_results(
+        &mut self,
+        op: BuildPaths,
+    ) -> Result<Vec<(DerivedPath, BuildResult)>> {
+        let mut results = Vec::new();
+        for path in op.paths {
+            let result = if self.is_realised(&path)? {
+                build_result(BuildStatus::AlreadyValid, String::new())
+            } else {
+                let msg = format!("cannot build '{path}': this store can't build");
+                build_result(BuildStatus::MiscFailure, msg)
+            };
+            results.push((path, result));
+        }
+        Ok(results)
+    }
+
+    fn add_perm_root(&mut self, op: AddPermRoot) -> Result<Path> {
+        if !self.db.is_valid(&op.store_path)? {
+            return Err(not_valid(&op.store_path));
+        }
+        let gc_root = FsPath::new(OsStr::from_bytes(op.gc_root.as_ref()));
+        if self.to_store_path(gc_root).is_some() {
+            return Err(anyhow!(
+                "creating a garbage collector root ({}) in the Nix store is forbidden",
+                gc_root.display()
+            )
+            .into());
+        }
+        match std::fs::symlink_metadata(gc_root) {
+            Ok(metadata) if !metadata.is_symlink() => {
+                return Err(anyhow!(
+                    "cannot create symlink '{}'; already exists",
+                    gc_root.display()
+                )
+                .into());
+            }
+            Ok(_) => std::fs::remove_file(gc_root)?,
+            Err(_) => {}
+        }
+        symlink(OsStr::from_bytes(op.store_path.as_ref()), gc_root)?;
+        self.add_indirect_root(op.gc_root.clone())?;
+        Ok(op.gc_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        content_address::{ContentAddressMethodWithAlgo, OptionalContentAddress},
+        nar::{Nar, NarDirectoryEntry, NarFile},
+        nix_db::store_path,
+        worker_op::GcAction,
+    };
+
+    use super::{
+        super::{
+            hash_nar,
+            testing::{path, temp_dir},
+        },
+        *,
+    };
+    use tempfile::TempDir;
+
+    /// A fresh, empty store, which lives as long as the returned directory.
+    fn store() -> (TempDir, LocalStore) {
+        let root = temp_dir();
+        let store = LocalStore::open(LocalStoreConfig::with_root(root.path().to_owned())).unwrap();
+        (root, store)
+    }
+
+    fn file(contents: &str, executable: bool) -> Nar {
+        Nar::Contents(NarFile {
+            contents: NixString::from_bytes(contents.as_bytes()),
+            executable,
+        })
+    }
+
+    fn entry(name: &str, node: Nar) -> NarDirectoryEntry {
+        NarDirectoryEntry {
+            name: NixString::from_bytes(name.as_bytes()),
+            node,
+        }
+    }
+
+    fn add(store: &mut LocalStore, path: &StorePath, nar: &Nar, references: &[&StorePath]) {
+        let (nar_hash, nar_size) = hash_nar(nar).unwrap();
+        let info = ValidPathInfo {
+            deriver: store_path(String::new()),
+            hash: NarHash::from(&nar_hash),
+            references: path_set(references.iter().map(|&p| p.clone())),
+            registration_time: 0,
+            nar_size,
+            ultimate: false,
+            sigs: StringSet {
+                paths: vec!["cache:c2ln".to_owned().into()],
+            },
+            content_address: OptionalContentAddress::default(),
+        };
+        let bytes = crate::to_vec(nar).unwrap();
+        store.add(path, info, &mut bytes.as_slice(), false).unwrap();
+    }
+
+    #[test]
+    fn add_and_dump() {
+        let (_root, mut store) = store();
+        let (a, b) = (path("a"), path("b"));
+        let nar = Nar::Directory(vec![
+            entry(
+                "bin",
+                Nar::Directory(vec![entry("hello", file("#!", true))]),
+            ),
+            entry("link", Nar::Target(NixString::from_bytes(b"bin/hello"))),
+            entry("readme", file("hi", false)),
+        ]);
+        add(&mut store, &b, &file("b", false), &[]);
+        add(&mut store, &a, &nar, &[&a, &b]);
+
+        let info = store.query_path_info(a.clone()).unwrap().path.unwrap();
+        assert_eq!(info.hash, NarHash::from(&hash_nar(&nar).unwrap().0));
+        assert_eq!(info.references, path_set([a.clone(), b.clone()]));
+        assert_eq!(info.sigs.paths, vec![NixString::from_bytes(b"cache:c2ln")]);
+        assert_ne!(info.registration_time, 0);
+        assert_eq!(
+            store.query_referrers(b.clone()).unwrap(),
+            path_set([a.clone()])
+        );
+        assert_eq!(
+            store
+                .query_path_from_hash_part(NixString::from_bytes(&[b'a'; 32]))
+                .unwrap(),
+            a
+        );
+
+        let mut dumped = Vec::new();
+        store.nar_from_path(a.clone(), &mut dumped).unwrap();
+        assert_eq!(dumped.as_slice().read_nix::<Nar>().unwrap(), nar);
+        assert!(!store
+            .verify_store(VerifyStore {
+                check_contents: true,
+                repair: false,
+            })
+            .unwrap());
+
+        // The database survives reopening the store.
+        let config = store.config().clone();
+        drop(store);
+        let mut store = LocalStore::open(config).unwrap();
+        assert!(store.is_valid_path(a).unwrap());
+    }
+
+    #[test]
+    fn add_text() {
+        let (_root, mut store) = store();
+        let b = path("b");
+        add(&mut store, &b, &file("b", false), &[]);
+        let text = store
+            .add_text_to_store(AddTextToStore {
+                suffix: NixString::from_bytes(b"hello.txt"),
+                text: NixString::from_bytes(b"hello"),
+                refs: path_set([b.clone()]),
+            })
+            .unwrap();
+        let real = store.real_store_path(&text).unwrap();
+        assert_eq!(std::fs::read(real).unwrap(), b"hello");
+
+        let info = store.query_path_info(text.clone()).unwrap().path.unwrap();
+        assert_eq!(info.references, path_set([b.clone()]));
+        let ca = info.content_address.0.unwrap();
+        let expected = store
+            .store_dir
+            .make_text_path(
+                "hello.txt",
+                ca.hash(),
+                &[store.store_dir.parse(&b).unwrap()].into(),
+            )
+            .unwrap();
+        assert_eq!(text, store.store_dir.print(&expected));
+
+        // Adding it again is fine, and gives the same path.
+        let again = store
+            .add_text_to_store(AddTextToStore {
+                suffix: NixString::from_bytes(b"hello.txt"),
+                text: NixString::from_bytes(b"hello"),
+                refs: path_set([b]),
+            })
+            .unwrap();
+        assert_eq!(again, text);
+    }
+
+    #[test]
+    fn add_to_store() {
+        let (_root, mut store) = store();
+        let mut memory = crate::store::InMemoryStore::new();
+        let nar = Nar::Directory(vec![entry("hello", file("hello", true))]);
+        let nar_bytes = crate::to_vec(&nar).unwrap();
+        for (method, dump, stored) in [
+            (
+                ContentAddressMethod::Flat,
+                &b"hello"[..],
+                file("hello", false),
+            ),
+            (ContentAddressMethod::Recursive, &nar_bytes[..], nar.clone()),
+        ] {
+            let op = AddToStore {
+                name: StorePath(NixString::from_bytes(b"hello")),
+                cam_str: ContentAddressMethodWithAlgo {
+                    method,
+                    algorithm: HashAlgorithm::Sha1,
+                },
+                refs: path_set([]),
+                repair: false,
+            };
+            let added = store.add_to_store(op.clone(), &mut &dump[..]).unwrap();
+            let expected = memory.add_to_store(op, &mut &dump[..]).unwrap();
+            assert_eq!(added.path, expected.path);
+            assert_eq!(added.info.hash, expected.info.hash);
+            assert_eq!(added.info.nar_size, expected.info.nar_size);
+            assert_eq!(added.info.content_address, expected.info.content_address);
+
+            let mut dumped = Vec::new();
+            store.nar_from_path(added.path, &mut dumped).unwrap();
+            assert_eq!(dumped.as_slice().read_nix::<Nar>().unwrap(), stored);
+        }
+    }
+
+    #[test]
+    fn content_address() {
+        let (_root, mut store) = store();
+        let nar = Nar::Directory(vec![entry("hello", file("hello", false))]);
+        let (nar_hash, nar_size) = hash_nar(&nar).unwrap();
+        let bytes = crate::to_vec(&nar).unwrap();
+        let mut info = ValidPathInfo {
+            deriver: store_path(String::new()),
+            hash: NarHash::from(&nar_hash),
+            references: path_set([]),
+            registration_time: 0,
+            nar_size,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: OptionalContentAddress(Some(ContentAddress::Flat(nar_hash))),
+        };
+
+        // A directory can't have a flat content address...
+        let a = path("a");
+        let err = store.add(&a, info.clone(), &mut bytes.as_slice(), false);
+        assert!(err.is_err());
+        assert!(!store.is_valid_path(a.clone()).unwrap());
+
+        // ...and this one's nar hashes to something else.
+        let hash = Hash::compute(HashAlgorithm::Sha256, b"hello");
+        info.content_address = OptionalContentAddress(Some(ContentAddress::Recursive(hash)));
+        let err = store.add(&a, info.clone(), &mut bytes.as_slice(), false);
+        assert!(err.is_err());
+        assert!(!store.is_valid_path(a.clone()).unwrap());
+
+        info.content_address = OptionalContentAddress(Some(ContentAddress::Recursive(nar_hash)));
+        store.add(&a, info, &mut bytes.as_slice(), false).unwrap();
+        assert!(store.is_valid_path(a).unwrap());
+    }
+
+    #[test]
+    fn config_from_url() {
+        let config = LocalStoreConfig::from_url("local?root=/tmp/x").unwrap();
+        assert_eq!(config, LocalStoreConfig::with_root("/tmp/x"));
+        let config = LocalStoreConfig::from_url("local?store=/s&state=/v").unwrap();
+        assert_eq!(config.store_dir, FsPath::new("/s"));
+        assert_eq!(config.state_dir, FsPath::new("/v"));
+        assert!(LocalStoreConfig::from_url("daemon").is_err());
+        assert!(LocalStoreConfig::from_url("local?real=/x").is_err());
+    }
+
+    #[test]
+    fn indirect_root_name() {
+        let (_root, mut store) = store();
+        let root = Path(NixString::from_bytes(b"/home/user/result"));
+        store.add_indirect_root(root).unwrap();
+        let link = store
+            .gc_roots_dir()
+            .join("auto/cimffwcqlq2zxckkc5hss37xddx1rl29");
+        assert_eq!(
+            std::fs::read_link(link).unwrap(),
+            FsPath::new("/home/user/result")
+        );
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let (_root, mut store) = store();
+        let (a, b, c) = (path("a"), path("b"), path("c"));
+        add(&mut store, &b, &file("b", false), &[]);
+        add(&mut store, &a, &file("a", false), &[&b]);
+        add(&mut store, &c, &file("c", false), &[]);
+
+        let root = store.real_path(FsPath::new("/result"));
+        let root = Path(root.as_os_str().as_bytes().to_vec().into());
+        store
+            .add_perm_root(AddPermRoot {
+                store_path: a.clone(),
+                gc_root: root.clone(),
+            })
+            .unwrap();
+        assert_eq!(store.find_roots().unwrap().roots, vec![(root, a.clone())]);
+
+        let op = CollectGarbage::new(GcAction::DeleteDead, path_set([]), false, u64::MAX);
+        let resp = store.collect_garbage(op).unwrap();
+        assert_eq!(resp.paths.paths, vec![Path(c.0.clone())]);
+        assert!(!store.is_valid_path(c.clone()).unwrap());
+        assert!(!store.real_store_path(&c).unwrap().exists());
+
+        let op = CollectGarbage::new(GcAction::DeleteSpecific, path_set([b]), false, u64::MAX);
+        assert!(store.collect_garbage(op).is_err());
+    }
+}
@@ -0,0 +1,991 @@
+//! A store that lives entirely in memory.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use anyhow::anyhow;
+use bstr::BStr;
+
+use super::{
+    add_to_store_info, bad_content_address, build_result, check_content_address, check_nar,
+    hash_nar, now, text_to_add_to_store, unsupported, Store,
+};
+use crate::{
+    content_address::{ContentAddressMethod, OptionalContentAddress},
+    derived_path::OutputsSpec,
+    hash::Hasher,
+    nar::{Nar, NarFile},
+    realisation::DrvOutput,
+    serialize::Tee,
+    store_path::StoreDir,
+    worker_op::{
+        AddMultipleToStore, AddPermRoot, AddSignatures, AddTextToStore, AddToStore, AddToStoreNar,
+        BuildPaths, BuildResult, BuildStatus, CollectGarbage, CollectGarbageResponse,
+        DerivationOutputMap, ExportPath, FindRootsResponse, GcAction, QueryMissing,
+        QueryMissingResponse, QueryPathInfoResponse, QuerySubstitutablePathInfos, QueryValidPaths,
+        SubstitutablePathInfo, ValidPathInfo, VerifyStore,
+    },
+    DerivedPath, NarHash, NixReadExt, NixString, NixWriteExt, Path, PathSet, Realisation,
+    RealisationSet, Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+};
+
+/// Written after the nar in the output of `ExportPath`.
+const EXPORT_MAGIC: u64 = 0x4558494e;
+
+/// A store that keeps everything in memory, and forgets it all when dropped.
+///
+/// It can't build or substitute anything, so paths only get in by being added by a client
+/// (or with [`InMemoryStore::add_path`]). Apart from that, it tries to answer every op
+/// the way the nix daemon would.
+///
+/// Temporary roots live as long as the store does (the nix daemon drops them when the
+/// client disconnects), and indirect roots aren't supported because there is no
+/// filesystem to follow them on.
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    paths: BTreeMap<StorePath, Entry>,
+    referrers: BTreeMap<StorePath, BTreeSet<StorePath>>,
+    /// For each derivation, its output paths by output name.
+    derivation_outputs: BTreeMap<StorePath, BTreeMap<NixString, StorePath>>,
+    /// Realisations, by their `DrvOutput`.
+    realisations: BTreeMap<DrvOutput, Realisation>,
+    temp_roots: BTreeSet<StorePath>,
+    /// Permanent roots, by the path of the root.
+    perm_roots: BTreeMap<NixString, StorePath>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ValidPathInfo,
+    nar: Nar,
+}
+
+fn not_valid(path: &StorePath) -> crate::Error {
+    anyhow!("path '{}' is not valid", BStr::new(path)).into()
+}
+
+fn path_set(paths: impl IntoIterator<Item = StorePath>) -> StorePathSet {
+    StorePathSet {
+        paths: paths.into_iter().collect(),
+    }
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a path to the store, replacing it if it is already there.
+    ///
+    /// The nar hash and size in `info` are checked against `nar`, and all references
+    /// (apart from self-references) must already be valid.
+    pub fn add_path(&mut self, path: StorePath, info: ValidPathInfo, nar: Nar) -> Result<()> {
+        self.check_path(&path, &info, &nar)?;
+        self.check_references(&path, &info.references, &BTreeSet::new())?;
+        self.insert(path, info, nar);
+        Ok(())
+    }
+
+    /// Records the outputs of a derivation, for `QueryDerivationOutputMap` and friends.
+    pub fn add_derivation_outputs(
+        &mut self,
+        drv: StorePath,
+        outputs: impl IntoIterator<Item = (NixString, StorePath)>,
+    ) {
+        self.derivation_outputs
+            .entry(drv)
+            .or_default()
+            .extend(outputs);
+    }
+
+    fn entry(&self, path: &StorePath) -> Result<&Entry> {
+        self.paths.get(path).ok_or_else(|| not_valid(path))
+    }
+
+    fn check_path(&self, path: &StorePath, info: &ValidPathInfo, nar: &Nar) -> Result<()> {
+        let (hash, size) = hash_nar(nar)?;
+        check_nar(path, info, &hash, size)?;
+        if let Some(ca) = &info.content_address.0 {
+            let mut hasher = Hasher::new(ca.hash().algorithm());
+            match (ca.method(), nar) {
+                (ContentAddressMethod::Text | ContentAddressMethod::Flat, Nar::Contents(file)) => {
+                    hasher.update(&file.contents.0)
+                }
+                (ContentAddressMethod::Recursive, nar) => hasher.write_nix(nar)?,
+                _ => return bad_content_address(path, ca),
+            }
+            check_content_address(path, ca, &hasher.finish())?;
+        }
+        Ok(())
+    }
+
+    /// Checks that every reference is valid, or is `path` itself, or is in `incoming`.
+    fn check_references(
+        &self,
+        path: &StorePath,
+        references: &StorePathSet,
+        incoming: &BTreeSet<StorePath>,
+    ) -> Result<()> {
+        for r in &references.paths {
+            if r != path && !self.paths.contains_key(r) && !incoming.contains(r) {
+                return Err(anyhow!(
+                    "cannot add path '{}' because it references path '{}' which is not valid",
+                    BStr::new(path),
+                    BStr::new(r)
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, path: StorePath, mut info: ValidPathInfo, nar: Nar) {
+        self.remove(&path);
+        if info.registration_time == 0 {
+            info.registration_time = now();
+        }
+        // Store the hash the way that `QueryPathHash` returns it.
+        if let Ok((hash, _)) = hash_nar(&nar) {
+            info.hash = NarHash::from(&hash);
+        }
+        for r in &info.references.paths {
+            self.referrers
+                .entry(r.clone())
+                .or_default()
+                .insert(path.clone());
+        }
+        self.paths.insert(path, Entry { info, nar });
+    }
+
+    fn remove(&mut self, path: &StorePath) -> Option<Entry> {
+        let entry = self.paths.remove(path)?;
+        for r in &entry.info.references.paths {
+            if let Some(referrers) = self.referrers.get_mut(r) {
+                referrers.remove(path);
+            }
+        }
+        self.derivation_outputs.remove(path);
+        self.temp_roots.remove(path);
+        Some(entry)
+    }
+
+    fn valid_referrers(&self, path: &StorePath) -> impl Iterator<Item = &StorePath> {
+        self.referrers
+            .get(path)
+            .into_iter()
+            .flatten()
+            .filter(|p| self.paths.contains_key(*p))
+    }
+
+    /// All paths that are reachable from a gc root.
+    fn live_paths(&self) -> BTreeSet<StorePath> {
+        let mut live = BTreeSet::new();
+        let mut todo: Vec<_> = self
+            .temp_roots
+            .iter()
+            .chain(self.perm_roots.values())
+            .cloned()
+            .collect();
+        while let Some(path) = todo.pop() {
+            let Some(entry) = self.paths.get(&path) else {
+                continue;
+            };
+            if live.insert(path) {
+                todo.extend(entry.info.references.paths.iter().cloned());
+            }
+        }
+        live
+    }
+
+    /// Whether a derived path is valid (for an opaque path) or has all of the requested
+    /// outputs valid (for a built path).
+    fn is_realised(&self, path: &DerivedPath) -> bool {
+        match path {
+            DerivedPath::Opaque(path) => self.paths.contains_key(path),
+            DerivedPath::Built {
+                drv_path,
+                outputs: wanted,
+            } => {
+                let Some(outputs) = self.derivation_outputs.get(drv_path) else {
+                    return false;
+                };
+                let valid = |name: &NixString| {
+                    outputs
+                        .get(name)
+                        .is_some_and(|out| self.paths.contains_key(out))
+                };
+                match wanted {
+                    OutputsSpec::All => outputs.keys().all(valid),
+                    OutputsSpec::Names(names) => names.iter().all(valid),
+                }
+            }
+        }
+    }
+}
+
+impl Store for InMemoryStore {
+    fn is_valid_path(&mut self, path: StorePath) -> Result<bool> {
+        Ok(self.paths.contains_key(&path))
+    }
+
+    fn has_substitutes(&mut self, _path: StorePath) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn query_path_hash(&mut self, path: StorePath) -> Result<NixString> {
+        Ok(NixString(self.entry(&path)?.info.hash.data.clone()))
+    }
+
+    fn query_references(&mut self, path: StorePath) -> Result<StorePathSet> {
+        Ok(self.entry(&path)?.info.references.clone())
+    }
+
+    fn query_referrers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        Ok(path_set(self.valid_referrers(&path).cloned()))
+    }
+
+    fn add_to_store(
+        &mut self,
+        op: AddToStore,
+        source: &mut dyn Read,
+    ) -> Result<ValidPathInfoWithPath> {
+        let mut hasher = Hasher::new(op.cam_str.algorithm);
+        let mut source = Tee::new(source, &mut hasher);
+        let nar = match op.cam_str.method {
+            ContentAddressMethod::Text | ContentAddressMethod::Flat => {
+                let mut contents = Vec::new();
+                source.read_to_end(&mut contents)?;
+                Nar::Contents(NarFile {
+                    contents: NixString(contents.into()),
+                    executable: false,
+                })
+            }
+            ContentAddressMethod::Recursive => source.read_nix()?,
+            ContentAddressMethod::Git => return unsupported("AddToStore with git hashing"),
+        };
+        let (nar_hash, nar_size) = hash_nar(&nar)?;
+        let info = add_to_store_info(
+            &StoreDir::default(),
+            &op,
+            hasher.finish(),
+            nar_hash,
+            nar_size,
+        )?;
+        if !self.paths.contains_key(&info.path) || op.repair {
+            self.add_path(info.path.clone(), info.info, nar)?;
+        }
+        Ok(ValidPathInfoWithPath {
+            info: self.entry(&info.path)?.info.clone(),
+            path: info.path,
+        })
+    }
+
+    fn add_text_to_store(&mut self, op: AddTextToStore) -> Result<StorePath> {
+        let text = op.text.clone();
+        let info = self.add_to_store(text_to_add_to_store(&op), &mut text.0.as_slice())?;
+        Ok(info.path)
+    }
+
+    fn build_paths(&mut self, op: BuildPaths) -> Result<u64> {
+        for path in op.paths {
+            if !self.is_realised(&path) {
+                return Err(anyhow!("cannot build '{path}': this store can't build").into());
+            }
+        }
+        Ok(1)
+    }
+
+    fn ensure_path(&mut self, path: StorePath) -> Result<u64> {
+        self.entry(&path)?;
+        Ok(1)
+    }
+
+    fn add_temp_root(&mut self, path: StorePath) -> Result<u64> {
+        self.temp_roots.insert(path);
+        Ok(1)
+    }
+
+    fn sync_with_gc(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+
+    fn find_roots(&mut self) -> Result<FindRootsResponse> {
+        let roots = self
+            .perm_roots
+            .iter()
+            .map(|(root, path)| (Path(root.clone()), path.clone()))
+            .collect();
+        Ok(FindRootsResponse { roots })
+    }
+
+    fn export_path(&mut self, op: ExportPath, mut sink: &mut dyn Write) -> Result<u64> {
+        let entry = self.entry(&op.path)?;
+        sink.write_nix(&entry.nar)?;
+        sink.write_nix(&EXPORT_MAGIC)?;
+        sink.write_nix(&op.path)?;
+        sink.write_nix(&entry.info.references)?;
+        sink.write_nix(&entry.info.deriver)?;
+        // We don't sign exports.
+        sink.write_nix(&0u64)?;
+        sink.flush()?;
+        Ok(1)
+    }
+
+    fn query_deriver(&mut self, path: StorePath) -> Result<StorePath> {
+        Ok(self.entry(&path)?.info.deriver.clone())
+    }
+
+    fn collect_garbage(&mut self, op: CollectGarbage) -> Result<CollectGarbageResponse> {
+        let live = if op.ignore_liveness {
+            BTreeSet::new()
+        } else {
+            self.live_paths()
+        };
+
+        let to_delete: Vec<StorePath> = match op.action {
+            GcAction::ReturnLive => {
+                let paths = live.into_iter().map(|p| Path(p.0)).collect();
+                return Ok(CollectGarbageResponse::new(PathSet { paths }, 0));
+            }
+            GcAction::ReturnDead => {
+                let paths = self
+                    .paths
+                    .keys()
+                    .filter(|p| !live.contains(*p))
+                    .map(|p| Path(p.0.clone()))
+                    .collect();
+                return Ok(CollectGarbageResponse::new(PathSet { paths }, 0));
+            }
+            GcAction::DeleteDead => self
+                .paths
+                .keys()
+                .filter(|p| !live.contains(*p))
+                .cloned()
+                .collect(),
+            GcAction::DeleteSpecific => {
+                let specific: BTreeSet<_> = op.paths_to_delete.paths.iter().collect();
+                for path in &specific {
+                    if live.contains(*path) {
+                        return Err(anyhow!(
+                            "cannot delete path '{}' since it is still alive",
+                            BStr::new(path)
+                        )
+                        .into());
+                    }
+                    if let Some(r) = self.valid_referrers(path).find(|r| !specific.contains(r)) {
+                        return Err(anyhow!(
+                            "cannot delete path '{}' because it is referenced by '{}'",
+                            BStr::new(path),
+                            BStr::new(r)
+                        )
+                        .into());
+                    }
+                }
+                specific
+                    .into_iter()
+                    .filter(|p| self.paths.contains_key(*p))
+                    .cloned()
+                    .collect()
+            }
+        };
+
+        // Delete referrers before the paths that they refer to, so that the store is
+        // consistent if we stop early.
+        let mut deleted = Vec::new();
+        let mut bytes_freed = 0;
+        let mut remaining: BTreeSet<_> = to_delete.into_iter().collect();
+        while bytes_freed < op.max_freed {
+            let Some(path) = remaining
+                .iter()
+                .find(|p| self.valid_referrers(p).all(|r| r == *p))
+                .cloned()
+            else {
+                break;
+            };
+            remaining.remove(&path);
+            if let Some(entry) = self.remove(&path) {
+                bytes_freed += entry.info.nar_size;
+                deleted.push(Path(path.0));
+            }
+        }
+        Ok(CollectGarbageResponse::new(
+            PathSet { paths: deleted },
+            bytes_freed,
+        ))
+    }
+
+    fn query_substitutable_path_info(
+        &mut self,
+        _path: StorePath,
+    ) -> Result<Option<SubstitutablePathInfo>> {
+        Ok(None)
+    }
+
+    fn query_derivation_outputs(&mut self, path: StorePath) -> Result<StorePathSet> {
+        let outputs = self.derivation_outputs.get(&path).into_iter().flatten();
+        Ok(path_set(outputs.map(|(_name, out)| out.clone())))
+    }
+
+    fn query_all_valid_paths(&mut self) -> Result<StorePathSet> {
+        Ok(path_set(self.paths.keys().cloned()))
+    }
+
+    fn query_path_info(&mut self, path: StorePath) -> Result<QueryPathInfoResponse> {
+        Ok(QueryPathInfoResponse {
+            path: self.paths.get(&path).map(|entry| entry.info.clone()),
+        })
+    }
+
+    fn import_paths(&mut self, mut source: &mut dyn Read) -> Result<StorePathSet> {
+        let mut imported = Vec::new();
+        loop {
+            match source.read_nix::<u64>()? {
+                0 => break,
+                1 => {}
+                n => {
+                    return Err(anyhow!(
+                    "input doesn't look like something created by 'nix-store --export' (got {n})"
+                )
+                    .into())
+                }
+            }
+            let nar: Nar = source.read_nix()?;
+            let magic: u64 = source.read_nix()?;
+            if magic != EXPORT_MAGIC {
+                return Err(anyhow!("nix archive cannot be imported; wrong format").into());
+            }
+            let path: StorePath = source.read_nix()?;
+            let references: StorePathSet = source.read_nix()?;
+            let deriver: StorePath = source.read_nix()?;
+            // Old versions of nix could sign exports. The signature is ignored.
+            if source.read_nix::<u64>()? == 1 {
+                source.read_nix::<NixString>()?;
+            }
+
+            let (nar_hash, nar_size) = hash_nar(&nar)?;
+            let info = ValidPathInfo {
+                deriver,
+                hash: NarHash::from(&nar_hash),
+                references,
+                registration_time: 0,
+                nar_size,
+                ultimate: false,
+                sigs: StringSet::default(),
+                content_address: OptionalContentAddress::default(),
+            };
+            if !self.paths.contains_key(&path) {
+                self.add_path(path.clone(), info, nar)?;
+            }
+            imported.push(path);
+        }
+        Ok(path_set(imported))
+    }
+
+    fn query_derivation_output_names(&mut self, path: StorePath) -> Result<StringSet> {
+        let outputs = self.derivation_outputs.get(&path).into_iter().flatten();
+        Ok(StringSet {
+            paths: outputs.map(|(name, _out)| name.clone()).collect(),
+        })
+    }
+
+    fn query_path_from_hash_part(&mut self, hash_part: NixString) -> Result<StorePath> {
+        let mut prefix = hash_part.0.into_vec();
+        prefix.push(b'-');
+        let found = self.paths.keys().find(|path| {
+            let path: &[u8] = path.as_ref();
+            let base_name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+            base_name.starts_with(&prefix)
+        });
+        Ok(found.cloned().unwrap_or(StorePath(NixString::default())))
+    }
+
+    fn query_substitutable_path_infos(
+        &mut self,
+        _op: QuerySubstitutablePathInfos,
+    ) -> Result<Vec<(StorePath, SubstitutablePathInfo)>> {
+        Ok(Vec::new())
+    }
+
+    fn query_valid_paths(&mut self, op: QueryValidPaths) -> Result<StorePathSet> {
+        let valid = op.paths.paths.into_iter();
+        Ok(path_set(valid.filter(|p| self.paths.contains_key(p))))
+    }
+
+    fn query_substitutable_paths(&mut self, _paths: StorePathSet) -> Result<StorePathSet> {
+        Ok(path_set([]))
+    }
+
+    fn query_valid_derivers(&mut self, path: StorePath) -> Result<StorePathSet> {
+        let deriver = self.entry(&path)?.info.deriver.clone();
+        let valid = self.paths.contains_key(&deriver).then_some(deriver);
+        Ok(path_set(valid))
+    }
+
+    fn optimise_store(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+
+    fn verify_store(&mut self, op: VerifyStore) -> Result<bool> {
+        let mut errors = false;
+        for (path, entry) in &self.paths {
+            if op.check_contents && self.check_path(path, &entry.info, &entry.nar).is_err() {
+                tracing::warn!("path '{}' was modified!", BStr::new(path));
+                errors = true;
+            }
+            if let Err(e) = self.check_references(path, &entry.info.references, &BTreeSet::new()) {
+                tracing::warn!("{e}");
+                errors = true;
+            }
+        }
+        Ok(errors)
+    }
+
+    fn add_signatures(&mut self, op: AddSignatures) -> Result<u64> {
+        let entry = self
+            .paths
+            .get_mut(&op.path)
+            .ok_or_else(|| not_valid(&op.path))?;
+        for sig in op.signatures.paths {
+            if !entry.info.sigs.paths.contains(&sig) {
+                entry.info.sigs.paths.push(sig);
+            }
+        }
+        Ok(1)
+    }
+
+    fn nar_from_path(&mut self, path: StorePath, mut sink: &mut dyn Write) -> Result<()> {
+        sink.write_nix(&self.entry(&path)?.nar)?;
+        Ok(())
+    }
+
+    fn add_to_store_nar(&mut self, op: AddToStoreNar, mut source: &mut dyn Read) -> Result<()> {
+        let nar: Nar = source.read_nix()?;
+        if self.paths.contains_key(&op.path) && !op.repair {
+            return Ok(());
+        }
+        let info = ValidPathInfo {
+            deriver: op.deriver,
+            hash: NarHash {
+                data: op.nar_hash.0,
+            },
+            references: op.references,
+            registration_time: op.registration_time,
+            nar_size: op.nar_size,
+            // Paths added by clients are never ultimately trusted.
+            ultimate: false,
+            sigs: op.sigs,
+            content_address: op.content_address,
+        };
+        self.add_path(op.path, info, nar)
+    }
+
+    fn query_missing(&mut self, op: QueryMissing) -> Result<QueryMissingResponse> {
+        let mut will_build = BTreeSet::new();
+        let mut unknown = BTreeSet::new();
+        for path in op.paths {
+            if self.is_realised(&path) {
+                continue;
+            }
+            match path {
+                DerivedPath::Built { drv_path, .. } if self.paths.contains_key(&drv_path) => {
+                    will_build.insert(drv_path);
+                }
+                path => {
+                    unknown.insert(path.store_path().clone());
+                }
+            }
+        }
+        Ok(QueryMissingResponse {
+            will_build: path_set(will_build),
+            will_substitute: path_set([]),
+            unknown: path_set(unknown),
+            download_size: 0,
+            nar_size: 0,
+        })
+    }
+
+    fn query_derivation_output_map(&mut self, path: StorePath) -> Result<DerivationOutputMap> {
+        let outputs = self
+            .derivation_outputs
+            .get(&path)
+            .ok_or_else(|| anyhow!("'{}' is not a known derivation", BStr::new(&path)))?;
+        Ok(DerivationOutputMap {
+            paths: outputs
+                .iter()
+                .map(|(name, out)| (name.clone(), out.clone()))
+                .collect(),
+        })
+    }
+
+    fn register_drv_output(&mut self, realisation: Realisation) -> Result<()> {
+        self.realisations
+            .insert(realisation.id.clone(), realisation);
+        Ok(())
+    }
+
+    fn query_realisation(&mut self, drv_output: DrvOutput) -> Result<RealisationSet> {
+        Ok(RealisationSet {
+            realisations: self
+                .realisations
+                .get(&drv_output)
+                .cloned()
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    fn add_multiple_to_store(
+        &mut self,
+        _op: AddMultipleToStore,
+        mut source: &mut dyn Read,
+    ) -> Result<()> {
+        let count: u64 = source.read_nix()?;
+        let mut incoming = BTreeMap::new();
+        for _ in 0..count {
+            let path: StorePath = source.read_nix()?;
+            let mut info: ValidPathInfo = source.read_nix()?;
+            info.ultimate = false;
+            let nar: Nar = source.read_nix()?;
+            self.check_path(&path, &info, &nar)?;
+            incoming.insert(path, (info, nar));
+        }
+
+        // The paths can refer to each other, so check the references once we have them all.
+        let incoming_paths: BTreeSet<_> = incoming.keys().cloned().collect();
+        for (path, (info, _nar)) in &incoming {
+            self.check_references(path, &info.references, &incoming_paths)?;
+        }
+        for (path, (info, nar)) in incoming {
+            self.insert(path, info, nar);
+        }
+        Ok(())
+    }
+
+    fn build_paths_with_results(
+        &mut self,
+        op: BuildPaths,
+    ) -> Result<Vec<(DerivedPath, BuildResult)>> {
+        Ok(op
+            .paths
+            .into_iter()
+            .map(|path| {
+                let result = if self.is_realised(&path) {
+                    build_result(BuildStatus::AlreadyValid, String::new())
+                } else {
+                    let msg = format!("cannot build '{path}': this store can't build");
+                    build_result(BuildStatus::MiscFailure, msg)
+                };
+                (path, result)
+            })
+            .collect())
+    }
+
+    fn add_perm_root(&mut self, op: AddPermRoot) -> Result<Path> {
+        self.entry(&op.store_path)?;
+        self.perm_roots.insert(op.gc_root.0.clone(), op.store_path);
+        Ok(op.gc_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use crate::{
+        content_address::{ContentAddressMethod, ContentAddressMethodWithAlgo},
+        hash::{Hash, HashAlgorithm},
+        nar::{NarDirectoryEntry, NarFile},
+    };
+
+    use super::{super::testing::path, *};
+
+    fn file(contents: &str) -> Nar {
+        Nar::Contents(NarFile {
+            contents: NixString::from_bytes(contents.as_bytes()),
+            executable: false,
+        })
+    }
+
+    fn info(nar: &Nar, references: &[&StorePath]) -> ValidPathInfo {
+        let (nar_hash, nar_size) = hash_nar(nar).unwrap();
+        ValidPathInfo {
+            deriver: StorePath(NixString::default()),
+            hash: NarHash::from(&nar_hash),
+            references: path_set(references.iter().map(|&p| p.clone())),
+            registration_time: 1,
+            nar_size,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: OptionalContentAddress::default(),
+        }
+    }
+
+    /// A store with `a -> b -> c`, and `d` on its own.
+    fn store() -> InMemoryStore {
+        let (a, b, c, d) = (path("a"), path("b"), path("c"), path("d"));
+        let mut store = InMemoryStore::new();
+        for (p, refs) in [
+            (&c, vec![]),
+            (&b, vec![&c]),
+            (&a, vec![&a, &b]),
+            (&d, vec![]),
+        ] {
+            let nar = file(&String::from_utf8_lossy(p.as_ref()));
+            store.add_path(p.clone(), info(&nar, &refs), nar).unwrap();
+        }
+        store
+    }
+
+    fn gc_op(action: GcAction, paths: &[&StorePath]) -> CollectGarbage {
+        let paths = path_set(paths.iter().map(|&p| p.clone()));
+        CollectGarbage::new(action, paths, false, u64::MAX)
+    }
+
+    fn gc(store: &mut InMemoryStore, action: GcAction, paths: &[&StorePath]) -> Vec<StorePath> {
+        let resp = store.collect_garbage(gc_op(action, paths)).unwrap();
+        let mut paths: Vec<_> = resp
+            .paths
+            .paths
+            .into_iter()
+            .map(|p| StorePath(p.0))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn queries() {
+        let mut store = store();
+        assert!(store.is_valid_path(path("a")).unwrap());
+        assert!(!store.is_valid_path(path("x")).unwrap());
+        assert_eq!(
+            store.query_referrers(path("c")).unwrap().paths,
+            vec![path("b")]
+        );
+        assert_eq!(
+            store.query_references(path("b")).unwrap().paths,
+            vec![path("c")]
+        );
+        assert!(store.query_references(path("x")).is_err());
+        assert_eq!(
+            store
+                .query_path_from_hash_part(NixString::from_bytes(&[b'b'; 32]))
+                .unwrap(),
+            path("b")
+        );
+        assert_eq!(
+            store
+                .query_valid_paths(QueryValidPaths {
+                    paths: path_set([path("a"), path("x")]),
+                    builders_use_substitutes: false,
+                })
+                .unwrap()
+                .paths,
+            vec![path("a")]
+        );
+        let mut nar = Vec::new();
+        store.nar_from_path(path("d"), &mut nar).unwrap();
+        assert_eq!(
+            nar.as_slice().read_nix::<Nar>().unwrap(),
+            file("/nix/store/dddddddddddddddddddddddddddddddd-d")
+        );
+    }
+
+    #[test]
+    fn add_path_checks() {
+        let mut store = InMemoryStore::new();
+        let nar = Nar::Directory(vec![NarDirectoryEntry {
+            name: NixString::from_bytes(b"foo"),
+            node: file("foo"),
+        }]);
+
+        let mut bad_hash = info(&nar, &[]);
+        bad_hash.hash = NarHash::from(&Hash::compute(HashAlgorithm::Sha256, b""));
+        assert!(store.add_path(path("a"), bad_hash, nar.clone()).is_err());
+
+        let mut bad_size = info(&nar, &[]);
+        bad_size.nar_size += 1;
+        assert!(store.add_path(path("a"), bad_size, nar.clone()).is_err());
+
+        let dangling = info(&nar, &[&path("b")]);
+        assert!(store.add_path(path("a"), dangling, nar.clone()).is_err());
+
+        let mut base32 = info(&nar, &[]);
+        let (nar_hash, _) = hash_nar(&nar).unwrap();
+        base32.hash.data = ByteBuf::from(nar_hash.to_string().into_bytes());
+        store.add_path(path("a"), base32, nar).unwrap();
+        assert!(store.is_valid_path(path("a")).unwrap());
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let mut store = store();
+        store
+            .add_perm_root(AddPermRoot {
+                store_path: path("b"),
+                gc_root: Path(NixString::from_bytes(b"/tmp/root")),
+            })
+            .unwrap();
+
+        assert_eq!(
+            gc(&mut store, GcAction::ReturnLive, &[]),
+            vec![path("b"), path("c")]
+        );
+        assert_eq!(
+            gc(&mut store, GcAction::ReturnDead, &[]),
+            vec![path("a"), path("d")]
+        );
+
+        let mut op = gc_op(GcAction::DeleteSpecific, &[&path("c")]);
+        assert!(store.collect_garbage(op.clone()).is_err());
+        op.ignore_liveness = true;
+        // Still referenced by `b`.
+        assert!(store.collect_garbage(op).is_err());
+
+        assert_eq!(
+            gc(&mut store, GcAction::DeleteDead, &[]),
+            vec![path("a"), path("d")]
+        );
+        assert_eq!(
+            store.query_all_valid_paths().unwrap().paths,
+            vec![path("b"), path("c")]
+        );
+        assert!(store.query_referrers(path("b")).unwrap().paths.is_empty());
+    }
+
+    #[test]
+    fn export_import() {
+        let mut store = store();
+        let mut export = Vec::new();
+        for p in ["c", "b"] {
+            export.write_nix(&1u64).unwrap();
+            store
+                .export_path(
+                    ExportPath {
+                        path: path(p),
+                        sign: false,
+                    },
+                    &mut export,
+                )
+                .unwrap();
+        }
+        export.write_nix(&0u64).unwrap();
+
+        let mut other = InMemoryStore::new();
+        let imported = other.import_paths(&mut export.as_slice()).unwrap();
+        assert_eq!(imported.paths, vec![path("c"), path("b")]);
+        assert_eq!(
+            other.query_path_info(path("b")).unwrap().path.unwrap().hash,
+            store.query_path_info(path("b")).unwrap().path.unwrap().hash
+        );
+    }
+
+    #[test]
+    fn derived_paths() {
+        let mut store = store();
+        let drv = path("drv");
+        let drv_nar = file("Derive()");
+        store
+            .add_path(drv.clone(), info(&drv_nar, &[]), drv_nar)
+            .unwrap();
+        store.add_derivation_outputs(
+            drv.clone(),
+            [
+                (NixString::from_bytes(b"out"), path("a")),
+                (NixString::from_bytes(b"dev"), path("x")),
+            ],
+        );
+
+        let derived = |s: &str| DerivedPath::parse(s.as_bytes()).unwrap();
+        let drv_str = String::from_utf8_lossy(drv.as_ref()).into_owned();
+        let missing = store
+            .query_missing(QueryMissing {
+                paths: vec![
+                    derived(&format!("{drv_str}!out")),
+                    derived(&format!("{drv_str}!dev")),
+                    derived(&format!("{drv_str}!*")),
+                    DerivedPath::Opaque(path("x")),
+                ],
+            })
+            .unwrap();
+        assert_eq!(missing.will_build.paths, vec![drv.clone()]);
+        assert_eq!(missing.unknown.paths, vec![path("x")]);
+
+        let op = |paths| BuildPaths {
+            paths,
+            build_mode: crate::worker_op::BuildMode::Normal,
+        };
+        store
+            .build_paths(op(vec![
+                derived(&format!("{drv_str}!out")),
+                DerivedPath::Opaque(path("d")),
+            ]))
+            .unwrap();
+        assert!(store
+            .build_paths(op(vec![derived(&format!("{drv_str}!dev"))]))
+            .is_err());
+        let results = store
+            .build_paths_with_results(op(vec![derived(&format!("{drv_str}!*"))]))
+            .unwrap();
+        assert_eq!(results[0].1.status, BuildStatus::MiscFailure);
+    }
+
+    #[test]
+    fn add_to_store() {
+        let mut store = InMemoryStore::new();
+        let mut add = |method, dump: &[u8]| {
+            let op = AddToStore {
+                name: StorePath(NixString::from_bytes(b"hello.txt")),
+                cam_str: ContentAddressMethodWithAlgo {
+                    method,
+                    algorithm: HashAlgorithm::Sha256,
+                },
+                refs: path_set([]),
+                repair: false,
+            };
+            store.add_to_store(op, &mut &dump[..]).unwrap()
+        };
+
+        let flat = add(ContentAddressMethod::Flat, b"hello");
+        assert_eq!(
+            flat.path,
+            StorePath(NixString::from_bytes(
+                b"/nix/store/iixxin28s82lrxs8v4lcf7nha2dkwprm-hello.txt"
+            ))
+        );
+        assert_eq!(flat.info.nar_size, 120);
+        assert_eq!(
+            flat.info.content_address.0.unwrap().to_string(),
+            "fixed:sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+        );
+
+        let nar = crate::to_vec(&file("hello")).unwrap();
+        let recursive = add(ContentAddressMethod::Recursive, &nar);
+        assert_eq!(
+            recursive.path,
+            StorePath(NixString::from_bytes(
+                b"/nix/store/xfp2hphvk98rkk9ywlqpp587rjdcgnc2-hello.txt"
+            ))
+        );
+        assert_eq!(recursive.info.hash, flat.info.hash);
+
+        let text = store
+            .add_text_to_store(AddTextToStore {
+                suffix: NixString::from_bytes(b"hello.txt"),
+                text: NixString::from_bytes(b"hello"),
+                refs: path_set([]),
+            })
+            .unwrap();
+        assert_eq!(
+            text,
+            StorePath(NixString::from_bytes(
+                b"/nix/store/q790zdjk75hm2cn42nh77pqw4gbv1b88-hello.txt"
+            ))
+        );
+        let mut dumped = Vec::new();
+        store.nar_from_path(text, &mut dumped).unwrap();
+        assert_eq!(dumped, nar);
+    }
+}
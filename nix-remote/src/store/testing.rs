@@ -0,0 +1,21 @@
+//! Fixtures shared by the tests of the stores.
+
+use tempfile::TempDir;
+
+use crate::{NixString, StorePath};
+
+/// A store path named `name`, whose hash part is `name` repeated.
+pub fn path(name: &str) -> StorePath {
+    let hash_part = &name.repeat(32)[..32];
+    StorePath(NixString::from_bytes(
+        format!("/nix/store/{hash_part}-{name}").as_bytes(),
+    ))
+}
+
+/// A fresh directory for a test's store, removed again when it's dropped.
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("nix-remote-")
+        .tempdir()
+        .unwrap()
+}
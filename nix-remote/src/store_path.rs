@@ -0,0 +1,541 @@
+//! Parsed store paths.
+//!
+//! On the wire, a store path is just a string like
+//! `/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1`, and that's what
+//! [`StorePath`] holds. A [`ParsedStorePath`] is what nix works with internally: the
+//! 20-byte digest from the hash part, and the name. Going between the two needs the
+//! [`StoreDir`].
+
+use std::{collections::BTreeSet, fmt};
+
+use bstr::BStr;
+
+use crate::{
+    base32,
+    content_address::ContentAddress,
+    hash::{Hash, HashAlgorithm, HashFormat},
+    NixString, StorePath,
+};
+
+/// The length of the hash part of a store path, in base32 digits.
+pub const HASH_PART_LEN: usize = 32;
+
+/// The longest name that nix allows in a store path.
+pub const MAX_NAME_LEN: usize = 211;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StorePathError {
+    #[error("path '{0}' is not in the Nix store")]
+    NotInStore(String),
+
+    #[error("store path '{0}' has an invalid hash part")]
+    InvalidHashPart(String),
+
+    #[error("store path '{0}' has an empty name")]
+    EmptyName(String),
+
+    #[error("store path '{0}' has a name longer than {MAX_NAME_LEN} characters")]
+    NameTooLong(String),
+
+    #[error("store path '{0}' contains illegal character {1:?}")]
+    IllegalChar(String, char),
+
+    #[error("store path '{0}' starts with a period")]
+    LeadingPeriod(String),
+
+    #[error("store directory '{0}' is not an absolute path")]
+    InvalidStoreDir(String),
+
+    #[error(
+        "content-addressed path '{0}' can't have references unless it is text or a sha256 nar"
+    )]
+    UnexpectedReferences(String),
+
+    #[error("text path '{0}' can't refer to itself")]
+    TextSelfReference(String),
+}
+
+/// A store path without its store directory.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ParsedStorePath {
+    digest: [u8; 20],
+    name: String,
+}
+
+/// Checks that `name` can be the name part of a store path.
+///
+/// `path` is only used for error messages.
+pub fn check_name(name: &[u8], path: &[u8]) -> Result<(), StorePathError> {
+    let path = || BStr::new(path).to_string();
+    if name.is_empty() {
+        return Err(StorePathError::EmptyName(path()));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(StorePathError::NameTooLong(path()));
+    }
+    if name[0] == b'.' {
+        return Err(StorePathError::LeadingPeriod(path()));
+    }
+    if let Some(&c) = name
+        .iter()
+        .find(|&&c| !(c.is_ascii_alphanumeric() || b"+-._?=".contains(&c)))
+    {
+        return Err(StorePathError::IllegalChar(path(), c as char));
+    }
+    Ok(())
+}
+
+impl ParsedStorePath {
+    pub fn new(digest: [u8; 20], name: impl Into<String>) -> Result<Self, StorePathError> {
+        let name = name.into();
+        check_name(name.as_bytes(), name.as_bytes())?;
+        Ok(ParsedStorePath { digest, name })
+    }
+
+    /// Parses a store path without the store directory, like `<hash part>-<name>`.
+    pub fn from_base_name(base_name: &[u8]) -> Result<Self, StorePathError> {
+        Self::parse_base_name(base_name, base_name)
+    }
+
+    /// Like `from_base_name`, but mentions the full `path` in errors.
+    fn parse_base_name(base_name: &[u8], path: &[u8]) -> Result<Self, StorePathError> {
+        let invalid_hash = || StorePathError::InvalidHashPart(BStr::new(path).to_string());
+        if base_name.len() <= HASH_PART_LEN || base_name[HASH_PART_LEN] != b'-' {
+            return Err(invalid_hash());
+        }
+        let (hash_part, name) = (&base_name[..HASH_PART_LEN], &base_name[HASH_PART_LEN + 1..]);
+        let digest = base32::decode(hash_part).map_err(|_| invalid_hash())?;
+        check_name(name, path)?;
+        Ok(ParsedStorePath {
+            digest: digest.try_into().map_err(|_| invalid_hash())?,
+            // `check_name` only allows ASCII.
+            name: String::from_utf8_lossy(name).into_owned(),
+        })
+    }
+
+    pub fn digest(&self) -> &[u8; 20] {
+        &self.digest
+    }
+
+    /// The digest in base32, as it appears in the path.
+    pub fn hash_part(&self) -> String {
+        base32::encode(&self.digest)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Is this a derivation?
+    pub fn is_derivation(&self) -> bool {
+        self.name.ends_with(".drv")
+    }
+}
+
+/// Displays the path without the store directory, like nix does.
+impl fmt::Display for ParsedStorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.hash_part(), self.name)
+    }
+}
+
+impl fmt::Debug for ParsedStorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ParsedStorePath({self})")
+    }
+}
+
+/// The directory that store paths are in, usually `/nix/store`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StoreDir(String);
+
+impl Default for StoreDir {
+    fn default() -> Self {
+        StoreDir("/nix/store".to_owned())
+    }
+}
+
+impl StoreDir {
+    pub fn new(dir: impl Into<String>) -> Result<Self, StorePathError> {
+        let dir = dir.into();
+        let trimmed = dir.trim_end_matches('/');
+        if !trimmed.starts_with('/') {
+            return Err(StorePathError::InvalidStoreDir(dir));
+        }
+        Ok(StoreDir(trimmed.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Parses a path, which must be directly in this store directory.
+    pub fn parse(&self, path: &StorePath) -> Result<ParsedStorePath, StorePathError> {
+        let raw: &[u8] = path.as_ref();
+        let base_name = raw
+            .strip_prefix(self.0.as_bytes())
+            .and_then(|p| p.strip_prefix(b"/"))
+            .filter(|name| !name.contains(&b'/'))
+            .ok_or_else(|| StorePathError::NotInStore(BStr::new(raw).to_string()))?;
+        ParsedStorePath::parse_base_name(base_name, raw)
+    }
+
+    /// Parses a path that may be inside a store path, like
+    /// `/nix/store/<hash>-hello/bin/hello`, returning the store path and the rest.
+    pub fn parse_prefix<'a>(
+        &self,
+        path: &'a [u8],
+    ) -> Result<(ParsedStorePath, &'a [u8]), StorePathError> {
+        let not_in_store = || StorePathError::NotInStore(BStr::new(path).to_string());
+        let rest = path
+            .strip_prefix(self.0.as_bytes())
+            .and_then(|p| p.strip_prefix(b"/"))
+            .ok_or_else(not_in_store)?;
+        let end = rest.iter().position(|&b| b == b'/').unwrap_or(rest.len());
+        let store_path = &path[..path.len() - rest.len() + end];
+        let parsed = self.parse(&StorePath(NixString::from_bytes(store_path)))?;
+        Ok((parsed, &rest[end..]))
+    }
+
+    /// The wire form of a parsed path.
+    pub fn print(&self, path: &ParsedStorePath) -> StorePath {
+        StorePath(format!("{}/{path}", self.0).into())
+    }
+
+    /// Computes a store path from its type (like `source` or `output:out`), a hash, and
+    /// a name, like nix's `makeStorePath`.
+    pub fn make_store_path(
+        &self,
+        path_type: &str,
+        hash: &Hash,
+        name: &str,
+    ) -> Result<ParsedStorePath, StorePathError> {
+        let s = format!(
+            "{path_type}:{}:{}:{name}",
+            hash.encode(HashFormat::Base16, true),
+            self.0
+        );
+        let hash = Hash::compute(HashAlgorithm::Sha256, s.as_bytes());
+        // The sha256 is folded into the 20 bytes of the hash part.
+        let mut digest = [0; 20];
+        for (i, b) in hash.digest().iter().enumerate() {
+            digest[i % 20] ^= b;
+        }
+        ParsedStorePath::new(digest, name)
+    }
+
+    /// The path of an output of an input-addressed derivation called `drv_name`, whose
+    /// hash modulo fixed-output derivations is `hash`.
+    pub fn make_output_path(
+        &self,
+        output: &str,
+        hash: &Hash,
+        drv_name: &str,
+    ) -> Result<ParsedStorePath, StorePathError> {
+        let path_type = format!("output:{output}");
+        self.make_store_path(&path_type, hash, &output_path_name(drv_name, output))
+    }
+
+    /// The path of content-addressed data without references, like the output of a
+    /// fixed-output derivation.
+    pub fn make_fixed_output_path(
+        &self,
+        name: &str,
+        ca: &ContentAddress,
+    ) -> Result<ParsedStorePath, StorePathError> {
+        self.make_content_addressed_path(name, ca, &StoreReferences::default())
+    }
+
+    /// The path of content-addressed data, like nix's `makeFixedOutputPathFromCA`.
+    ///
+    /// Only text, and nars hashed with sha256, can have references, and only the nars
+    /// can refer to themselves.
+    pub fn make_content_addressed_path(
+        &self,
+        name: &str,
+        ca: &ContentAddress,
+        references: &StoreReferences,
+    ) -> Result<ParsedStorePath, StorePathError> {
+        match ca {
+            ContentAddress::Text(hash) => {
+                if references.self_reference {
+                    return Err(StorePathError::TextSelfReference(name.to_owned()));
+                }
+                self.make_text_path(name, hash, &references.others)
+            }
+            ContentAddress::Recursive(hash) if hash.algorithm() == HashAlgorithm::Sha256 => {
+                self.make_store_path(&self.make_type("source", references), hash, name)
+            }
+            _ => {
+                if !references.is_empty() {
+                    return Err(StorePathError::UnexpectedReferences(name.to_owned()));
+                }
+                let method = ca.method().prefix().trim_start_matches("fixed:");
+                let inner = format!(
+                    "fixed:out:{method}{}:",
+                    ca.hash().encode(HashFormat::Base16, true)
+                );
+                let hash = Hash::compute(HashAlgorithm::Sha256, inner.as_bytes());
+                self.make_store_path("output:out", &hash, name)
+            }
+        }
+    }
+
+    /// The path of a text file with the given sha256 hash, like the ones made by
+    /// `builtins.toFile` and `AddTextToStore`.
+    pub fn make_text_path(
+        &self,
+        name: &str,
+        hash: &Hash,
+        references: &BTreeSet<ParsedStorePath>,
+    ) -> Result<ParsedStorePath, StorePathError> {
+        let references = StoreReferences {
+            others: references.clone(),
+            self_reference: false,
+        };
+        self.make_store_path(&self.make_type("text", &references), hash, name)
+    }
+
+    /// The type of a path for `make_store_path`, which includes its references.
+    fn make_type(&self, path_type: &str, references: &StoreReferences) -> String {
+        // Nix sorts them by their base names, which is the same as sorting the full paths.
+        let mut others: Vec<_> = references
+            .others
+            .iter()
+            .map(|path| format!("{}/{path}", self.0))
+            .collect();
+        others.sort();
+        let mut path_type = path_type.to_owned();
+        for path in others {
+            path_type.push(':');
+            path_type.push_str(&path);
+        }
+        if references.self_reference {
+            path_type.push_str(":self");
+        }
+        path_type
+    }
+}
+
+/// The references of a content-addressed path, which are part of its hash.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreReferences {
+    pub others: BTreeSet<ParsedStorePath>,
+    /// Does the path refer to itself?
+    pub self_reference: bool,
+}
+
+impl StoreReferences {
+    pub fn is_empty(&self) -> bool {
+        self.others.is_empty() && !self.self_reference
+    }
+}
+
+/// The name of the path of output `output` of a derivation called `drv_name`.
+pub fn output_path_name(drv_name: &str, output: &str) -> String {
+    if output == "out" {
+        drv_name.to_owned()
+    } else {
+        format!("{drv_name}-{output}")
+    }
+}
+
+impl fmt::Display for StoreDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for ParsedStorePath {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let name: String = u.arbitrary()?;
+        let mut name: String = name.chars().filter(char::is_ascii_alphanumeric).collect();
+        if name.is_empty() {
+            name.push_str("foo");
+        }
+        name.truncate(MAX_NAME_LEN);
+        Ok(ParsedStorePath::new(u.arbitrary()?, name).expect("the name is valid"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_address::ContentAddressMethod;
+
+    const HELLO: &str = "/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1";
+
+    fn path(p: &str) -> StorePath {
+        StorePath(p.to_owned().into())
+    }
+
+    #[test]
+    fn parse_and_print() {
+        let store_dir = StoreDir::default();
+        let parsed = store_dir.parse(&path(HELLO)).unwrap();
+        assert_eq!(parsed.name(), "hello-2.12.1");
+        assert_eq!(parsed.hash_part(), "fxq6wwh8xypah2kly1q8akh4ivzdw1vl");
+        assert_eq!(
+            parsed.to_string(),
+            "fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1"
+        );
+        assert!(!parsed.is_derivation());
+        assert_eq!(store_dir.print(&parsed), path(HELLO));
+
+        let other = StoreDir::new("/gnu/store/").unwrap();
+        assert_eq!(other.as_str(), "/gnu/store");
+        assert_eq!(
+            other.print(&parsed),
+            path("/gnu/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1")
+        );
+        assert!(StoreDir::new("nix/store").is_err());
+        assert!(StoreDir::new("/").is_err());
+    }
+
+    #[test]
+    fn parse_prefix() {
+        let store_dir = StoreDir::default();
+        let bin = format!("{HELLO}/bin/hello");
+        let (parsed, rest) = store_dir.parse_prefix(bin.as_bytes()).unwrap();
+        assert_eq!(store_dir.print(&parsed), path(HELLO));
+        assert_eq!(rest, b"/bin/hello");
+        let (_, rest) = store_dir.parse_prefix(HELLO.as_bytes()).unwrap();
+        assert_eq!(rest, b"");
+    }
+
+    #[test]
+    fn make_paths() {
+        let store_dir = StoreDir::default();
+        let print = |p: ParsedStorePath| store_dir.print(&p);
+        let hello = Hash::compute(HashAlgorithm::Sha256, b"hello");
+        let fixed = |ca| print(store_dir.make_fixed_output_path("hello.txt", &ca).unwrap());
+        assert_eq!(
+            fixed(ContentAddress::Flat(hello)),
+            path("/nix/store/iixxin28s82lrxs8v4lcf7nha2dkwprm-hello.txt")
+        );
+        assert_eq!(
+            fixed(ContentAddress::Recursive(hello)),
+            path("/nix/store/znxk460dqf3s9jhv1pynin6mszpxzizm-hello.txt")
+        );
+        assert_eq!(
+            fixed(ContentAddress::Recursive(Hash::compute(
+                HashAlgorithm::Sha1,
+                b"hello"
+            ))),
+            path("/nix/store/i1qwvcgyn128a46qdk48ap7hrd5vrxjd-hello.txt")
+        );
+        assert_eq!(
+            fixed(ContentAddress::Text(hello)),
+            path("/nix/store/q790zdjk75hm2cn42nh77pqw4gbv1b88-hello.txt")
+        );
+
+        let drv_hash = Hash::compute(HashAlgorithm::Sha256, b"");
+        let output = |name| {
+            print(
+                store_dir
+                    .make_output_path(name, &drv_hash, "hello")
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            output("out"),
+            path("/nix/store/pzhj6mmwcrncsx978f1yb8n2qv2lyz75-hello")
+        );
+        assert_eq!(
+            output("dev"),
+            path("/nix/store/sqs7a8jrjqbga4l91ngnb7pidcd50bsv-hello-dev")
+        );
+        assert!(store_dir.make_store_path("text", &hello, "a b").is_err());
+    }
+
+    #[test]
+    fn references() {
+        let store_dir = StoreDir::default();
+        let print = |p: ParsedStorePath| store_dir.print(&p);
+        let hello = Hash::compute(HashAlgorithm::Sha256, b"hello");
+        let mut references = StoreReferences {
+            others: [
+                "fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1",
+                "3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39",
+            ]
+            .into_iter()
+            .map(|p| ParsedStorePath::from_base_name(p.as_bytes()).unwrap())
+            .collect(),
+            self_reference: false,
+        };
+        assert_eq!(
+            print(
+                store_dir
+                    .make_text_path("hello.txt", &hello, &references.others)
+                    .unwrap()
+            ),
+            path("/nix/store/n0pbdzkhwa26kprjbp6cqfcgn5lb9727-hello.txt")
+        );
+
+        references.self_reference = true;
+        let ca = |method| ContentAddress::new(method, hello).unwrap();
+        let make =
+            |method| store_dir.make_content_addressed_path("hello.txt", &ca(method), &references);
+        assert_eq!(
+            print(make(ContentAddressMethod::Recursive).unwrap()),
+            path("/nix/store/ll8ia0mgwgpzjvbn9aibf39977gjwki4-hello.txt")
+        );
+        assert_eq!(
+            make(ContentAddressMethod::Text),
+            Err(StorePathError::TextSelfReference("hello.txt".to_owned()))
+        );
+        assert_eq!(
+            make(ContentAddressMethod::Flat),
+            Err(StorePathError::UnexpectedReferences("hello.txt".to_owned()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let store_dir = StoreDir::default();
+        let parse = |p: &str| store_dir.parse(&path(p)).unwrap_err();
+        assert!(matches!(
+            parse("/gnu/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello"),
+            StorePathError::NotInStore(_)
+        ));
+        assert!(matches!(
+            parse(&format!("{HELLO}/bin")),
+            StorePathError::NotInStore(_)
+        ));
+        // `e` isn't a base32 digit.
+        assert!(matches!(
+            parse("/nix/store/exq6wwh8xypah2kly1q8akh4ivzdw1vl-hello"),
+            StorePathError::InvalidHashPart(_)
+        ));
+        assert!(matches!(
+            parse("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1v-hello"),
+            StorePathError::InvalidHashPart(_)
+        ));
+        assert!(matches!(
+            parse("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-"),
+            StorePathError::EmptyName(_)
+        ));
+        assert!(matches!(
+            parse("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-.hello"),
+            StorePathError::LeadingPeriod(_)
+        ));
+        assert_eq!(
+            parse("/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello world"),
+            StorePathError::IllegalChar(
+                "/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello world".to_owned(),
+                ' '
+            )
+        );
+        let long = format!(
+            "/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-{}",
+            "a".repeat(212)
+        );
+        assert!(matches!(parse(&long), StorePathError::NameTooLong(_)));
+        let longest = format!(
+            "/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-{}",
+            "a".repeat(211)
+        );
+        assert!(store_dir.parse(&path(&longest)).is_ok());
+    }
+}
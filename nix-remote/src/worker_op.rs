@@ -1,11 +1,21 @@
 //! Worker ops from the Nix protocol.
 
-use serde::{Deserialize, Serialize};
-use std::ops::{Deref, DerefMut};
+use serde::{ser::SerializeTuple, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::ops::{Deref, DerefMut, RangeInclusive};
 use tagged_serde::TaggedSerde;
 
+use crate::content_address::{ContentAddressMethodWithAlgo, OptionalContentAddress};
+use crate::derivation::{self, DerivationOutput};
 use crate::nar::Nar;
-use crate::{DerivedPath, Path, PathSet, Realisation, RealisationSet};
+use crate::realisation::DrvOutput;
+use crate::serialize::{versioned_serde, NixReadExt, SeqAccessExt, Versioned};
+use crate::store_path::ParsedStorePath;
+use crate::{
+    DaemonVersion, DerivedPath, Path, PathSet, Realisation, RealisationSet, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
 use crate::{
     NarHash, NixString, Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
 };
@@ -95,107 +105,219 @@ impl<T> StreamingRecv for Plain<T> {
 ///
 /// The second argument in each variant is a tag denoting the expected return value.
 ///
-/// On the wire, they are represented as the opcode followed by the body.
+/// On the wire, they are represented as the opcode followed by the body. Not every op is
+/// available in every protocol version (see [`WorkerOp::versions`]), and one opcode can
+/// mean different ops in different protocol versions.
+///
+/// Opcodes 2, 15 and 17 belonged to ops that were removed before protocol version 1.21,
+/// the oldest one we speak.
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[derive(Debug, TaggedSerde, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum WorkerOp {
-    #[tagged_serde = 1]
     IsValidPath(Plain<StorePath>, Resp<bool>),
-    #[tagged_serde = 6]
+    HasSubstitutes(Plain<StorePath>, Resp<bool>),
+    /// Obsolete: returns the base16 nar hash, which is also in `QueryPathInfo`.
+    QueryPathHash(Plain<StorePath>, Resp<NixString>),
+    /// Obsolete: the references are also in `QueryPathInfo`.
+    QueryReferences(Plain<StorePath>, Resp<StorePathSet>),
     QueryReferrers(Plain<StorePath>, Resp<StorePathSet>),
-    #[tagged_serde = 7]
+    /// The layout of `AddToStore` before protocol version 1.25.
+    AddToStoreLegacy(Plain<AddToStoreLegacy>, Resp<StorePath>),
     AddToStore(WithFramedSource<AddToStore>, Resp<ValidPathInfoWithPath>),
-    #[tagged_serde = 9]
+    /// Obsolete: clients use `AddToStore` since protocol version 1.25.
+    AddTextToStore(Plain<AddTextToStore>, Resp<StorePath>),
     BuildPaths(Plain<BuildPaths>, Resp<u64>),
-    #[tagged_serde = 10]
     EnsurePath(Plain<StorePath>, Resp<u64>),
-    #[tagged_serde = 11]
     AddTempRoot(Plain<StorePath>, Resp<u64>),
-    #[tagged_serde = 14]
+    AddIndirectRoot(Plain<Path>, Resp<u64>),
+    SyncWithGC(Plain<()>, Resp<u64>),
     FindRoots(Plain<()>, Resp<FindRootsResponse>),
-    #[tagged_serde = 19]
+    /// Obsolete: the export is sent as `stderr::Msg::Write` messages.
+    ExportPath(Plain<ExportPath>, Resp<u64>),
+    /// Obsolete: the deriver is also in `QueryPathInfo`.
+    QueryDeriver(Plain<StorePath>, Resp<OptionalStorePath>),
     SetOptions(Plain<SetOptions>, Resp<()>),
-    #[tagged_serde = 20]
     CollectGarbage(Plain<CollectGarbage>, Resp<CollectGarbageResponse>),
-    #[tagged_serde = 23]
+    QuerySubstitutablePathInfo(Plain<StorePath>, Resp<Option<SubstitutablePathInfo>>),
+    /// Obsolete: replaced by `QueryDerivationOutputMap`.
+    QueryDerivationOutputs(Plain<StorePath>, Resp<StorePathSet>),
     QueryAllValidPaths(Plain<()>, Resp<StorePathSet>),
-    #[tagged_serde = 26]
+    /// Obsolete: nix 2 no longer caches build failures, and rejects this op.
+    QueryFailedPaths(Plain<()>, Resp<StorePathSet>),
+    /// Obsolete: nix 2 no longer caches build failures, and rejects this op.
+    ClearFailedPaths(Plain<StorePathSet>, Resp<u64>),
     QueryPathInfo(Plain<StorePath>, Resp<QueryPathInfoResponse>),
-    #[tagged_serde = 29]
+    /// Obsolete: the exports are requested with `stderr::Msg::Read` messages.
+    ImportPaths(Plain<()>, Resp<StorePathSet>),
+    /// Obsolete: replaced by `QueryDerivationOutputMap`.
+    QueryDerivationOutputNames(Plain<StorePath>, Resp<StringSet>),
     QueryPathFromHashPart(Plain<NixString>, Resp<OptionalStorePath>),
-    #[tagged_serde = 31]
+    QuerySubstitutablePathInfos(
+        Plain<QuerySubstitutablePathInfos>,
+        Resp<Vec<(StorePath, SubstitutablePathInfo)>>,
+    ),
     QueryValidPaths(Plain<QueryValidPaths>, Resp<StorePathSet>),
-    #[tagged_serde = 32]
     QuerySubstitutablePaths(Plain<StorePathSet>, Resp<StorePathSet>),
-    #[tagged_serde = 33]
     QueryValidDerivers(Plain<StorePath>, Resp<StorePathSet>),
-    #[tagged_serde = 34]
     OptimiseStore(Plain<()>, Resp<u64>),
-    #[tagged_serde = 35]
     VerifyStore(Plain<VerifyStore>, Resp<bool>),
-    #[tagged_serde = 36]
     BuildDerivation(Plain<BuildDerivation>, Resp<BuildResult>),
-    #[tagged_serde = 37]
     AddSignatures(Plain<AddSignatures>, Resp<u64>),
-    #[tagged_serde = 38]
     NarFromPath(Plain<StorePath>, Resp<Nar>),
-    #[tagged_serde = 39]
     AddToStoreNar(WithFramedSource<AddToStoreNar>, Resp<()>),
-    #[tagged_serde = 40]
     QueryMissing(Plain<QueryMissing>, Resp<QueryMissingResponse>),
-    #[tagged_serde = 41]
     QueryDerivationOutputMap(Plain<StorePath>, Resp<DerivationOutputMap>),
-    #[tagged_serde = 42]
-    RegisterDrvOutput(Plain<Realisation>, Resp<()>),
-    #[tagged_serde = 43]
-    QueryRealisation(Plain<NixString>, Resp<RealisationSet>),
-    #[tagged_serde = 44]
+    RegisterDrvOutput(Plain<RegisterDrvOutput>, Resp<()>),
+    QueryRealisation(Plain<DrvOutput>, Resp<QueryRealisationResponse>),
     AddMultipleToStore(WithFramedSource<AddMultipleToStore>, Resp<()>),
-    #[tagged_serde = 45]
     AddBuildLog(WithFramedSource<AddBuildLog>, Resp<u64>),
-    #[tagged_serde = 46]
     BuildPathsWithResults(Plain<BuildPaths>, Resp<Vec<(DerivedPath, BuildResult)>>),
+    /// Returns the path of the new gc root.
+    AddPermRoot(Plain<AddPermRoot>, Resp<Path>),
 }
 
+// When two ops share an opcode, the older one comes first.
 macro_rules! for_each_op {
     ($macro_name:ident !) => {
         $macro_name!(
-            IsValidPath,
-            QueryReferrers,
-            AddToStore,
-            BuildPaths,
-            EnsurePath,
-            AddTempRoot,
-            FindRoots,
-            SetOptions,
-            CollectGarbage,
-            QueryAllValidPaths,
-            QueryPathInfo,
-            QueryPathFromHashPart,
-            QueryValidPaths,
-            QuerySubstitutablePaths,
-            QueryValidDerivers,
-            OptimiseStore,
-            VerifyStore,
-            BuildDerivation,
-            AddSignatures,
-            NarFromPath,
-            AddToStoreNar,
-            QueryMissing,
-            QueryDerivationOutputMap,
-            RegisterDrvOutput,
-            QueryRealisation,
-            AddMultipleToStore,
-            AddBuildLog,
-            BuildPathsWithResults
+            1 => IsValidPath,
+            3 => HasSubstitutes,
+            4 => QueryPathHash,
+            5 => QueryReferences,
+            6 => QueryReferrers,
+            7 => AddToStoreLegacy,
+            7 => AddToStore,
+            8 => AddTextToStore,
+            9 => BuildPaths,
+            10 => EnsurePath,
+            11 => AddTempRoot,
+            12 => AddIndirectRoot,
+            13 => SyncWithGC,
+            14 => FindRoots,
+            16 => ExportPath,
+            18 => QueryDeriver,
+            19 => SetOptions,
+            20 => CollectGarbage,
+            21 => QuerySubstitutablePathInfo,
+            22 => QueryDerivationOutputs,
+            23 => QueryAllValidPaths,
+            24 => QueryFailedPaths,
+            25 => ClearFailedPaths,
+            26 => QueryPathInfo,
+            27 => ImportPaths,
+            28 => QueryDerivationOutputNames,
+            29 => QueryPathFromHashPart,
+            30 => QuerySubstitutablePathInfos,
+            31 => QueryValidPaths,
+            32 => QuerySubstitutablePaths,
+            33 => QueryValidDerivers,
+            34 => OptimiseStore,
+            35 => VerifyStore,
+            36 => BuildDerivation,
+            37 => AddSignatures,
+            38 => NarFromPath,
+            39 => AddToStoreNar,
+            40 => QueryMissing,
+            41 => QueryDerivationOutputMap,
+            42 => RegisterDrvOutput,
+            43 => QueryRealisation,
+            44 => AddMultipleToStore,
+            45 => AddBuildLog,
+            46 => BuildPathsWithResults,
+            47 => AddPermRoot
         )
     };
 }
 
+/// The minor protocol versions in which the op with this name exists.
+fn op_versions(name: &str) -> RangeInclusive<u8> {
+    let min = MIN_PROTOCOL_VERSION.minor;
+    let max = PROTOCOL_VERSION.minor;
+    match name {
+        "AddToStoreLegacy" => min..=24,
+        "AddToStore" => 25..=max,
+        "QueryDerivationOutputMap" => 22..=max,
+        "RegisterDrvOutput" | "QueryRealisation" => 27..=max,
+        "AddMultipleToStore" | "AddBuildLog" => 32..=max,
+        "BuildPathsWithResults" => 34..=max,
+        "AddPermRoot" => 36..=max,
+        _ => min..=max,
+    }
+}
+
+impl WorkerOp {
+    /// The opcode of this op.
+    pub fn opcode(&self) -> u64 {
+        macro_rules! opcode {
+            ($($tag:literal => $name:ident),*) => {
+                match self {
+                    $(WorkerOp::$name(..) => $tag,)*
+                }
+            };
+        }
+        for_each_op!(opcode!)
+    }
+
+    /// The name of this op.
+    pub fn name(&self) -> &'static str {
+        macro_rules! name {
+            ($($tag:literal => $name:ident),*) => {
+                match self {
+                    $(WorkerOp::$name(..) => stringify!($name),)*
+                }
+            };
+        }
+        for_each_op!(name!)
+    }
+
+    /// The protocol versions in which this op can be sent.
+    pub fn versions(&self) -> RangeInclusive<DaemonVersion> {
+        let minor = op_versions(self.name());
+        DaemonVersion {
+            major: 1,
+            minor: *minor.start(),
+        }..=DaemonVersion {
+            major: 1,
+            minor: *minor.end(),
+        }
+    }
+
+    /// Whether `opcode` belongs to an op that exists in the given protocol version.
+    pub fn is_available(opcode: u64, version: DaemonVersion) -> bool {
+        macro_rules! available {
+            ($($tag:literal => $name:ident),*) => {
+                $((opcode == $tag && op_versions(stringify!($name)).contains(&version.minor)))||*
+            };
+        }
+        for_each_op!(available!)
+    }
+
+    /// Read the body of the op with the given opcode, when the opcode has already been read.
+    pub fn read_body(
+        opcode: u64,
+        read: impl Read,
+        version: DaemonVersion,
+    ) -> crate::serialize::Result<WorkerOp> {
+        let opcode = opcode.to_le_bytes();
+        opcode.as_slice().chain(read).read_nix_with_version(version)
+    }
+
+    /// Like [`StreamingRecv::requires_streaming`], but for a specific protocol version.
+    ///
+    /// Before protocol version 1.23, `AddToStoreNar` gets its data by sending
+    /// `stderr::Msg::Read` messages instead.
+    pub fn requires_streaming_at(&self, version: DaemonVersion) -> bool {
+        match self {
+            WorkerOp::AddToStoreNar(..) => version.minor >= 23,
+            _ => self.requires_streaming(),
+        }
+    }
+}
+
 impl StreamingRecv for WorkerOp {
     fn requires_streaming(&self) -> bool {
         macro_rules! requires_streaming {
-            ($($name:ident),*) => {
+            ($($tag:literal => $name:ident),*) => {
                 match self {
                     $(WorkerOp::$name(op, _resp) => {
                         return op.requires_streaming();
@@ -207,6 +329,80 @@ impl StreamingRecv for WorkerOp {
     }
 }
 
+impl Versioned for WorkerOp {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if !self.versions().contains(&version) {
+            return Err(serde::ser::Error::custom(format!(
+                "{} is not available in protocol version {version}",
+                self.name()
+            )));
+        }
+
+        let mut tup = serializer.serialize_tuple(usize::MAX)?;
+        tup.serialize_element(&self.opcode())?;
+        macro_rules! serialize {
+            ($($tag:literal => $name:ident),*) => {
+                match self {
+                    $(WorkerOp::$name(op, resp) => {
+                        tup.serialize_element(op)?;
+                        tup.serialize_element(resp)?;
+                    },)*
+                }
+            };
+        }
+        for_each_op!(serialize!);
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct Visitor(DaemonVersion);
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = WorkerOp;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("WorkerOp")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<WorkerOp, A::Error> {
+                let version = self.0;
+                let tag: u64 = seq.expect_element()?;
+                let mut known = false;
+                macro_rules! deserialize {
+                    ($($tag:literal => $name:ident),*) => {
+                        $(if tag == $tag {
+                            known = true;
+                            if op_versions(stringify!($name)).contains(&version.minor) {
+                                return Ok(WorkerOp::$name(seq.expect_element()?, seq.expect_element()?));
+                            }
+                        })*
+                    };
+                }
+                for_each_op!(deserialize!);
+                if known {
+                    Err(serde::de::Error::custom(format!(
+                        "worker op {tag} is not available in protocol version {version}"
+                    )))
+                } else {
+                    Err(serde::de::Error::custom(format!("unknown worker op {tag}")))
+                }
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, Visitor(version))
+    }
+}
+
 type Time = u64;
 type OptionalStorePath = StorePath;
 
@@ -253,11 +449,42 @@ pub struct SetOptions {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct AddToStore {
     pub name: StorePath,
-    pub cam_str: StorePath,
+    pub cam_str: ContentAddressMethodWithAlgo,
     pub refs: StorePathSet,
     pub repair: bool,
 }
 
+/// The body of `AddToStore` before protocol version 1.25.
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AddToStoreLegacy {
+    pub base_name: NixString,
+    /// If false, the other fields are ignored and the path is added recursively,
+    /// with sha256.
+    pub fixed: bool,
+    pub recursive: bool,
+    pub hash_algo: NixString,
+    /// The contents. This is a nar even if `recursive` is false, in which case it
+    /// contains a single file.
+    pub nar: Nar,
+}
+
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AddTextToStore {
+    pub suffix: NixString,
+    pub text: NixString,
+    pub refs: StorePathSet,
+}
+
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExportPath {
+    pub path: StorePath,
+    /// Obsolete, and ignored by the daemon.
+    pub sign: bool,
+}
+
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 #[derive(Debug, Clone, Copy, TaggedSerde, PartialEq, Eq)]
 pub enum BuildMode {
@@ -272,14 +499,14 @@ pub enum BuildMode {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BuildPaths {
-    pub paths: Vec<StorePath>,
+    pub paths: Vec<DerivedPath>,
     pub build_mode: BuildMode,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct QueryMissing {
-    pub paths: Vec<StorePath>,
+    pub paths: Vec<DerivedPath>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -333,22 +560,105 @@ pub enum BuildStatus {
     NoSubstituters,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BuildResult {
     pub status: BuildStatus,
     pub error_msg: NixString,
+    /// Since protocol version 1.29.
     pub times_built: u64,
+    /// Since protocol version 1.29.
     pub is_non_deterministic: bool,
+    /// Since protocol version 1.29.
     pub start_time: Time,
+    /// Since protocol version 1.29.
     pub stop_time: Time,
+    /// User CPU time used by the build, in microseconds. Since protocol version 1.37.
+    pub cpu_user: Option<u64>,
+    /// System CPU time used by the build, in microseconds. Since protocol version 1.37.
+    pub cpu_system: Option<u64>,
+    /// Since protocol version 1.28.
     pub built_outputs: DrvOutputs,
 }
 
-// TODO: first NixString is a DrvOutput; second is a Realisation
+impl Versioned for BuildResult {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(usize::MAX)?;
+        tup.serialize_element(&self.status)?;
+        tup.serialize_element(&self.error_msg)?;
+        if version.minor >= 29 {
+            tup.serialize_element(&self.times_built)?;
+            tup.serialize_element(&self.is_non_deterministic)?;
+            tup.serialize_element(&self.start_time)?;
+            tup.serialize_element(&self.stop_time)?;
+        }
+        if version.minor >= 37 {
+            tup.serialize_element(&self.cpu_user)?;
+            tup.serialize_element(&self.cpu_system)?;
+        }
+        if version.minor >= 28 {
+            tup.serialize_element(&self.built_outputs)?;
+        }
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct Visitor(DaemonVersion);
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = BuildResult;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("BuildResult")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<BuildResult, A::Error> {
+                let version = self.0;
+                let mut ret = BuildResult {
+                    status: seq.expect_element()?,
+                    error_msg: seq.expect_element()?,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs::default(),
+                };
+                if version.minor >= 29 {
+                    ret.times_built = seq.expect_element()?;
+                    ret.is_non_deterministic = seq.expect_element()?;
+                    ret.start_time = seq.expect_element()?;
+                    ret.stop_time = seq.expect_element()?;
+                }
+                if version.minor >= 37 {
+                    ret.cpu_user = seq.expect_element()?;
+                    ret.cpu_system = seq.expect_element()?;
+                }
+                if version.minor >= 28 {
+                    ret.built_outputs = seq.expect_element()?;
+                }
+                Ok(ret)
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, Visitor(version))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct DrvOutputs(pub Vec<(NixString, Realisation)>);
+pub struct DrvOutputs(pub Vec<(DrvOutput, Realisation)>);
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -362,6 +672,25 @@ pub struct CollectGarbage {
     _obsolete2: u64,
 }
 
+impl CollectGarbage {
+    pub fn new(
+        action: GcAction,
+        paths_to_delete: StorePathSet,
+        ignore_liveness: bool,
+        max_freed: u64,
+    ) -> Self {
+        CollectGarbage {
+            action,
+            paths_to_delete,
+            ignore_liveness,
+            max_freed,
+            _obsolete0: 0,
+            _obsolete1: 0,
+            _obsolete2: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct DerivationOutputMap {
@@ -376,6 +705,16 @@ pub struct CollectGarbageResponse {
     _obsolete: u64,
 }
 
+impl CollectGarbageResponse {
+    pub fn new(paths: PathSet, bytes_freed: u64) -> Self {
+        CollectGarbageResponse {
+            paths,
+            bytes_freed,
+            _obsolete: 0,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, TaggedSerde, Default, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub enum GcAction {
@@ -401,24 +740,127 @@ pub struct AddToStoreNar {
     pub nar_size: u64,
     pub ultimate: bool,
     pub sigs: StringSet,
-    pub content_address: RenderedContentAddress,
+    pub content_address: OptionalContentAddress,
     pub repair: bool,
     pub dont_check_sigs: bool,
 }
 
+impl AddToStoreNar {
+    /// The path that this op adds, and its info.
+    pub fn path_info(&self) -> ValidPathInfoWithPath {
+        ValidPathInfoWithPath {
+            path: self.path.clone(),
+            info: ValidPathInfo {
+                deriver: self.deriver.clone(),
+                hash: NarHash {
+                    data: self.nar_hash.0.clone(),
+                },
+                references: self.references.clone(),
+                registration_time: self.registration_time,
+                nar_size: self.nar_size,
+                ultimate: self.ultimate,
+                sigs: self.sigs.clone(),
+                content_address: self.content_address,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct FindRootsResponse {
     pub roots: Vec<(Path, StorePath)>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct QueryValidPaths {
     pub paths: StorePathSet,
+    /// Since protocol version 1.27.
     pub builders_use_substitutes: bool,
 }
 
+impl Versioned for QueryValidPaths {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if version.minor >= 27 {
+            (&self.paths, self.builders_use_substitutes).serialize(serializer)
+        } else {
+            self.paths.serialize(serializer)
+        }
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        if version.minor >= 27 {
+            let (paths, builders_use_substitutes) = Deserialize::deserialize(deserializer)?;
+            Ok(QueryValidPaths {
+                paths,
+                builders_use_substitutes,
+            })
+        } else {
+            Ok(QueryValidPaths {
+                paths: Deserialize::deserialize(deserializer)?,
+                builders_use_substitutes: false,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct QuerySubstitutablePathInfos {
+    /// The paths to query, with their content addresses (which can be empty).
+    ///
+    /// Before protocol version 1.22, only the paths are sent.
+    pub paths: Vec<(StorePath, OptionalContentAddress)>,
+}
+
+impl Versioned for QuerySubstitutablePathInfos {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if version.minor >= 22 {
+            self.paths.serialize(serializer)
+        } else {
+            let paths: Vec<_> = self.paths.iter().map(|(path, _ca)| path).collect();
+            paths.serialize(serializer)
+        }
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let paths = if version.minor >= 22 {
+            Deserialize::deserialize(deserializer)?
+        } else {
+            let paths: Vec<StorePath> = Deserialize::deserialize(deserializer)?;
+            paths
+                .into_iter()
+                .map(|path| (path, OptionalContentAddress::default()))
+                .collect()
+        };
+        Ok(QuerySubstitutablePathInfos { paths })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct SubstitutablePathInfo {
+    pub deriver: OptionalStorePath,
+    pub references: StorePathSet,
+    pub download_size: u64,
+    pub nar_size: u64,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct AddMultipleToStore {
@@ -427,19 +869,195 @@ pub struct AddMultipleToStore {
 }
 
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ValidPathInfo {
     pub deriver: OptionalStorePath,
     pub hash: NarHash,
     pub references: StorePathSet,
     pub registration_time: Time, // In seconds, since the epoch
     pub nar_size: u64,
+    /// Since protocol version 1.16.
     pub ultimate: bool,
+    /// Since protocol version 1.16.
     pub sigs: StringSet,
-    pub content_address: RenderedContentAddress, // Can be empty
+    /// Since protocol version 1.16.
+    pub content_address: OptionalContentAddress,
+}
+
+impl Versioned for ValidPathInfo {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(usize::MAX)?;
+        tup.serialize_element(&self.deriver)?;
+        tup.serialize_element(&self.hash)?;
+        tup.serialize_element(&self.references)?;
+        tup.serialize_element(&self.registration_time)?;
+        tup.serialize_element(&self.nar_size)?;
+        if version.minor >= 16 {
+            tup.serialize_element(&self.ultimate)?;
+            tup.serialize_element(&self.sigs)?;
+            tup.serialize_element(&self.content_address)?;
+        }
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct Visitor(DaemonVersion);
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ValidPathInfo;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("ValidPathInfo")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ValidPathInfo, A::Error> {
+                let mut ret = ValidPathInfo {
+                    deriver: seq.expect_element()?,
+                    hash: seq.expect_element()?,
+                    references: seq.expect_element()?,
+                    registration_time: seq.expect_element()?,
+                    nar_size: seq.expect_element()?,
+                    ultimate: false,
+                    sigs: StringSet::default(),
+                    content_address: OptionalContentAddress::default(),
+                };
+                if self.0.minor >= 16 {
+                    ret.ultimate = seq.expect_element()?;
+                    ret.sigs = seq.expect_element()?;
+                    ret.content_address = seq.expect_element()?;
+                }
+                Ok(ret)
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, Visitor(version))
+    }
+}
+
+/// The body of `RegisterDrvOutput`.
+///
+/// Before protocol version 1.31, this was the `DrvOutput` and the output path (without
+/// the store directory) instead of the realisation's JSON, so signatures and dependent
+/// realisations were lost.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct RegisterDrvOutput {
+    pub realisation: Realisation,
 }
 
-type RenderedContentAddress = NixString;
+impl Versioned for RegisterDrvOutput {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if version.minor >= 31 {
+            return self.realisation.serialize(serializer);
+        }
+        let mut tup = serializer.serialize_tuple(2)?;
+        tup.serialize_element(&self.realisation.id)?;
+        tup.serialize_element(&NixString::from(self.realisation.out_path.to_string()))?;
+        tup.end()
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        if version.minor >= 31 {
+            let realisation = Realisation::deserialize(deserializer)?;
+            return Ok(RegisterDrvOutput { realisation });
+        }
+
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = RegisterDrvOutput;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("RegisterDrvOutput")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<RegisterDrvOutput, A::Error> {
+                let id = seq.expect_element()?;
+                let out_path: NixString = seq.expect_element()?;
+                let out_path = ParsedStorePath::from_base_name(&out_path.0)
+                    .map_err(serde::de::Error::custom)?;
+                Ok(RegisterDrvOutput {
+                    realisation: Realisation {
+                        id,
+                        out_path,
+                        signatures: BTreeSet::new(),
+                        dependent_realisations: BTreeMap::new(),
+                    },
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple(2, Visitor)
+    }
+}
+
+/// The response to `QueryRealisation`.
+///
+/// Before protocol version 1.31, only the output paths of the realisations were sent,
+/// as full store paths. Which variant gets sent has to match the protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub enum QueryRealisationResponse {
+    Realisations(RealisationSet),
+    OutPaths(StorePathSet),
+}
+
+impl Versioned for QueryRealisationResponse {
+    fn serialize_versioned<S: serde::Serializer>(
+        &self,
+        version: DaemonVersion,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match (self, version.minor >= 31) {
+            (QueryRealisationResponse::Realisations(r), true) => r.serialize(serializer),
+            (QueryRealisationResponse::OutPaths(p), false) => p.serialize(serializer),
+            _ => Err(serde::ser::Error::custom(format!(
+                "wrong kind of QueryRealisation response for protocol version {version}"
+            ))),
+        }
+    }
+
+    fn deserialize_versioned<'de, D: serde::Deserializer<'de>>(
+        version: DaemonVersion,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        if version.minor >= 31 {
+            RealisationSet::deserialize(deserializer).map(QueryRealisationResponse::Realisations)
+        } else {
+            StorePathSet::deserialize(deserializer).map(QueryRealisationResponse::OutPaths)
+        }
+    }
+}
+
+versioned_serde!(
+    WorkerOp,
+    BuildResult,
+    ValidPathInfo,
+    QueryValidPaths,
+    QuerySubstitutablePathInfos,
+    RegisterDrvOutput,
+    QueryRealisationResponse
+);
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -461,6 +1079,13 @@ pub struct AddBuildLog {
     pub path: StorePath,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct AddPermRoot {
+    pub store_path: StorePath,
+    pub gc_root: Path,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BuildDerivation {
@@ -480,12 +1105,11 @@ pub struct Derivation {
     pub env: Vec<(NixString, NixString)>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct DerivationOutput {
-    pub store_path: StorePath,
-    pub method_or_hash: NixString,
-    pub hash_or_impure: NixString,
+impl Derivation {
+    /// Is this a fixed-output derivation? See [`derivation::is_fixed_output`].
+    pub fn is_fixed_output(&self) -> bool {
+        derivation::is_fixed_output(self.outputs.iter().map(|(name, output)| (name, output)))
+    }
 }
 
 #[cfg(test)]
@@ -496,6 +1120,7 @@ mod tests {
     use crate::{
         serialize::{NixDeserializer, NixSerializer},
         worker_op::SetOptions,
+        NixWriteExt,
     };
 
     use super::*;
@@ -521,11 +1146,11 @@ mod tests {
             )],
         };
         let mut cursor = std::io::Cursor::new(Vec::new());
-        let mut serializer = NixSerializer { write: &mut cursor };
+        let mut serializer = NixSerializer::new(&mut cursor);
         options.serialize(&mut serializer).unwrap();
 
         cursor.set_position(0);
-        let mut deserializer = NixDeserializer { read: &mut cursor };
+        let mut deserializer = NixDeserializer::new(&mut cursor);
         assert_eq!(options, SetOptions::deserialize(&mut deserializer).unwrap());
     }
 
@@ -533,12 +1158,135 @@ mod tests {
     fn test_roundtrip() {
         arbtest(|u| {
             let op: WorkerOp = u.arbitrary()?;
-            let bytes = crate::to_vec(&op).unwrap();
-            let new_op: WorkerOp = crate::from_bytes(&bytes).unwrap();
+            // The newest version that has this op, so that no fields get dropped.
+            let version = *op.versions().end();
+            let mut bytes = Vec::new();
+            bytes.write_nix_with_version(&op, version).unwrap();
+            let new_op: WorkerOp = bytes.as_slice().read_nix_with_version(version).unwrap();
 
             assert_eq!(op, new_op);
 
             Ok(())
         });
     }
+
+    #[test]
+    fn test_versions() {
+        let v = |minor| DaemonVersion { major: 1, minor };
+
+        // Opcode 7 means the legacy AddToStore before 1.25.
+        let legacy = WorkerOp::AddToStoreLegacy(
+            Plain(AddToStoreLegacy {
+                base_name: NixString::from_bytes(b"foo"),
+                fixed: true,
+                recursive: false,
+                hash_algo: NixString::from_bytes(b"sha256"),
+                nar: Nar::default(),
+            }),
+            Resp::default(),
+        );
+        assert_eq!(legacy.opcode(), 7);
+        let mut bytes = Vec::new();
+        bytes.write_nix_with_version(&legacy, v(24)).unwrap();
+        let op: WorkerOp = bytes.as_slice().read_nix_with_version(v(24)).unwrap();
+        assert_eq!(op, legacy);
+        assert!(Vec::new().write_nix_with_version(&legacy, v(25)).is_err());
+
+        // QueryDerivationOutputMap was added in 1.22.
+        let op = WorkerOp::QueryDerivationOutputMap(
+            Plain(StorePath(NixString::from_bytes(b"/nix/store/foo"))),
+            Resp::default(),
+        );
+        let mut bytes = Vec::new();
+        bytes.write_nix_with_version(&op, v(22)).unwrap();
+        assert!(bytes
+            .as_slice()
+            .read_nix_with_version::<WorkerOp>(v(21))
+            .is_err());
+    }
+
+    #[test]
+    fn test_realisations_before_1_31() {
+        let v27 = DaemonVersion {
+            major: 1,
+            minor: 27,
+        };
+        let realisation = Realisation {
+            id: "sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!out"
+                .parse()
+                .unwrap(),
+            out_path: crate::store_path::ParsedStorePath::from_base_name(
+                b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            )
+            .unwrap(),
+            signatures: Default::default(),
+            dependent_realisations: Default::default(),
+        };
+        let op = WorkerOp::RegisterDrvOutput(
+            Plain(RegisterDrvOutput {
+                realisation: realisation.clone(),
+            }),
+            Resp::default(),
+        );
+        let mut bytes = Vec::new();
+        bytes.write_nix_with_version(&op, v27).unwrap();
+        let mut expected = Vec::new();
+        expected.write_nix(&42u64).unwrap();
+        expected.write_nix(&realisation.id).unwrap();
+        expected
+            .write_nix(&NixString::from_bytes(
+                b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            ))
+            .unwrap();
+        assert_eq!(bytes, expected);
+        let new_op: WorkerOp = bytes.as_slice().read_nix_with_version(v27).unwrap();
+        assert_eq!(op, new_op);
+
+        let paths = QueryRealisationResponse::OutPaths(StorePathSet {
+            paths: vec![StorePath(NixString::from_bytes(
+                b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            ))],
+        });
+        let mut bytes = Vec::new();
+        bytes.write_nix_with_version(&paths, v27).unwrap();
+        let resp: QueryRealisationResponse = bytes.as_slice().read_nix_with_version(v27).unwrap();
+        assert_eq!(resp, paths);
+        assert!(Vec::new()
+            .write_nix_with_version(&paths, PROTOCOL_VERSION)
+            .is_err());
+        let realisations = QueryRealisationResponse::Realisations(RealisationSet {
+            realisations: vec![realisation],
+        });
+        assert!(Vec::new()
+            .write_nix_with_version(&realisations, v27)
+            .is_err());
+    }
+
+    #[test]
+    fn test_add_perm_root() {
+        let op = WorkerOp::AddPermRoot(
+            Plain(AddPermRoot {
+                store_path: StorePath(NixString::from_bytes(b"/nix/store/foo")),
+                gc_root: Path(NixString::from_bytes(b"/home/user/result")),
+            }),
+            Resp::default(),
+        );
+        assert_eq!(op.opcode(), 47);
+        assert_eq!(op.versions().start().minor, 36);
+
+        let bytes = crate::to_vec(&op).unwrap();
+        assert_eq!(&bytes[..8], &47u64.to_le_bytes());
+        let new_op: WorkerOp = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(op, new_op);
+
+        let v35 = DaemonVersion {
+            major: 1,
+            minor: 35,
+        };
+        assert!(Vec::new().write_nix_with_version(&op, v35).is_err());
+        assert!(bytes
+            .as_slice()
+            .read_nix_with_version::<WorkerOp>(v35)
+            .is_err());
+    }
 }
@@ -0,0 +1,83 @@
+-- A small store database, in the schema that nix itself creates (schema version 10,
+-- with the ca-derivations tables).
+
+create table ValidPaths (
+    id               integer primary key autoincrement not null,
+    path             text unique not null,
+    hash             text not null, -- base16 representation
+    registrationTime integer not null,
+    deriver          text,
+    narSize          integer,
+    ultimate         integer, -- null implies "false"
+    sigs             text, -- space-separated
+    ca               text -- if not null, an assertion that the path is content-addressed; see ValidPathInfo
+);
+
+create table Refs (
+    referrer  integer not null,
+    reference integer not null,
+    primary key (referrer, reference),
+    foreign key (referrer) references ValidPaths(id) on delete cascade,
+    foreign key (reference) references ValidPaths(id) on delete restrict
+);
+
+create index IndexReferrer on Refs(referrer);
+create index IndexReference on Refs(reference);
+
+create trigger DeleteSelfRefs before delete on ValidPaths
+  begin
+    delete from Refs where referrer = old.id and reference = old.id;
+  end;
+
+create table DerivationOutputs (
+    drv  integer not null,
+    id   text not null, -- symbolic output id, usually "out"
+    path text not null,
+    primary key (drv, id),
+    foreign key (drv) references ValidPaths(id) on delete cascade
+);
+
+create index IndexDerivationOutputs on DerivationOutputs(path);
+
+create table Realisations (
+    id integer primary key autoincrement not null,
+    drvPath text not null,
+    outputName text not null, -- symbolic output id, usually "out"
+    outputPath integer not null,
+    signatures text, -- space-separated list
+    foreign key (outputPath) references ValidPaths(id) on delete cascade
+);
+
+create index IndexRealisations on Realisations(drvPath, outputName);
+
+create table RealisationsRefs (
+    referrer integer not null,
+    realisationReference integer,
+    foreign key (referrer) references Realisations(id) on delete cascade,
+    foreign key (realisationReference) references Realisations(id) on delete restrict
+);
+
+create index IndexRealisationsRefs on RealisationsRefs(referrer);
+create index IndexRealisationsRefsRealisationReference on RealisationsRefs(realisationReference);
+
+insert into ValidPaths values (1, '/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39',
+    'sha256:51b8e34d2a4ad5e91c35bb16b4c6b8dbf3bdf6b9f61b1cbe1c2f0de1f4e2c1a3', 1700000000,
+    '/nix/store/9zv1hwsl5fyrz2gk59pcb0lbb5h7k5hk-glibc-2.39.drv', 29741152, null,
+    'cache.nixos.org-1:bm90IGEgcmVhbCBzaWduYXR1cmU= other-cache:c2ln', null);
+insert into ValidPaths values (2, '/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1',
+    'sha256:a6d2fef3c4a5e5f53a10c1ddbbdc4e8a6e4a5e3f6d4f7d1c0b0e2e8b1d6c3c5a', 1700000100,
+    '/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv', 226560, 1, null, null);
+insert into ValidPaths values (3, '/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv',
+    'sha256:0f2bd5b9e0c9b2df1d2a7a84ad3a6c1a4e5c61e2f7a6b73c8bb9a13a4a3f2b11', 1700000050,
+    null, 2320, 0, null, 'text:sha256:1hh2w3kzwc6rkzd06hy4qmlywwafwpl5bfxq3iwvzwfgywfg9y5n');
+
+insert into Refs values (2, 1);
+insert into Refs values (2, 2);
+
+insert into DerivationOutputs values (3, 'out', '/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1');
+insert into DerivationOutputs values (3, 'dev', '/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-hello-2.12.1-dev');
+
+insert into Realisations values (1, 'sha256:1c9c2d4f3a1f6fd5b8c2a0e6d0a3ca72ab41d3e6b0a5e3d1e9a0f4b7c6d5e4f3', 'out', 1, null);
+insert into Realisations values (2, 'sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527', 'out', 2,
+    'cache:c2ln');
+insert into RealisationsRefs values (2, 1);
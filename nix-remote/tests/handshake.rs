@@ -0,0 +1,251 @@
+use std::{
+    io::{BufReader, Cursor},
+    os::unix::net::UnixStream,
+};
+
+use nix_remote::{
+    handshake::{ClientHello, HandshakeError, ServerHello, TrustedFlag},
+    nix_client::NixDaemonClient,
+    nix_daemon_proxy::{NixDaemonProxy, ProxyConfig},
+    stderr::{Msg, StderrError},
+    DaemonVersion, Error, NixReadExt, NixString, NixWriteExt, PROTOCOL_VERSION, WORKER_MAGIC_1,
+    WORKER_MAGIC_2,
+};
+
+/// Connects a client offering `client_version` to a proxy, returning the versions
+/// that each side negotiated.
+fn handshake(client_version: DaemonVersion) -> (DaemonVersion, DaemonVersion) {
+    handshake_with_config(client_version, ProxyConfig::default())
+}
+
+fn handshake_with_config(
+    client_version: DaemonVersion,
+    config: ProxyConfig,
+) -> (DaemonVersion, DaemonVersion) {
+    let (client_sock, proxy_sock) = UnixStream::pair().unwrap();
+
+    let proxy = std::thread::spawn(move || {
+        let proxy =
+            NixDaemonProxy::new(proxy_sock.try_clone().unwrap(), proxy_sock, config).unwrap();
+        assert!(proxy.features().is_empty());
+        proxy.protocol_version()
+    });
+
+    let client = NixDaemonClient::with_version(
+        BufReader::new(client_sock.try_clone().unwrap()),
+        client_sock,
+        client_version,
+    )
+    .unwrap();
+    assert!(client.features().is_empty());
+    assert!(!client.has_feature("some-feature"));
+
+    (client.protocol_version(), proxy.join().unwrap())
+}
+
+#[test]
+fn handshake_latest() {
+    let (client, proxy) = handshake(PROTOCOL_VERSION);
+    assert_eq!(client, PROTOCOL_VERSION);
+    assert_eq!(proxy, PROTOCOL_VERSION);
+}
+
+#[test]
+fn handshake_before_features() {
+    let old = DaemonVersion {
+        major: 1,
+        minor: 21,
+    };
+    let (client, proxy) = handshake(old);
+    assert_eq!(client, old);
+    assert_eq!(proxy, old);
+}
+
+const VERSIONS: [DaemonVersion; 3] = [
+    DaemonVersion {
+        major: 1,
+        minor: 21,
+    },
+    DaemonVersion {
+        major: 1,
+        minor: 35,
+    },
+    PROTOCOL_VERSION,
+];
+
+#[test]
+fn replay_client_hello() {
+    for version in VERSIONS {
+        let client_hello = ClientHello {
+            version,
+            cpu_affinity: Some(3),
+            ..ClientHello::default()
+        };
+        let mut input = Vec::new();
+        input
+            .write_nix_with_version(&client_hello, version)
+            .unwrap();
+
+        let mut proxy =
+            NixDaemonProxy::new(Cursor::new(input), Vec::new(), ProxyConfig::default()).unwrap();
+        assert_eq!(proxy.protocol_version(), version);
+
+        let mut expected = Vec::new();
+        expected
+            .write_nix_with_version(&ServerHello::default(), version)
+            .unwrap();
+        expected.write_nix(&Msg::Last(())).unwrap();
+        assert_eq!(proxy.writer(), &expected);
+    }
+}
+
+#[test]
+fn record_client_hello() {
+    for version in VERSIONS {
+        let server_hello = ServerHello {
+            version,
+            ..ServerHello::default()
+        };
+        let mut input = Vec::new();
+        input
+            .write_nix_with_version(&server_hello, version)
+            .unwrap();
+        input.write_nix(&Msg::Last(())).unwrap();
+
+        let mut client = NixDaemonClient::new(Cursor::new(input), Vec::new()).unwrap();
+        assert_eq!(client.protocol_version(), version);
+
+        let mut expected = Vec::new();
+        expected
+            .write_nix_with_version(&ClientHello::default(), version)
+            .unwrap();
+        assert_eq!(client.writer(), &expected);
+    }
+}
+
+#[test]
+fn proxy_config() {
+    let config = ProxyConfig {
+        daemon_version: NixString::from_bytes(b"test-proxy"),
+        trust: TrustedFlag::NotTrusted,
+        ..ProxyConfig::default()
+    };
+    let mut input = Vec::new();
+    input.write_nix(&ClientHello::default()).unwrap();
+
+    let mut proxy = NixDaemonProxy::new(Cursor::new(input), Vec::new(), config.clone()).unwrap();
+    assert_eq!(proxy.trust(), TrustedFlag::NotTrusted);
+
+    let server_hello: ServerHello = proxy.writer().as_slice().read_nix().unwrap();
+    assert_eq!(server_hello.daemon_version, config.daemon_version);
+    assert_eq!(server_hello.trusted, TrustedFlag::NotTrusted);
+
+    // Replay the proxy's side of the handshake to a client.
+    let client = NixDaemonClient::new(Cursor::new(proxy.writer().clone()), Vec::new()).unwrap();
+    assert_eq!(client.trusted(), TrustedFlag::NotTrusted);
+}
+
+#[test]
+fn proxy_capped_version() {
+    let capped = DaemonVersion {
+        major: 1,
+        minor: 34,
+    };
+    let config = ProxyConfig {
+        protocol_version: capped,
+        ..ProxyConfig::default()
+    };
+    let (client, proxy) = handshake_with_config(PROTOCOL_VERSION, config);
+    assert_eq!(client, capped);
+    assert_eq!(proxy, capped);
+}
+
+fn proxy_error(input: Vec<u8>) -> (HandshakeError, Vec<u8>) {
+    let mut output = Vec::new();
+    let err = NixDaemonProxy::new(Cursor::new(input), &mut output, ProxyConfig::default())
+        .err()
+        .unwrap();
+    let Error::Handshake(err) = err else {
+        panic!("expected a handshake error, got {err}");
+    };
+    (err, output)
+}
+
+fn client_error(input: Vec<u8>) -> HandshakeError {
+    let err = NixDaemonClient::new(Cursor::new(input), Vec::new())
+        .err()
+        .unwrap();
+    let Error::Handshake(err) = err else {
+        panic!("expected a handshake error, got {err}");
+    };
+    err
+}
+
+#[test]
+fn proxy_bad_magic() {
+    let (err, output) = proxy_error(nix_remote::to_vec(&0x1234u64).unwrap());
+    assert!(matches!(
+        err,
+        HandshakeError::BadMagic {
+            expected: WORKER_MAGIC_1,
+            got: 0x1234
+        }
+    ));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn proxy_eof() {
+    let (err, _) = proxy_error(Vec::new());
+    assert!(matches!(err, HandshakeError::Eof));
+}
+
+#[test]
+fn proxy_unsupported_version() {
+    let old = DaemonVersion {
+        major: 1,
+        minor: 20,
+    };
+    let input = nix_remote::to_vec(&(WORKER_MAGIC_1, old)).unwrap();
+    let (err, output) = proxy_error(input);
+    assert!(matches!(err, HandshakeError::UnsupportedVersion(v) if v == old));
+
+    // The client gets told why, in the layout that it understands.
+    let mut output = Cursor::new(output);
+    let (_magic, _version): (u64, DaemonVersion) = output.read_nix().unwrap();
+    let msg: Msg = output.read_nix_with_version(old).unwrap();
+    let Msg::Error(e) = msg else {
+        panic!("expected an error, got {msg:?}");
+    };
+    assert_eq!(e.message, "unsupported protocol version 1.20");
+}
+
+#[test]
+fn client_bad_magic() {
+    let err = client_error(nix_remote::to_vec(&WORKER_MAGIC_1).unwrap());
+    assert!(matches!(
+        err,
+        HandshakeError::BadMagic {
+            expected: WORKER_MAGIC_2,
+            got: WORKER_MAGIC_1
+        }
+    ));
+}
+
+#[test]
+fn client_eof() {
+    let mut input = Vec::new();
+    input.write_nix(&ServerHello::default()).unwrap();
+    assert!(matches!(client_error(input), HandshakeError::Eof));
+}
+
+#[test]
+fn client_daemon_error() {
+    let mut input = Vec::new();
+    input.write_nix(&ServerHello::default()).unwrap();
+    input
+        .write_nix(&Msg::Error(StderrError::new("no thanks")))
+        .unwrap();
+    let err = client_error(input);
+    assert!(matches!(err, HandshakeError::Daemon(e) if e.message == "no thanks"));
+}
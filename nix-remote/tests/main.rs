@@ -2,9 +2,12 @@ use std::{collections::BTreeSet, io::Cursor};
 
 use expect_test::{expect, Expect};
 use nix_remote::{
+    content_address::{ContentAddress, OptionalContentAddress},
+    handshake::TrustedFlag,
+    realisation::DrvOutput,
     serialize::{NixReadExt, NixWriteExt},
     worker_op::{BuildMode, BuildResult},
-    DerivedPath, NixString, Realisation, StorePath, ValidPathInfoWithPath,
+    DaemonVersion, DerivedPath, NixString, Realisation, StorePath, ValidPathInfoWithPath,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
@@ -22,6 +25,24 @@ fn check<T: DeserializeOwned + Serialize + std::fmt::Debug>(data: &[u8], expect:
     assert_eq!(&out, data);
 }
 
+/// Like `check`, but for data in the layout of a specific protocol version.
+fn check_versioned<T: DeserializeOwned + Serialize + std::fmt::Debug>(
+    data: &[u8],
+    minor: u8,
+    expect: Expect,
+) {
+    let version = DaemonVersion { major: 1, minor };
+    let mut read = Cursor::new(data);
+    let actual: T = read.read_nix_with_version(version).unwrap();
+
+    let mut out = Vec::new();
+    out.write_nix_with_version(&actual, version).unwrap();
+
+    expect.assert_debug_eq(&actual);
+
+    assert_eq!(&out, data);
+}
+
 #[test]
 fn string() {
     // This is a bit different from the test in CppNix; they have
@@ -58,26 +79,115 @@ fn store_path() {
     );
 }
 
-// CppNix has a ContentAddress test here, but we don't yet have a ContentAddress type.
+#[test]
+fn content_address() {
+    check::<(ContentAddress, ContentAddress, ContentAddress)>(
+        include_bytes!("data/worker-protocol/content-address.bin"),
+        expect![[r#"
+            (
+                Text(
+                    Hash(sha256:1vb2xd67750jrvqgykxrf5ipl3h1q57sxc57q5nzjw56bqp3sxzr),
+                ),
+                Flat(
+                    Hash(sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0),
+                ),
+                Recursive(
+                    Hash(sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh),
+                ),
+            )
+        "#]],
+    );
+}
 
 #[test]
-fn derived_path() {
-    check::<(DerivedPath, DerivedPath, DerivedPath, DerivedPath)>(
-        include_bytes!("data/worker-protocol/derived-path-1.30.bin"),
+fn optional_content_address() {
+    check::<(OptionalContentAddress, OptionalContentAddress)>(
+        include_bytes!("data/worker-protocol/optional-content-address.bin"),
         expect![[r#"
             (
-                DerivedPath(
-                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo,
+                OptionalContentAddress(
+                    None,
+                ),
+                OptionalContentAddress(
+                    Some(
+                        Flat(
+                            Hash(sha1:1amk6n7wxjlw4qkq6dcj6mm7hw3acrw0),
+                        ),
+                    ),
                 ),
-                DerivedPath(
-                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv,
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn derived_path_1_29() {
+    check_versioned::<(DerivedPath, DerivedPath, DerivedPath)>(
+        include_bytes!("data/worker-protocol/derived-path-1.29.bin"),
+        29,
+        expect![[r#"
+            (
+                Opaque(
+                    StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo,
+                    ),
                 ),
-                DerivedPath(
-                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv!*,
+                Built {
+                    drv_path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                    ),
+                    outputs: All,
+                },
+                Built {
+                    drv_path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                    ),
+                    outputs: Names(
+                        {
+                            x,
+                            y,
+                        },
+                    ),
+                },
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn derived_path_1_30() {
+    check_versioned::<(DerivedPath, DerivedPath, DerivedPath, DerivedPath)>(
+        include_bytes!("data/worker-protocol/derived-path-1.30.bin"),
+        30,
+        expect![[r#"
+            (
+                Opaque(
+                    StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo,
+                    ),
                 ),
-                DerivedPath(
-                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv!x,y,
+                Opaque(
+                    StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv,
+                    ),
                 ),
+                Built {
+                    drv_path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                    ),
+                    outputs: All,
+                },
+                Built {
+                    drv_path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                    ),
+                    outputs: Names(
+                        {
+                            x,
+                            y,
+                        },
+                    ),
+                },
             )
         "#]],
     );
@@ -85,14 +195,18 @@ fn derived_path() {
 
 #[test]
 fn drv_output() {
-    // CppNix uses DrvOutput as the type, but we don't have it and it's just a NixString
-    // on the wire anyway.
-    check::<(NixString, NixString)>(
+    check::<(DrvOutput, DrvOutput)>(
         include_bytes!("data/worker-protocol/drv-output.bin"),
         expect![[r#"
             (
-                sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!baz,
-                sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!quux,
+                DrvOutput {
+                    drv_hash: Hash(sha256:09ymwqf5i9q7d4dm7x4pjjcqqj0qrcp5lnznbh42gfsci5hcbqqm),
+                    output_name: "baz",
+                },
+                DrvOutput {
+                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                    output_name: "quux",
+                },
             )
         "#]],
     );
@@ -104,21 +218,178 @@ fn realisation() {
         include_bytes!("data/worker-protocol/realisation.bin"),
         expect![[r#"
             (
-                Realisation(
-                    {"dependentRealisations":{},"id":"sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!baz","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","signatures":["asdf","qwer"]},
-                ),
-                Realisation(
-                    {"dependentRealisations":{"sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!quux":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"},"id":"sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!baz","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","signatures":["asdf","qwer"]},
-                ),
+                Realisation {
+                    id: DrvOutput {
+                        drv_hash: Hash(sha256:09ymwqf5i9q7d4dm7x4pjjcqqj0qrcp5lnznbh42gfsci5hcbqqm),
+                        output_name: "baz",
+                    },
+                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                    signatures: {
+                        "asdf",
+                        "qwer",
+                    },
+                    dependent_realisations: {},
+                },
+                Realisation {
+                    id: DrvOutput {
+                        drv_hash: Hash(sha256:09ymwqf5i9q7d4dm7x4pjjcqqj0qrcp5lnznbh42gfsci5hcbqqm),
+                        output_name: "baz",
+                    },
+                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                    signatures: {
+                        "asdf",
+                        "qwer",
+                    },
+                    dependent_realisations: {
+                        DrvOutput {
+                            drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                            output_name: "quux",
+                        }: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                    },
+                },
             )
         "#]],
     );
 }
 
 #[test]
-fn build_result() {
-    check::<(BuildResult, BuildResult, BuildResult)>(
+fn build_result_1_27() {
+    check_versioned::<(BuildResult, BuildResult, BuildResult)>(
+        include_bytes!("data/worker-protocol/build-result-1.27.bin"),
+        27,
+        expect![[r#"
+            (
+                BuildResult {
+                    status: OutputRejected,
+                    error_msg: no idea why,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: NotDeterministic,
+                    error_msg: no idea why,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: Built,
+                    error_msg: ,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn build_result_1_28() {
+    check_versioned::<(BuildResult, BuildResult, BuildResult)>(
+        include_bytes!("data/worker-protocol/build-result-1.28.bin"),
+        28,
+        expect![[r#"
+            (
+                BuildResult {
+                    status: OutputRejected,
+                    error_msg: no idea why,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: NotDeterministic,
+                    error_msg: no idea why,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: Built,
+                    error_msg: ,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [
+                            (
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "bar",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "bar",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
+                            ),
+                            (
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "foo",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "foo",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
+                            ),
+                        ],
+                    ),
+                },
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn build_result_1_29() {
+    check_versioned::<(BuildResult, BuildResult, BuildResult)>(
         include_bytes!("data/worker-protocol/build-result-1.29.bin"),
+        29,
         expect![[r#"
             (
                 BuildResult {
@@ -128,6 +399,8 @@ fn build_result() {
                     is_non_deterministic: false,
                     start_time: 0,
                     stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
                     built_outputs: DrvOutputs(
                         [],
                     ),
@@ -139,6 +412,8 @@ fn build_result() {
                     is_non_deterministic: true,
                     start_time: 30,
                     stop_time: 50,
+                    cpu_user: None,
+                    cpu_system: None,
                     built_outputs: DrvOutputs(
                         [],
                     ),
@@ -150,19 +425,125 @@ fn build_result() {
                     is_non_deterministic: false,
                     start_time: 30,
                     stop_time: 50,
+                    cpu_user: None,
+                    cpu_system: None,
                     built_outputs: DrvOutputs(
                         [
                             (
-                                sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!bar,
-                                Realisation(
-                                    {"dependentRealisations":{},"id":"sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!bar","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar","signatures":[]},
-                                ),
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "bar",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "bar",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
                             ),
                             (
-                                sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!foo,
-                                Realisation(
-                                    {"dependentRealisations":{},"id":"sha256:6f869f9ea2823bda165e06076fd0de4366dead2c0e8d2dbbad277d4f15c373f5!foo","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","signatures":[]},
-                                ),
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "foo",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "foo",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
+                            ),
+                        ],
+                    ),
+                },
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn build_result_1_37() {
+    check_versioned::<(BuildResult, BuildResult, BuildResult)>(
+        include_bytes!("data/worker-protocol/build-result-1.37.bin"),
+        37,
+        expect![[r#"
+            (
+                BuildResult {
+                    status: OutputRejected,
+                    error_msg: no idea why,
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: NotDeterministic,
+                    error_msg: no idea why,
+                    times_built: 3,
+                    is_non_deterministic: true,
+                    start_time: 30,
+                    stop_time: 50,
+                    cpu_user: None,
+                    cpu_system: None,
+                    built_outputs: DrvOutputs(
+                        [],
+                    ),
+                },
+                BuildResult {
+                    status: Built,
+                    error_msg: ,
+                    times_built: 1,
+                    is_non_deterministic: false,
+                    start_time: 30,
+                    stop_time: 50,
+                    cpu_user: Some(
+                        500000000,
+                    ),
+                    cpu_system: Some(
+                        604000000,
+                    ),
+                    built_outputs: DrvOutputs(
+                        [
+                            (
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "bar",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "bar",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
+                            ),
+                            (
+                                DrvOutput {
+                                    drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                    output_name: "foo",
+                                },
+                                Realisation {
+                                    id: DrvOutput {
+                                        drv_hash: Hash(sha256:1xbkqcalyz97mnxjv38f5jnxwrj3vv86y1q6bqbdlfw2lag9z1kg),
+                                        output_name: "foo",
+                                    },
+                                    out_path: ParsedStorePath(g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo),
+                                    signatures: {},
+                                    dependent_realisations: {},
+                                },
                             ),
                         ],
                     ),
@@ -175,13 +556,16 @@ fn build_result() {
 type KeyedBuildResult = (DerivedPath, BuildResult);
 #[test]
 fn keyed_build_result() {
-    check::<(KeyedBuildResult, KeyedBuildResult)>(
+    check_versioned::<(KeyedBuildResult, KeyedBuildResult)>(
         include_bytes!("data/worker-protocol/keyed-build-result-1.29.bin"),
+        29,
         expect![[r#"
             (
                 (
-                    DerivedPath(
-                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-xxx,
+                    Opaque(
+                        StorePath(
+                            /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-xxx,
+                        ),
                     ),
                     BuildResult {
                         status: OutputRejected,
@@ -190,15 +574,24 @@ fn keyed_build_result() {
                         is_non_deterministic: false,
                         start_time: 0,
                         stop_time: 0,
+                        cpu_user: None,
+                        cpu_system: None,
                         built_outputs: DrvOutputs(
                             [],
                         ),
                     },
                 ),
                 (
-                    DerivedPath(
-                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv!out,
-                    ),
+                    Built {
+                        drv_path: StorePath(
+                            /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                        ),
+                        outputs: Names(
+                            {
+                                out,
+                            },
+                        ),
+                    },
                     BuildResult {
                         status: NotDeterministic,
                         error_msg: no idea why,
@@ -206,6 +599,8 @@ fn keyed_build_result() {
                         is_non_deterministic: true,
                         start_time: 30,
                         stop_time: 50,
+                        cpu_user: None,
+                        cpu_system: None,
                         built_outputs: DrvOutputs(
                             [],
                         ),
@@ -217,13 +612,213 @@ fn keyed_build_result() {
 }
 
 #[test]
-fn valid_path_info() {
-    check::<(
+fn valid_path_info_1_15() {
+    check_versioned::<(ValidPathInfoWithPath, ValidPathInfoWithPath)>(
+        include_bytes!("data/worker-protocol/valid-path-info-1.15.bin"),
+        15,
+        expect![[r#"
+            (
+                ValidPathInfoWithPath {
+                    path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar,
+                    ),
+                    info: ValidPathInfo {
+                        deriver: StorePath(
+                            ,
+                        ),
+                        hash: NarHash {
+                            data: [
+                                49,
+                                53,
+                                101,
+                                51,
+                                99,
+                                53,
+                                54,
+                                48,
+                                56,
+                                57,
+                                52,
+                                99,
+                                98,
+                                98,
+                                50,
+                                55,
+                                48,
+                                56,
+                                53,
+                                99,
+                                102,
+                                54,
+                                53,
+                                98,
+                                53,
+                                97,
+                                50,
+                                101,
+                                99,
+                                98,
+                                49,
+                                56,
+                                52,
+                                56,
+                                56,
+                                99,
+                                57,
+                                57,
+                                57,
+                                52,
+                                57,
+                                55,
+                                102,
+                                52,
+                                53,
+                                51,
+                                49,
+                                98,
+                                54,
+                                57,
+                                48,
+                                55,
+                                97,
+                                55,
+                                53,
+                                56,
+                                49,
+                                99,
+                                101,
+                                54,
+                                100,
+                                53,
+                                50,
+                                55,
+                            ],
+                        },
+                        references: StorePathSet {
+                            paths: [],
+                        },
+                        registration_time: 23423,
+                        nar_size: 34878,
+                        ultimate: false,
+                        sigs: StringSet {
+                            paths: [],
+                        },
+                        content_address: OptionalContentAddress(
+                            None,
+                        ),
+                    },
+                },
+                ValidPathInfoWithPath {
+                    path: StorePath(
+                        /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar,
+                    ),
+                    info: ValidPathInfo {
+                        deriver: StorePath(
+                            /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                        ),
+                        hash: NarHash {
+                            data: [
+                                49,
+                                53,
+                                101,
+                                51,
+                                99,
+                                53,
+                                54,
+                                48,
+                                56,
+                                57,
+                                52,
+                                99,
+                                98,
+                                98,
+                                50,
+                                55,
+                                48,
+                                56,
+                                53,
+                                99,
+                                102,
+                                54,
+                                53,
+                                98,
+                                53,
+                                97,
+                                50,
+                                101,
+                                99,
+                                98,
+                                49,
+                                56,
+                                52,
+                                56,
+                                56,
+                                99,
+                                57,
+                                57,
+                                57,
+                                52,
+                                57,
+                                55,
+                                102,
+                                52,
+                                53,
+                                51,
+                                49,
+                                98,
+                                54,
+                                57,
+                                48,
+                                55,
+                                97,
+                                55,
+                                53,
+                                56,
+                                49,
+                                99,
+                                101,
+                                54,
+                                100,
+                                53,
+                                50,
+                                55,
+                            ],
+                        },
+                        references: StorePathSet {
+                            paths: [
+                                StorePath(
+                                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar,
+                                ),
+                                StorePath(
+                                    /nix/store/g1w7hyyyy1w7hy3qg1w7hy3qgqqqqy3q-foo,
+                                ),
+                            ],
+                        },
+                        registration_time: 23423,
+                        nar_size: 34878,
+                        ultimate: false,
+                        sigs: StringSet {
+                            paths: [],
+                        },
+                        content_address: OptionalContentAddress(
+                            None,
+                        ),
+                    },
+                },
+            )
+        "#]],
+    );
+}
+
+#[test]
+fn valid_path_info_1_16() {
+    check_versioned::<(
         ValidPathInfoWithPath,
         ValidPathInfoWithPath,
         ValidPathInfoWithPath,
     )>(
         include_bytes!("data/worker-protocol/valid-path-info-1.16.bin"),
+        16,
         expect![[r#"
             (
                 ValidPathInfoWithPath {
@@ -311,7 +906,9 @@ fn valid_path_info() {
                         sigs: StringSet {
                             paths: [],
                         },
-                        content_address: ,
+                        content_address: OptionalContentAddress(
+                            None,
+                        ),
                     },
                 },
                 ValidPathInfoWithPath {
@@ -409,7 +1006,9 @@ fn valid_path_info() {
                                 fake-sig-2,
                             ],
                         },
-                        content_address: ,
+                        content_address: OptionalContentAddress(
+                            None,
+                        ),
                     },
                 },
                 ValidPathInfoWithPath {
@@ -504,7 +1103,13 @@ fn valid_path_info() {
                         sigs: StringSet {
                             paths: [],
                         },
-                        content_address: fixed:r:sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh,
+                        content_address: OptionalContentAddress(
+                            Some(
+                                Recursive(
+                                    Hash(sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh),
+                                ),
+                            ),
+                        ),
                     },
                 },
             )
@@ -526,7 +1131,19 @@ fn build_mode() {
     );
 }
 
-// TODO: TrustedFlag (which is needed for protocol version 35)
+#[test]
+fn optional_trusted_flag() {
+    check::<(TrustedFlag, TrustedFlag, TrustedFlag)>(
+        include_bytes!("data/worker-protocol/optional-trusted-flag.bin"),
+        expect![[r#"
+            (
+                Unknown,
+                Trusted,
+                NotTrusted,
+            )
+        "#]],
+    );
+}
 
 #[test]
 fn vector() {
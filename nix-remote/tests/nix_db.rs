@@ -0,0 +1,135 @@
+use nix_remote::{
+    nix_db::NixDb, realisation::DrvOutput, worker_op::DerivationOutputMap, NixString, StorePath,
+    StorePathSet,
+};
+use tempfile::TempDir;
+
+const GLIBC: &str = "/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39";
+const HELLO: &str = "/nix/store/fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1";
+const HELLO_DEV: &str = "/nix/store/jbyxmkhkw4r4rzx8pm7xpqgswcrkn9w3-hello-2.12.1-dev";
+const HELLO_DRV: &str = "/nix/store/x7vnk8ac2l2h2hifqa3qbhyk6dy1ix2z-hello-2.12.1.drv";
+
+fn path(p: &str) -> StorePath {
+    StorePath(NixString::from_bytes(p.as_bytes()))
+}
+
+fn paths(ps: &[&str]) -> StorePathSet {
+    StorePathSet {
+        paths: ps.iter().map(|p| path(p)).collect(),
+    }
+}
+
+/// Creates the fixture database (with nix's own schema) and opens it. The
+/// database is removed along with the returned directory.
+fn open() -> (TempDir, NixDb) {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("db.sqlite");
+    let conn = rusqlite::Connection::open(&file).unwrap();
+    conn.execute_batch(include_str!("data/nix-db/db.sql"))
+        .unwrap();
+    drop(conn);
+    let db = NixDb::open(&file).unwrap();
+    (dir, db)
+}
+
+#[test]
+fn path_info() {
+    let (_dir, db) = open();
+    let info = db.path_info(&path(HELLO)).unwrap().unwrap();
+    assert_eq!(
+        info.hash.data.as_slice(),
+        b"a6d2fef3c4a5e5f53a10c1ddbbdc4e8a6e4a5e3f6d4f7d1c0b0e2e8b1d6c3c5a"
+    );
+    assert_eq!(info.deriver, path(HELLO_DRV));
+    assert_eq!(info.references, paths(&[GLIBC, HELLO]));
+    assert_eq!(info.registration_time, 1700000100);
+    assert_eq!(info.nar_size, 226560);
+    assert!(info.ultimate);
+    assert!(info.sigs.paths.is_empty());
+    assert_eq!(info.content_address.0, None);
+
+    let info = db.path_info(&path(GLIBC)).unwrap().unwrap();
+    assert!(!info.ultimate);
+    assert_eq!(info.sigs.paths.len(), 2);
+    assert_eq!(
+        info.sigs.paths[1],
+        NixString::from_bytes(b"other-cache:c2ln")
+    );
+
+    let info = db.path_info(&path(HELLO_DRV)).unwrap().unwrap();
+    assert_eq!(info.deriver, path(""));
+    assert_eq!(
+        info.content_address.0.unwrap().to_string(),
+        "text:sha256:1hh2w3kzwc6rkzd06hy4qmlywwafwpl5bfxq3iwvzwfgywfg9y5n"
+    );
+
+    assert!(db.path_info(&path(HELLO_DEV)).unwrap().is_none());
+    assert!(db.is_valid(&path(HELLO)).unwrap());
+    assert!(!db.is_valid(&path(HELLO_DEV)).unwrap());
+}
+
+#[test]
+fn references() {
+    let (_dir, db) = open();
+    assert_eq!(db.references(&path(HELLO)).unwrap(), paths(&[GLIBC, HELLO]));
+    assert_eq!(db.referrers(&path(GLIBC)).unwrap(), paths(&[HELLO]));
+    assert_eq!(db.referrers(&path(HELLO_DRV)).unwrap(), paths(&[]));
+    assert_eq!(
+        db.all_valid_paths().unwrap(),
+        paths(&[GLIBC, HELLO, HELLO_DRV])
+    );
+    assert_eq!(
+        db.path_from_hash_part(b"/nix/store", b"fxq6wwh8xypah2kly1q8akh4ivzdw1vl")
+            .unwrap(),
+        Some(path(HELLO))
+    );
+    assert_eq!(
+        db.path_from_hash_part(b"/nix/store", b"fxq6wwh8xypah2kly1q8akh4ivzdw1vm")
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn derivations() {
+    let (_dir, db) = open();
+    assert_eq!(
+        db.valid_derivers(&path(HELLO)).unwrap(),
+        paths(&[HELLO_DRV])
+    );
+    assert_eq!(db.valid_derivers(&path(GLIBC)).unwrap(), paths(&[]));
+    assert_eq!(
+        db.derivation_output_map(&path(HELLO_DRV)).unwrap(),
+        DerivationOutputMap {
+            paths: vec![
+                (NixString::from_bytes(b"dev"), path(HELLO_DEV)),
+                (NixString::from_bytes(b"out"), path(HELLO)),
+            ]
+        }
+    );
+}
+
+#[test]
+fn realisation() {
+    let (_dir, db) = open();
+    let id = "sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!out";
+    let realisation = db.realisation(&id.parse().unwrap()).unwrap().unwrap();
+    let json: serde_json::Value = serde_json::from_str(&realisation.to_json()).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "id": id,
+            "outPath": "fxq6wwh8xypah2kly1q8akh4ivzdw1vl-hello-2.12.1",
+            "signatures": ["cache:c2ln"],
+            "dependentRealisations": {
+                "sha256:1c9c2d4f3a1f6fd5b8c2a0e6d0a3ca72ab41d3e6b0a5e3d1e9a0f4b7c6d5e4f3!out":
+                    "3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.39",
+            },
+        })
+    );
+    let other = DrvOutput {
+        output_name: "dev".to_owned(),
+        ..realisation.id
+    };
+    assert!(db.realisation(&other).unwrap().is_none());
+}
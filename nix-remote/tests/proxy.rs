@@ -0,0 +1,95 @@
+use std::io::Cursor;
+
+use nix_remote::{
+    handshake::{ClientHello, ServerHello, TrustedFlag},
+    nix_daemon_proxy::{NixDaemonProxy, ProxyConfig},
+    stderr::Msg,
+    worker_op::{AddMultipleToStore, Resp, WithFramedSource, WorkerOp},
+    DaemonVersion, Error, NixReadExt, NixWriteExt,
+};
+
+/// Sends `opcode` to a proxy (after the handshake), and checks that the proxy rejects it.
+/// Returns the message that the proxy sent to the client.
+fn reject(version: DaemonVersion, opcode: u64) -> Msg {
+    let client_hello = ClientHello {
+        version,
+        ..ClientHello::default()
+    };
+    let mut input = Vec::new();
+    input
+        .write_nix_with_version(&client_hello, version)
+        .unwrap();
+    input.write_nix(&opcode).unwrap();
+
+    let mut proxy =
+        NixDaemonProxy::new(Cursor::new(input), Vec::new(), ProxyConfig::default()).unwrap();
+    let err = proxy.receive_next_op_from_client().unwrap_err();
+    assert!(matches!(err, Error::UnknownOp(op) if op == opcode));
+
+    let mut output = Cursor::new(std::mem::take(proxy.writer()));
+    let _: ServerHello = output.read_nix_with_version(version).unwrap();
+    let last: Msg = output.read_nix().unwrap();
+    assert_eq!(last, Msg::Last(()));
+    let msg = output.read_nix_with_version(version).unwrap();
+    assert_eq!(output.position() as usize, output.get_ref().len());
+    msg
+}
+
+#[test]
+fn unknown_op() {
+    let version = nix_remote::PROTOCOL_VERSION;
+    let Msg::Error(e) = reject(version, 1234) else {
+        panic!("expected an error");
+    };
+    assert_eq!(e.message, "unsupported operation 1234");
+}
+
+#[test]
+fn op_too_new() {
+    // AddPermRoot needs protocol version 1.36.
+    let version = DaemonVersion {
+        major: 1,
+        minor: 35,
+    };
+    let Msg::Error(e) = reject(version, 47) else {
+        panic!("expected an error");
+    };
+    assert_eq!(e.message, "unsupported operation 47");
+}
+
+#[test]
+fn untrusted_check_sigs() {
+    let version = nix_remote::PROTOCOL_VERSION;
+    let mut input = Vec::new();
+    input
+        .write_nix_with_version(&ClientHello::default(), version)
+        .unwrap();
+    let op = WorkerOp::AddMultipleToStore(
+        WithFramedSource(AddMultipleToStore {
+            repair: false,
+            dont_check_sigs: true,
+        }),
+        Resp::default(),
+    );
+    input.write_nix_with_version(&op, version).unwrap();
+
+    // Like nix-daemon, we don't let untrusted clients skip signature checks. A client
+    // that we don't know to be trusted isn't trusted.
+    for (trust, dont_check_sigs) in [
+        (TrustedFlag::Trusted, true),
+        (TrustedFlag::NotTrusted, false),
+        (TrustedFlag::Unknown, false),
+    ] {
+        let config = ProxyConfig {
+            trust,
+            ..ProxyConfig::default()
+        };
+        let mut proxy =
+            NixDaemonProxy::new(Cursor::new(input.clone()), Vec::new(), config).unwrap();
+        let WorkerOp::AddMultipleToStore(op, _) = proxy.receive_next_op_from_client().unwrap()
+        else {
+            panic!("expected AddMultipleToStore");
+        };
+        assert_eq!(op.0.dont_check_sigs, dont_check_sigs, "{trust:?}");
+    }
+}
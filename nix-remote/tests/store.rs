@@ -0,0 +1,492 @@
+use std::{
+    io::{BufReader, Read},
+    os::unix::net::UnixStream,
+    thread::JoinHandle,
+};
+
+use nix_remote::{
+    content_address::{ContentAddress, OptionalContentAddress},
+    framed_data::FramedData,
+    handshake::TrustedFlag,
+    hash::{Hash, HashAlgorithm},
+    nar::{Nar, NarFile},
+    nix_client::NixDaemonClient,
+    nix_daemon_proxy::ProxyConfig,
+    signature::{self, SecretKey},
+    stderr::Msg,
+    store::{serve, serve_with_config, InMemoryStore, Store},
+    store_path::{StoreDir, StoreReferences},
+    worker_op::{
+        AddBuildLog, AddMultipleToStore, AddPermRoot, AddToStoreNar, Plain, QueryPathInfoResponse,
+        Resp, ValidPathInfo, WithFramedSource, WorkerOp,
+    },
+    NarHash, NixString, Path, Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+/// A store that knows one path, and remembers the start of the last build log.
+#[derive(Default)]
+struct TinyStore {
+    log_start: Vec<u8>,
+}
+
+fn path(name: &str) -> StorePath {
+    StorePath(NixString::from_bytes(name.as_bytes()))
+}
+
+impl Store for TinyStore {
+    fn is_valid_path(&mut self, p: StorePath) -> Result<bool> {
+        Ok(p == path("/nix/store/known"))
+    }
+
+    fn add_build_log(&mut self, _path: StorePath, log: &mut dyn Read) -> Result<u64> {
+        // Only read part of the log; the rest needs to be skipped by `serve`.
+        let mut buf = [0; 3];
+        log.read_exact(&mut buf)?;
+        self.log_start = buf.to_vec();
+        Ok(1)
+    }
+}
+
+type Client = NixDaemonClient<BufReader<UnixStream>, UnixStream>;
+
+fn start() -> (Client, JoinHandle<TinyStore>) {
+    start_with_config(ProxyConfig::default())
+}
+
+fn start_with_config(config: ProxyConfig) -> (Client, JoinHandle<TinyStore>) {
+    start_store(TinyStore::default(), config)
+}
+
+fn start_store<S: Store + Send + 'static>(
+    mut store: S,
+    config: ProxyConfig,
+) -> (Client, JoinHandle<S>) {
+    let (client_sock, store_sock) = UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || {
+        serve_with_config(
+            &mut store,
+            store_sock.try_clone().unwrap(),
+            store_sock,
+            config,
+        )
+        .unwrap();
+        store
+    });
+    let client = NixDaemonClient::new(
+        BufReader::new(client_sock.try_clone().unwrap()),
+        client_sock,
+    )
+    .unwrap();
+    (client, server)
+}
+
+fn is_valid_path(client: &mut Client, p: &str) -> bool {
+    let resp = Resp::default();
+    let op = WorkerOp::IsValidPath(Plain(path(p)), resp);
+    client.send_worker_op_to_daemon(&op).unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    client.read_build_response_from_daemon(&resp).unwrap()
+}
+
+#[test]
+fn query() {
+    let (mut client, server) = start();
+    assert!(is_valid_path(&mut client, "/nix/store/known"));
+    assert!(!is_valid_path(&mut client, "/nix/store/unknown"));
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn unsupported() {
+    let (mut client, server) = start();
+    let op = WorkerOp::QueryValidDerivers(Plain(path("/nix/store/known")), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    let Msg::Error(e) = client.read_error_msg().unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(
+        e.message,
+        "QueryValidDerivers is not supported by this store"
+    );
+
+    // The connection is still usable.
+    assert!(is_valid_path(&mut client, "/nix/store/known"));
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn untrusted_perm_root() {
+    let (mut client, server) = start_with_config(ProxyConfig {
+        trust: TrustedFlag::NotTrusted,
+        ..ProxyConfig::default()
+    });
+    let op = WorkerOp::AddPermRoot(
+        Plain(AddPermRoot {
+            store_path: path("/nix/store/known"),
+            gc_root: Path(NixString::from_bytes(b"/etc/result")),
+        }),
+        Resp::default(),
+    );
+    client.send_worker_op_to_daemon(&op).unwrap();
+    let Msg::Error(e) = client.read_error_msg().unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(e.message, "you are not privileged to create perm roots");
+
+    assert!(is_valid_path(&mut client, "/nix/store/known"));
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn framed_source() {
+    let (mut client, server) = start();
+    let resp = Resp::default();
+    let op = WorkerOp::AddBuildLog(
+        WithFramedSource(AddBuildLog {
+            path: path("/nix/store/known"),
+        }),
+        resp,
+    );
+    client.send_worker_op_to_daemon(&op).unwrap();
+    let log = FramedData {
+        data: vec![
+            ByteBuf::from(b"bu".to_vec()),
+            ByteBuf::from(b"ild log".to_vec()),
+        ],
+    };
+    log.write(client.writer()).unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    assert_eq!(client.read_build_response_from_daemon(&resp).unwrap(), 1);
+
+    assert!(is_valid_path(&mut client, "/nix/store/known"));
+    drop(client);
+    assert_eq!(server.join().unwrap().log_start, b"bui");
+}
+
+#[test]
+fn in_memory() {
+    let (client_sock, store_sock) = UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut store = InMemoryStore::new();
+        serve(&mut store, store_sock.try_clone().unwrap(), store_sock).unwrap();
+    });
+    let mut client = NixDaemonClient::new(
+        BufReader::new(client_sock.try_clone().unwrap()),
+        client_sock,
+    )
+    .unwrap();
+
+    let nar = Nar::Contents(NarFile {
+        contents: NixString::from_bytes(b"hello"),
+        executable: false,
+    });
+    let nar_bytes = nix_remote::to_vec(&nar).unwrap();
+    let nar_hash: String = Sha256::digest(&nar_bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let hello = path("/nix/store/00000000000000000000000000000000-hello");
+    let op = WorkerOp::AddToStoreNar(
+        WithFramedSource(AddToStoreNar {
+            path: hello.clone(),
+            deriver: path(""),
+            nar_hash: nar_hash.clone().into(),
+            references: StorePathSet { paths: vec![] },
+            registration_time: 0,
+            nar_size: nar_bytes.len() as u64,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: OptionalContentAddress::default(),
+            repair: false,
+            dont_check_sigs: false,
+        }),
+        Resp::default(),
+    );
+    client.send_worker_op_to_daemon(&op).unwrap();
+    let framed = FramedData {
+        data: vec![ByteBuf::from(nar_bytes)],
+    };
+    framed.write(client.writer()).unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+
+    let op = WorkerOp::QueryPathInfo(Plain(hello.clone()), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    let info = client
+        .read_build_response_from_daemon(&Resp::<QueryPathInfoResponse>::default())
+        .unwrap();
+    assert_eq!(info.path.unwrap().hash.data.as_slice(), nar_hash.as_bytes());
+
+    let op = WorkerOp::NarFromPath(Plain(hello), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    let resp = Resp::<Nar>::default();
+    assert_eq!(client.read_build_response_from_daemon(&resp).unwrap(), nar);
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn untrusted_unsigned() {
+    let key = SecretKey::from_seed("test-1", &[7; 32]);
+    let config = ProxyConfig {
+        trust: TrustedFlag::NotTrusted,
+        trusted_public_keys: [key.to_public_key()].into_iter().collect(),
+        ..ProxyConfig::default()
+    };
+    let (mut client, server) = start_store(InMemoryStore::new(), config);
+
+    let nar = Nar::Contents(NarFile {
+        contents: NixString::from_bytes(b"hello"),
+        executable: false,
+    });
+    let nar_bytes = nix_remote::to_vec(&nar).unwrap();
+    let nar_hash = Hash::compute(HashAlgorithm::Sha256, &nar_bytes);
+    let hello = path("/nix/store/00000000000000000000000000000000-hello");
+    let mut add = AddToStoreNar {
+        path: hello.clone(),
+        deriver: path(""),
+        nar_hash: nar_hash.to_wire(),
+        references: StorePathSet { paths: vec![] },
+        registration_time: 0,
+        nar_size: nar_bytes.len() as u64,
+        ultimate: false,
+        sigs: StringSet::default(),
+        content_address: OptionalContentAddress::default(),
+        repair: false,
+        dont_check_sigs: true,
+    };
+    let mut send = |add: &AddToStoreNar| {
+        let op = WorkerOp::AddToStoreNar(WithFramedSource(add.clone()), Resp::default());
+        client.send_worker_op_to_daemon(&op).unwrap();
+        let framed = FramedData {
+            data: vec![ByteBuf::from(nar_bytes.clone())],
+        };
+        framed.write(client.writer()).unwrap();
+        client.flush().unwrap();
+        client.read_error_msg().unwrap()
+    };
+
+    let Msg::Error(e) = send(&add) else {
+        panic!("expected an error");
+    };
+    assert_eq!(
+        e.message,
+        "cannot add path '/nix/store/00000000000000000000000000000000-hello' \
+         because it lacks a signature by a trusted key"
+    );
+
+    // The connection is still usable, and a signed upload goes through.
+    let fingerprint =
+        signature::fingerprint(&add.path, &nar_hash, add.nar_size, &add.references).unwrap();
+    add.sigs.paths.push(key.sign(fingerprint.as_bytes()).into());
+    assert_eq!(send(&add), Msg::Last(()));
+
+    let op = WorkerOp::IsValidPath(Plain(hello), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    assert!(client
+        .read_build_response_from_daemon(&Resp::<bool>::default())
+        .unwrap());
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn untrusted_import() {
+    let config = ProxyConfig {
+        trust: TrustedFlag::NotTrusted,
+        ..ProxyConfig::default()
+    };
+    let (mut client, server) = start_store(InMemoryStore::new(), config);
+
+    // Exports aren't signed, so the store never even asks for one.
+    let op = WorkerOp::ImportPaths(Plain(()), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    let Msg::Error(e) = client.read_error_msg().unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(
+        e.message,
+        "cannot import paths because exports lack signatures by a trusted key"
+    );
+
+    assert!(!is_valid_path(
+        &mut client,
+        "/nix/store/00000000000000000000000000000000-hello"
+    ));
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn untrusted_add_multiple() {
+    let key = SecretKey::from_seed("test-1", &[7; 32]);
+    let config = ProxyConfig {
+        trust: TrustedFlag::NotTrusted,
+        trusted_public_keys: [key.to_public_key()].into_iter().collect(),
+        ..ProxyConfig::default()
+    };
+    let (mut client, server) = start_store(InMemoryStore::new(), config);
+
+    let nar = Nar::Contents(NarFile {
+        contents: NixString::from_bytes(b"hello"),
+        executable: false,
+    });
+    let nar_bytes = nix_remote::to_vec(&nar).unwrap();
+    let nar_hash = Hash::compute(HashAlgorithm::Sha256, &nar_bytes);
+    let mut hello = ValidPathInfoWithPath {
+        path: path("/nix/store/00000000000000000000000000000000-hello"),
+        info: ValidPathInfo {
+            deriver: path(""),
+            hash: NarHash::from(&nar_hash),
+            references: StorePathSet { paths: vec![] },
+            registration_time: 0,
+            nar_size: nar_bytes.len() as u64,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: OptionalContentAddress::default(),
+        },
+    };
+    let mut send = |hello: &ValidPathInfoWithPath| {
+        let op = WorkerOp::AddMultipleToStore(
+            WithFramedSource(AddMultipleToStore {
+                repair: false,
+                dont_check_sigs: true,
+            }),
+            Resp::default(),
+        );
+        client.send_worker_op_to_daemon(&op).unwrap();
+        let mut data = nix_remote::to_vec(&(1u64, &hello.path, &hello.info)).unwrap();
+        data.extend_from_slice(&nar_bytes);
+        let framed = FramedData {
+            data: vec![ByteBuf::from(data)],
+        };
+        framed.write(client.writer()).unwrap();
+        client.flush().unwrap();
+        client.read_error_msg().unwrap()
+    };
+
+    let Msg::Error(e) = send(&hello) else {
+        panic!("expected an error");
+    };
+    assert_eq!(
+        e.message,
+        "cannot add path '/nix/store/00000000000000000000000000000000-hello' \
+         because it lacks a signature by a trusted key"
+    );
+
+    hello.sign(&key).unwrap();
+    assert_eq!(send(&hello), Msg::Last(()));
+
+    let op = WorkerOp::IsValidPath(Plain(hello.path), Resp::default());
+    client.send_worker_op_to_daemon(&op).unwrap();
+    assert_eq!(client.read_error_msg().unwrap(), Msg::Last(()));
+    assert!(client
+        .read_build_response_from_daemon(&Resp::<bool>::default())
+        .unwrap());
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn untrusted_content_addressed() {
+    let config = ProxyConfig {
+        trust: TrustedFlag::NotTrusted,
+        ..ProxyConfig::default()
+    };
+    let (mut client, server) = start_store(InMemoryStore::new(), config);
+
+    let nar_for = |contents: &[u8]| {
+        nix_remote::to_vec(&Nar::Contents(NarFile {
+            contents: NixString::from_bytes(contents),
+            executable: false,
+        }))
+        .unwrap()
+    };
+    let mut send = |add: &AddToStoreNar, nar_bytes: Vec<u8>| {
+        let op = WorkerOp::AddToStoreNar(WithFramedSource(add.clone()), Resp::default());
+        client.send_worker_op_to_daemon(&op).unwrap();
+        let framed = FramedData {
+            data: vec![ByteBuf::from(nar_bytes)],
+        };
+        framed.write(client.writer()).unwrap();
+        client.flush().unwrap();
+        client.read_error_msg().unwrap()
+    };
+
+    // Nobody signed this, but its path follows from its contents, so it doesn't need a
+    // signature.
+    let store_dir = StoreDir::default();
+    let ca = ContentAddress::Flat(Hash::compute(HashAlgorithm::Sha256, b"hello"));
+    let hello = store_dir.print(
+        &store_dir
+            .make_content_addressed_path("hello", &ca, &StoreReferences::default())
+            .unwrap(),
+    );
+    let nar_bytes = nar_for(b"hello");
+    let mut add = AddToStoreNar {
+        path: hello.clone(),
+        deriver: path(""),
+        nar_hash: Hash::compute(HashAlgorithm::Sha256, &nar_bytes).to_wire(),
+        references: StorePathSet { paths: vec![] },
+        registration_time: 0,
+        nar_size: nar_bytes.len() as u64,
+        ultimate: false,
+        sigs: StringSet::default(),
+        content_address: OptionalContentAddress(Some(ca)),
+        repair: false,
+        dont_check_sigs: true,
+    };
+
+    // A content address that doesn't match the path is no good...
+    let other = ContentAddress::Flat(Hash::compute(HashAlgorithm::Sha256, b"bye"));
+    let bad = AddToStoreNar {
+        content_address: OptionalContentAddress(Some(other)),
+        ..add.clone()
+    };
+    let Msg::Error(e) = send(&bad, nar_bytes.clone()) else {
+        panic!("expected an error");
+    };
+    let hello_str = String::from_utf8(hello.0 .0.to_vec()).unwrap();
+    assert_eq!(
+        e.message,
+        format!("cannot add path '{hello_str}' because it lacks a signature by a trusted key")
+    );
+
+    // ...and neither are contents that don't match the content address.
+    let bye = nar_for(b"bye");
+    let bad = AddToStoreNar {
+        nar_hash: Hash::compute(HashAlgorithm::Sha256, &bye).to_wire(),
+        nar_size: bye.len() as u64,
+        ..add.clone()
+    };
+    let Msg::Error(e) = send(&bad, bye) else {
+        panic!("expected an error");
+    };
+    assert_eq!(
+        e.message,
+        format!(
+            "ca hash mismatch importing path '{hello_str}': expected {}, got {}",
+            ca.hash(),
+            other.hash()
+        )
+    );
+
+    assert_eq!(send(&add, nar_bytes.clone()), Msg::Last(()));
+    add.repair = true;
+    assert_eq!(send(&add, nar_bytes), Msg::Last(()));
+
+    drop(client);
+    server.join().unwrap();
+}
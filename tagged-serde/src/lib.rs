@@ -27,7 +27,7 @@ pub fn derive(input: TokenStream) -> TokenStream {
                 attr.meta
                     .path()
                     .get_ident()
-                    .map_or(false, |i| i == "tagged_serde")
+                    .is_some_and(|i| i == "tagged_serde")
             })
             .map(|attr| {
                 let nv = attr.meta.require_name_value().expect("name-value");
@@ -68,7 +68,7 @@ pub fn derive(input: TokenStream) -> TokenStream {
                 attr.meta
                     .path()
                     .get_ident()
-                    .map_or(false, |i| i == "tagged_serde")
+                    .is_some_and(|i| i == "tagged_serde")
             })
             .map(|attr| {
                 let nv = attr.meta.require_name_value().expect("name-value");